@@ -8,6 +8,7 @@ use std::f64;
 use libs::movement as mv;
 use behavior::unit::core as unit;
 use data::aliases::*;
+use data::protocol::write_record;
 
 pub fn encode(game: &Game, id: MissileID, vec: &mut Cursor<Vec<u8>>) {
     let misls = &game.missiles;
@@ -18,14 +19,13 @@ pub fn encode(game: &Game, id: MissileID, vec: &mut Cursor<Vec<u8>>) {
     }
 
     if let Some(missile_type_id) = misls.missile_type_id(id).clone() {
-        unsafe {
-            let _ = vec.write_u8(ClientMessage::MissileMove as u8);
-            let _ = vec.write_u8(missile_type_id.usize_unwrap() as u8);
-            let _ = vec.write_u16::<BigEndian>(id.usize_unwrap() as u16);
-            let _ = vec.write_u16::<BigEndian>((x * 64.0) as u16);
-            let _ = vec.write_u16::<BigEndian>((y * 64.0) as u16);
-            let _ = vec.write_u8(misls.team(id).usize_unwrap() as u8);
-        }
+        write_record(vec, ClientMessage::MissileMove, |rec| unsafe {
+            let _ = rec.write_u8(missile_type_id.usize_unwrap() as u8);
+            let _ = rec.write_u16::<BigEndian>(id.usize_unwrap() as u16);
+            let _ = rec.write_u16::<BigEndian>((x * 64.0) as u16);
+            let _ = rec.write_u16::<BigEndian>((y * 64.0) as u16);
+            let _ = rec.write_u8(misls.team(id).usize_unwrap() as u8);
+        });
     }
     else {
         let name = misls.name(id);
@@ -4,13 +4,15 @@ use data::kdt_point::KDTUnit;
 use behavior::unit::core as unit;
 use std::f64;
 use data::aliases::*;
+use pathing::terrain;
 
 pub fn train_unit(game: &mut Game, id: UnitID, train_order: TrainOrder) {
     let team = game.units.team(id);
     let fps = game.fps();
     if let Some(new_id) = game.units.make(fps, train_order.unit_type) {
         let (ux, uy) = game.units.xy(id);
-        if let Some((x,y)) = game.teams.jps_grid[team].nearest_open((ux as isize, uy as isize)) {
+        let class = terrain::grid_class(game.units.move_type(new_id));
+        if let Some((x,y)) = game.teams.jps_grids[team][class].nearest_open((ux as isize, uy as isize)) {
             let x = x as f64;
             let y = y as f64;
             let build_cost = game.units.build_cost(new_id);
@@ -53,7 +55,7 @@ pub fn build_unit(game: &mut Game, id: UnitID, b_id: UnitID) {
         unit::slow_down(game, id);
         game.teams.apply_build_power(team, b_id, build_rate);
         game.logger.log_construction(id, b_id);
-    } else if let Some(nearest_open) = game.teams.jps_grid[team].nearest_open((bx as isize, by as isize)) {
+    } else if let Some(nearest_open) = game.teams.jps_grids[team][terrain::grid_class(game.units.move_type(id))].nearest_open((bx as isize, by as isize)) {
         let success = unit::calculate_path(game, id, nearest_open);
         if success {
             unit::prune_path(game, id);
@@ -79,6 +81,7 @@ pub fn build_at_point(game: &mut Game, bg: &BuildGroup, id: UnitID, (x, y): (f64
     let build_range = game.units.build_range(id) + proto.radius();
     let build_range_sqrd = build_range * build_range;
     let is_extractor = proto.is_extractor();
+    let class = terrain::grid_class(proto.move_type());
 
     if !proto.is_structure() {
         unit::complete_order(game, id);
@@ -98,7 +101,7 @@ pub fn build_at_point(game: &mut Game, bg: &BuildGroup, id: UnitID, (x, y): (f64
 
                 for xo in ix..ix + w {
                     for yo in iy..iy + h {
-                        if !game.bytegrid.is_open((xo, yo)) {
+                        if !game.bytegrids[class].is_open((xo, yo)) {
                             unit::complete_order(game, id);
                             return;
                         }
@@ -135,12 +138,8 @@ pub fn build_at_point(game: &mut Game, bg: &BuildGroup, id: UnitID, (x, y): (f64
                         let unit_targ = game.units.new_unit_target(b_id);
                         bg.set_build_target(BuildTarget::Unit(unit_targ));
 
-                        for xo in ix..ix + w {
-                            for yo in iy..iy + h {
-                                game.bytegrid.set_point(false, (xo, yo));
-                                game.teams.jps_grid[team].close_point((xo, yo));
-                            }
-                        }
+                        let tiles: Vec<(isize, isize)> = (ix..ix + w).flat_map(|xo| (iy..iy + h).map(move |yo| (xo, yo))).collect();
+                        terrain::close_tiles(game, team, &tiles);
                     }
                     None => panic!("build_at_point: Not enough unit IDs to go around."),
                 }
@@ -1,10 +1,9 @@
 extern crate rand;
-extern crate byteorder;
 
 use data::move_groups::MoveGroup;
+use data::patrol_groups::PatrolGroup;
+use data::escort_groups::{EscortGroup, GUARD_LEASH};
 use data::build_groups::BuildTarget;
-use self::byteorder::{WriteBytesExt, BigEndian};
-use std::io::Cursor;
 use std::f64;
 use std::f64::consts::PI;
 use std::rc::Rc;
@@ -13,30 +12,14 @@ use data::kdt_point as kdtp;
 use behavior::weapon::core as weapon;
 use behavior::unit::building;
 use libs::movement as mv;
+use pathing::terrain;
 use data::game::Game;
 use data::kdt_point::{KDTUnit, KDTMissile};
 use data::aliases::*;
+use data::snapshots::UnitState;
 
-/*
-header = 1
-type = 1
-id = 2
-x = 2
-y = 2
-anim = 1
-team = 1
-face = 1
-health = 1
-progress = 1
-weapons = 1a (face,anim)
-construction = id
-num_psngrs = 1
-psngr_ids = 2b
-
-TOTAL = 13 + 1 * wpns + 2 * psngrs
-*/
-
-pub fn encode(game: &Game, id: UnitID, vec: &mut Cursor<Vec<u8>>) {
+// Quantizes a unit for sending to clients. data::snapshots decides how much of it actually gets sent.
+pub fn unit_state(game: &Game, id: UnitID) -> UnitState {
     let units = &game.units;
     let health = units.health(id);
     let max_health = units.max_health(id);
@@ -55,47 +38,52 @@ pub fn encode(game: &Game, id: UnitID, vec: &mut Cursor<Vec<u8>>) {
     let facing = mv::denormalize(units.facing(id));
 
     if let Some(unit_type_id) = units.unit_type(id).clone() {
-        unsafe {
-            let _ = vec.write_u8(ClientMessage::UnitMove as u8);
-            let _ = vec.write_u8(unit_type_id.usize_unwrap() as u8);
-            let _ = vec.write_u16::<BigEndian>(id.usize_unwrap() as u16);
-            let (x, y) = units.xy(id);
-            let _ = vec.write_u16::<BigEndian>((x * 64.0) as u16);
-            let _ = vec.write_u16::<BigEndian>((y * 64.0) as u16);
-            let _ = vec.write_u8(units.anim(id) as u8);
-            let _ = vec.write_u8(units.team(id).usize_unwrap() as u8);
-            let _ = vec.write_u8((facing * 255.0 / (2.0 * PI)) as u8);
-            let _ = vec.write_u8(encoded_health);
-            let _ = vec.write_u8(encoded_progress);
-
-            for wpn in units.weapons(id) {
-                let f = mv::denormalize(wpn.facing());
-                let _ = vec.write_u8((f * 255.0 / (2.0 * PI)) as u8);
-            }
+        let (x, y) = units.xy(id);
 
-            let capacity = units.capacity(id);
-            if capacity > (0 as usize) {
-                let passengers = units.passengers(id);
-                let _ = vec.write_u8(passengers.len() as u8);
+        let weapon_facings = units.weapons(id).iter().map(|wpn| {
+            let f = mv::denormalize(wpn.facing());
+            (f * 255.0 / (2.0 * PI)) as u8
+        }).collect();
 
-                for psngr in passengers.iter() {
-                    let _ = vec.write_u16::<BigEndian>((*psngr).usize_unwrap() as u16);
-                }
-            }
+        let passengers = if units.capacity(id) > (0 as usize) {
+            Some(units.passengers(id).iter().map(|psngr| unsafe { psngr.usize_unwrap() as u16 }).collect())
+        }
+        else {
+            None
+        };
 
-            let train_rate = game.units.train_rate(id);
+        let train_progress = if game.units.train_rate(id) > 0.0 {
+            let train_order_front = game.units.train_queue(id).front().cloned();
+            if let Some(train_order) = train_order_front {
+                let proto = game.units.proto(train_order.unit_type);
+                let train_cost = proto.build_cost();
+                let train_progress = game.units.train_progress(id);
+                Some((train_progress / train_cost * 255.0) as u8)
+            }
+            else {
+                Some(0)
+            }
+        }
+        else {
+            None
+        };
 
-            if train_rate > 0.0 {
-                let train_order_front = game.units.train_queue(id).front().cloned();
-                if let Some(train_order) = train_order_front {
-                    let proto = game.units.proto(train_order.unit_type);
-                    let train_cost = proto.build_cost();
-                    let train_progress = game.units.train_progress(id);
-                    let _ = vec.write_u8((train_progress / train_cost * 255.0) as u8);
-                }
-                else {
-                    let _ = vec.write_u8(0);
-                }
+        unsafe {
+            UnitState {
+                id: id,
+                unit_type: unit_type_id.usize_unwrap() as u8,
+                x: (x * 64.0) as u16,
+                y: (y * 64.0) as u16,
+                anim: units.anim(id) as u8,
+                team: units.team(id).usize_unwrap() as u8,
+                facing: (facing * 255.0 / (2.0 * PI)) as u8,
+                health: encoded_health,
+                progress: encoded_progress,
+                weapon_facings: weapon_facings,
+                passengers: passengers,
+                train_progress: train_progress,
+                move_stance: units.move_stance(id) as u8,
+                fire_stance: units.fire_stance(id) as u8,
             }
         }
     }
@@ -115,6 +103,9 @@ pub fn event_handler(game: &mut Game, event: UnitEvent) {
             game.teams.energy_output[team] += game.units.energy_output(id);
             game.teams.prime[team] += game.units.prime_output(id);
             game.teams.energy[team] += game.units.energy_output(id);
+            let provoked = game.units.provoked(id);
+            game.units.set_provoked(id, f64::max(0.0, provoked - game.frame_time()));
+
             let health = game.units.health(id);
             let health_regen = game.units.health_regen(id);
             let max_health = game.units.max_health(id);
@@ -137,7 +128,11 @@ fn follow_top_order(game: &mut Game, id: UnitID) {
 
     match current_order {
         None => {
-            slow_down(game, id);
+            let roams = game.units.move_stance(id) == MoveStance::Roam;
+
+            if !roams || !engage_nearest_enemy(game, id) {
+                slow_down(game, id);
+            }
         }
         Some(ord) => {
             follow_order(game, id, &*ord);
@@ -151,34 +146,13 @@ fn follow_order(game: &mut Game, id: UnitID, ord: &Order) {
             proceed_on_path(game, id, mg);
         }
         OrderType::AttackMove(ref mg) => {
-            let nearest_enemy = kdtp::nearest_visible_enemy_in_active_range(game, id);
-
-            match nearest_enemy {
-                Some(t_id) => {
-                    let no_weapons = game.units.weapons(id).is_empty();
-
-                    if no_weapons {
-                        slow_down(game, id);
-                    } else {
-                        let wpn_range = game.units.weapons(id)[0].range();
-                        let target_in_range = weapon::target_in_range(game, id, t_id, wpn_range);
-                        let is_bomber = match game.units.weapons(id)[0].attack() {
-                            &Attack::Bomb(_) |
-                            &Attack::LaserBomb(_) => true,
-                            _ => false,
-                        };
-                        if target_in_range && !is_bomber {
-                            let (tx, ty) = game.units.xy(t_id);
-                            turn_towards_point(game, id, tx, ty);
-                            slow_down(game, id);
-                        } else {
-                            move_towards_target(game, id, t_id, mg);
-                        }
-                    }
-                }
-                None => {
-                    proceed_on_path(game, id, mg);
-                }
+            if !engage_nearest_enemy(game, id) {
+                proceed_on_path(game, id, mg);
+            }
+        }
+        OrderType::Patrol(ref pg) => {
+            if !engage_nearest_enemy(game, id) && !repair_nearest_ally(game, id) {
+                patrol(game, id, ord, pg);
             }
         }
         OrderType::AttackTarget(ref mg, target) => {
@@ -275,6 +249,12 @@ fn follow_order(game: &mut Game, id: UnitID, ord: &Order) {
                 }
             }
             complete_assist_order(game, id);
+        }
+        OrderType::Guard(ref eg) => {
+            escort(game, id, eg, true);
+        }
+        OrderType::Follow(ref eg) => {
+            escort(game, id, eg, false);
         }
 		OrderType::Stop => (),
     }
@@ -318,8 +298,232 @@ pub fn complete_training(game: &mut Game, id: UnitID) {
     }
 }
 
+// Fights the nearest enemy in range, closing in if nothing's in the way and its move stance allows.
+// Returns false if there's nobody to fight, nothing to fight them with or the unit has to find a way around to them.
+fn engage_nearest_enemy(game: &mut Game, id: UnitID) -> bool {
+    if game.units.weapons(id).is_empty() || !weapon::fires_at_will(game, id) {
+        return false;
+    }
+
+    let move_stance = game.units.move_stance(id);
+    let range = match move_stance {
+        MoveStance::Roam => f64::max(game.units.engagement_range(id), game.units.sight_range(id)),
+        _ => game.units.engagement_range(id),
+    };
+    let nearest_enemy = kdtp::nearest_visible_enemy_within(game, id, range);
+
+    match nearest_enemy {
+        Some(t_id) => engage(game, id, t_id, move_stance),
+        None => false,
+    }
+}
+
+// Fights t_id, closing in if the move stance allows. Returns false if the unit can't get at it.
+fn engage(game: &mut Game, id: UnitID, t_id: UnitID, move_stance: MoveStance) -> bool {
+    let no_weapons = game.units.weapons(id).is_empty();
+
+    if no_weapons {
+        slow_down(game, id);
+        true
+    } else {
+        let wpn_range = game.units.weapons(id)[0].range();
+        let target_in_range = weapon::target_in_range(game, id, t_id, wpn_range);
+        let is_bomber = match game.units.weapons(id)[0].attack() {
+            &Attack::Bomb(_) |
+            &Attack::LaserBomb(_) => true,
+            _ => false,
+        };
+        if target_in_range && !is_bomber {
+            let (tx, ty) = game.units.xy(t_id);
+            turn_towards_point(game, id, tx, ty);
+            slow_down(game, id);
+            true
+        } else if move_stance == MoveStance::HoldPosition {
+            false
+        } else {
+            charge(game, id, t_id)
+        }
+    }
+}
+
+// Builders mend the nearest damaged ally in range. Returns false if there's nothing to mend.
+fn repair_nearest_ally(game: &mut Game, id: UnitID) -> bool {
+    if game.units.build_rate(id) <= 0.0 {
+        return false;
+    }
+
+    match kdtp::nearest_damaged_ally_in_active_range(game, id) {
+        Some(a_id) => {
+            building::build_unit(game, id, a_id);
+            true
+        }
+        None => false,
+    }
+}
+
+// Goes round a patrol route. Unlike a move the order never completes, the group just moves on to the next waypoint.
+fn patrol(game: &mut Game, id: UnitID, ord: &Order, pg: &PatrolGroup) {
+    let mg = pg.move_group();
+    let (x, y) = mg.goal();
+    let flies = game.units.move_type(id) == MoveType::Air;
+
+    match game.units.move_type(id) {
+        MoveType::Air => {
+            turn_towards_point(game, id, x, y);
+        }
+        MoveType::None => {
+            complete_order(game, id);
+            return;
+        }
+        _ => {
+            // A waypoint the unit can't get to counts as reached, so the patrol carries on without it
+            let path_exists = calculate_path(game, id, (x as isize, y as isize));
+            if !path_exists {
+                arrive_on_patrol(game, id, ord, pg);
+                return;
+            }
+            prune_path(game, id);
+            turn_towards_path(game, id);
+        }
+    }
+
+    let unit_target = game.units.new_unit_target(id);
+    let (the_end_is_near, the_end_has_come) = if flies {
+        // Flyers never have a path to measure by, so they go by how far off the waypoint is
+        let speed = game.units.speed(id);
+        let dist_to_group = mg.dist_to_group();
+        let dist_to_stop = mv::dist_to_stop(speed, game.units.deceleration(id));
+        let dist_to_end = speed + game.units.radius(id);
+        (within(game, id, (x, y), dist_to_group + dist_to_stop), within(game, id, (x, y), dist_to_group + dist_to_end))
+    } else {
+        (approaching_end_of_move_group_path(game, id, mg), arrived_at_end_of_move_group_path(game, id, mg))
+    };
+
+    if pg.has_arrived(unit_target) || the_end_has_come || (!flies && game.units.path(id).is_empty()) {
+        arrive_on_patrol(game, id, ord, pg);
+    } else if the_end_is_near {
+        slow_down(game, id);
+    } else {
+        speed_up(game, id);
+    }
+}
+
+// Waits at the waypoint, and sets everyone off for the next one once they're all there.
+fn arrive_on_patrol(game: &mut Game, id: UnitID, ord: &Order, pg: &PatrolGroup) {
+    let unit_target = game.units.new_unit_target(id);
+    let radius = game.units.radius(id);
+
+    slow_down(game, id);
+    pg.arrive(unit_target, radius);
+
+    if everyone_on_patrol_arrived(game, ord, pg) {
+        pg.next_leg();
+    }
+}
+
+fn within(game: &Game, id: UnitID, (x, y): (f64, f64), distance: f64) -> bool {
+    let (ux, uy) = game.units.xy(id);
+    let (dx, dy) = (x - ux, y - uy);
+    dx * dx + dy * dy < distance * distance
+}
+
+// Members that died or were given something else to do aren't waited for.
+fn everyone_on_patrol_arrived(game: &Game, ord: &Order, pg: &PatrolGroup) -> bool {
+    pg.move_group().membership().iter().all(|&member| {
+        match game.units.target_id(member) {
+            Some(m_id) => {
+                let on_patrol = game.units.orders(m_id).front().map_or(false, |top| &**top as *const Order == ord as *const Order);
+                !on_patrol || pg.has_arrived(member)
+            }
+            None => true,
+        }
+    })
+}
+
+// Keeps to the unit's slot around whoever it escorts. While they're under attack, guards go after enemies near them,
+// but no further than the leash, and come back once there's nobody left to fight.
+fn escort(game: &mut Game, id: UnitID, eg: &EscortGroup, guard: bool) {
+    let t_id = match game.units.target_id(eg.target()) {
+        Some(t_id) => t_id,
+        None => {
+            complete_order(game, id);
+            return;
+        }
+    };
+    let team = game.units.team(id);
+
+    // Enemies can be followed only while they can be seen
+    if game.units.team(t_id) != team && !game.teams.visible[team][t_id].is_visible() {
+        complete_order(game, id);
+        return;
+    }
+
+    let txy = game.units.xy(t_id);
+
+    if guard && game.units.provoked(t_id) > 0.0 && weapon::fires_at_will(game, id) {
+        if let Some(e_id) = kdtp::nearest_visible_enemy_near(game, id, txy, GUARD_LEASH) {
+            let move_stance = game.units.move_stance(id);
+
+            if engage(game, id, e_id, move_stance) {
+                return;
+            }
+        }
+    }
+
+    let unit_target = game.units.new_unit_target(id);
+    let facing = mv::denormalize(game.units.facing(t_id));
+    let slot = eg.slot(unit_target, txy, facing).unwrap_or(txy);
+    keep_up(game, id, slot);
+}
+
+// Heads for a spot that moves, slowing down to wait there. Unlike a move it never completes.
+fn keep_up(game: &mut Game, id: UnitID, (x, y): (f64, f64)) {
+    match game.units.move_type(id) {
+        MoveType::None => {
+            return;
+        }
+        MoveType::Air => {
+            turn_towards_point(game, id, x, y);
+        }
+        _ => {
+            // The spot may be somewhere the unit can't go, so it waits for it to come back
+            if !calculate_path(game, id, (x as isize, y as isize)) {
+                slow_down(game, id);
+                return;
+            }
+            prune_path(game, id);
+
+            if game.units.path(id).is_empty() {
+                turn_towards_point(game, id, x, y);
+            } else {
+                turn_towards_path(game, id);
+            }
+        }
+    }
+
+    let (ux, uy) = game.units.xy(id);
+    let (dx, dy) = (x - ux, y - uy);
+    let speed = game.units.speed(id);
+    let dist_to_stop = mv::dist_to_stop(speed, game.units.deceleration(id));
+    let close_enough = dist_to_stop + game.units.radius(id) * 0.5;
+
+    if dx * dx + dy * dy <= close_enough * close_enough {
+        slow_down(game, id);
+    } else {
+        speed_up(game, id);
+    }
+}
+
 fn move_towards_target(game: &mut Game, id: UnitID, t_id: UnitID, mg: &MoveGroup) {
+    if !charge(game, id, t_id) {
+        proceed_on_path(game, id, mg);
+    }
+}
+
+// Heads straight for t_id. Returns false if something's in the way.
+fn charge(game: &mut Game, id: UnitID, t_id: UnitID) -> bool {
     let team = game.units.team(id);
+    let class = terrain::grid_class(game.units.move_type(id));
     let (ux, uy) = game.units.xy(id);
     let (tx, ty) = game.units.xy(t_id);
     let (ax, ay) = (ux as isize, uy as isize);
@@ -327,19 +531,20 @@ fn move_towards_target(game: &mut Game, id: UnitID, t_id: UnitID, mg: &MoveGroup
     let a = (ax, ay);
     let b = (bx, by);
 
-    let a_to_b_open = game.teams.jps_grid[team].is_line_open(a, b);
-    let b_to_a_open = game.teams.jps_grid[team].is_line_open(b, a);
+    let a_to_b_open = game.teams.jps_grids[team][class].is_line_open(a, b);
+    let b_to_a_open = game.teams.jps_grids[team][class].is_line_open(b, a);
 
     if a_to_b_open && b_to_a_open {
         turn_towards_point(game, id, tx, ty);
         speed_up(game, id);
+        true
     } else {
-        proceed_on_path(game, id, mg);
+        false
     }
 }
 
 fn move_towards_point(game: &mut Game, id: UnitID, (x,y): (f64,f64), dist: f64) {
-    if game.units.move_type(id) == MoveType::Ground {
+    if terrain::move_class(game.units.move_type(id)).is_some() {
         let path_exists = calculate_path(game, id, (x as isize, y as isize));
         if !path_exists {
             complete_order(game, id);
@@ -369,10 +574,10 @@ fn move_towards_point(game: &mut Game, id: UnitID, (x,y): (f64,f64), dist: f64)
 }
 
 fn proceed_on_path(game: &mut Game, id: UnitID, mg: &MoveGroup) {
-    let (x, y) = mg.goal();
+    let (x, y) = slot_goal(game, id, mg);
 
     match game.units.move_type(id) {
-        MoveType::Ground => {
+        MoveType::Ground | MoveType::Water | MoveType::Underwater | MoveType::Hover | MoveType::Amphibious => {
             let path_exists = calculate_path(game, id, (x as isize, y as isize));
             if !path_exists {
                 complete_order(game, id);
@@ -393,7 +598,7 @@ fn proceed_on_path(game: &mut Game, id: UnitID, mg: &MoveGroup) {
             } else if the_end_is_near {
                 slow_down(game, id);
             } else {
-                speed_up(game, id);
+                keep_pace(game, id, mg);
             }
         }
         MoveType::Air => {
@@ -411,23 +616,41 @@ fn proceed_on_path(game: &mut Game, id: UnitID, mg: &MoveGroup) {
             } else if the_end_is_near {
                 slow_down(game, id);
             } else {
-                speed_up(game, id);
+                keep_pace(game, id, mg);
             }
         }
-        MoveType:: Water | MoveType::Underwater => {
-            unimplemented!("Water / Underwater Movement");
-        }
-        MoveType::Hover | MoveType::Amphibious => {
-            unimplemented!("Hover / Amphibious Movement");
-        }
         MoveType::None => {
             complete_order(game, id);
         }
     }
 }
 
+// Where the unit's slot in the group's formation is, or the group's goal if there's no standing there.
+fn slot_goal(game: &Game, id: UnitID, mg: &MoveGroup) -> (f64, f64) {
+    let (x, y) = mg.goal_of(game.units.new_unit_target(id));
+    let class = terrain::grid_class(game.units.move_type(id));
+
+    if x >= 0.0 && y >= 0.0 && game.bytegrids[class].is_open((x as isize, y as isize)) {
+        (x, y)
+    } else {
+        mg.goal()
+    }
+}
+
+// Speeds up, but no faster than the slowest of the group so they get there together.
+fn keep_pace(game: &mut Game, id: UnitID, mg: &MoveGroup) {
+    speed_up(game, id);
+
+    if let Some(top_speed) = mg.top_speed() {
+        if game.units.speed(id) > top_speed {
+            game.units.set_speed(id, top_speed);
+        }
+    }
+}
+
 pub fn calculate_path(game: &mut Game, id: UnitID, (x, y): (isize, isize)) -> bool {
     let team = game.units.team(id);
+    let class = terrain::grid_class(game.units.move_type(id));
     let (sx, sy) = {
         let (zx, zy) = game.units.xy(id);
         (zx as isize, zy as isize)
@@ -436,12 +659,12 @@ pub fn calculate_path(game: &mut Game, id: UnitID, (x, y): (isize, isize)) -> bo
     if !game.units.path(id).is_empty() {
         let a = (sx, sy);
         let b = game.units.path(id)[game.units.path(id).len() - 1];
-        let a_to_b_open = game.teams.jps_grid[team].is_line_open(a, b);
-        let b_to_a_open = game.teams.jps_grid[team].is_line_open(b, a);
+        let a_to_b_open = game.teams.jps_grids[team][class].is_line_open(a, b);
+        let b_to_a_open = game.teams.jps_grids[team][class].is_line_open(b, a);
         let destination_changed = (x, y) != game.units.path(id)[0];
 
         if destination_changed || !a_to_b_open || !b_to_a_open {
-            match game.teams.jps_grid[team].find_path((sx, sy), (x, y)) {
+            match game.teams.jps_grids[team][class].find_path((sx, sy), (x, y)) {
                 None => {
                     // BAD WRONG FALSE STOP FREEZE
                     *game.units.mut_path(id) = Vec::new();
@@ -455,7 +678,7 @@ pub fn calculate_path(game: &mut Game, id: UnitID, (x, y): (isize, isize)) -> bo
         }
         true
     } else {
-        match game.teams.jps_grid[game.units.team(id)].find_path((sx, sy), (x, y)) {
+        match game.teams.jps_grids[team][class].find_path((sx, sy), (x, y)) {
             None => {
                 *game.units.mut_path(id) = Vec::new();
                 false
@@ -470,6 +693,7 @@ pub fn calculate_path(game: &mut Game, id: UnitID, (x, y): (isize, isize)) -> bo
 
 pub fn prune_path(game: &mut Game, id: UnitID) {
     let team = game.units.team(id);
+    let class = terrain::grid_class(game.units.move_type(id));
     let (sx, sy) = {
         let (zx, zy) = game.units.xy(id);
         (zx as isize, zy as isize)
@@ -480,8 +704,8 @@ pub fn prune_path(game: &mut Game, id: UnitID) {
     if path.len() >= 2 {
         let a = (sx, sy);
         let b = path[path.len() - 2];
-        let a_to_b_open = game.teams.jps_grid[team].is_line_open(a, b);
-        let b_to_a_open = game.teams.jps_grid[team].is_line_open(b, a);
+        let a_to_b_open = game.teams.jps_grids[team][class].is_line_open(a, b);
+        let b_to_a_open = game.teams.jps_grids[team][class].is_line_open(b, a);
 
         if a_to_b_open && b_to_a_open {
             path.pop();
@@ -540,7 +764,9 @@ fn collide(game: &Game, id: UnitID) -> (f64, f64) {
         game.units.xy_repulsion(id)
     };
 
-    ((x_repel + x_off * ratio) * resist, (y_repel + y_off * ratio) * resist)
+    let time_scale = COLLISION_TUNING_FPS / game.fps();
+
+    ((x_repel + x_off * ratio * time_scale) * resist, (y_repel + y_off * ratio * time_scale) * resist)
 }
 
 
@@ -549,11 +775,12 @@ fn collide(game: &Game, id: UnitID) -> (f64, f64) {
 // Corrects the unit to not be on any unpathable terrain
 pub fn move_and_collide_and_correct(game: &mut Game, id: UnitID) {
     let (x, y) = game.units.xy(id);
+    let class = terrain::grid_class(game.units.move_type(id));
     let (mx, my) = move_forward(game, id);
     let (xo, yo) = collide(game, id);
     let rx = game.get_random_collision_offset();
     let ry = game.get_random_collision_offset();
-    let (new_x, new_y, x_corrected, y_corrected) = game.bytegrid.correct_move(
+    let (new_x, new_y, x_corrected, y_corrected) = game.bytegrids[class].correct_move(
         (x, y),
         (mx + xo + rx, my + yo + ry),
     );
@@ -581,7 +808,7 @@ pub fn move_and_collide_and_correct(game: &mut Game, id: UnitID) {
 
     game.units.set_xy_repulsion(id, (x_repel, y_repel));
 
-    if game.bytegrid.is_open((new_x as isize, new_y as isize)) {
+    if game.bytegrids[class].is_open((new_x as isize, new_y as isize)) {
         game.units.set_xy(id, (new_x, new_y));
     }
 }
@@ -694,5 +921,6 @@ pub fn damage_unit(game: &mut Game, id: UnitID, amount: f64) {
     }
     else {
         game.units.set_health(id, health - amount);
+        game.units.set_provoked(id, RETURN_FIRE_SECONDS);
     }
 }
\ No newline at end of file
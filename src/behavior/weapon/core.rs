@@ -10,12 +10,19 @@ use data::aliases::*;
 
 pub fn attack_orders(game: &mut Game, wpn: &mut Weapon, u_id: UnitID) {
     let current_order = game.units.orders(u_id).front().cloned();
+    let frame_time = game.frame_time();
 
-    cooldown_weapon(wpn);
+    cooldown_weapon(wpn, frame_time);
     match current_order {
         Some(ord) => {
             match (*ord).order_type {
-                OrderType::AttackMove(_) => {
+                OrderType::AttackMove(_) |
+                OrderType::Patrol(_) => {
+                    if !fires_at_will(game, u_id) {
+                        rest_weapon(game, wpn, u_id);
+                        return;
+                    }
+
                     match wpn.target_id() {
                         Some(unit_target) => {
                             match game.units.target_id(unit_target) {
@@ -56,6 +63,8 @@ pub fn attack_orders(game: &mut Game, wpn: &mut Weapon, u_id: UnitID) {
                 OrderType::Move(_) |
                 OrderType::Build(_) |
                 OrderType::Assist(_) |
+                OrderType::Guard(_) |
+                OrderType::Follow(_) |
 				OrderType::Stop => {
                     attack_nearest_enemy(game, wpn, u_id);
                 }
@@ -67,26 +76,46 @@ pub fn attack_orders(game: &mut Game, wpn: &mut Weapon, u_id: UnitID) {
     }
 }
 
+// Whether the unit's fire stance lets it pick its own targets right now.
+pub fn fires_at_will(game: &Game, u_id: UnitID) -> bool {
+    match game.units.fire_stance(u_id) {
+        FireStance::HoldFire => false,
+        FireStance::ReturnFire => game.units.provoked(u_id) > 0.0,
+        FireStance::FireAtWill => true,
+    }
+}
+
 fn attack_nearest_enemy(game: &mut Game, wpn: &mut Weapon, u_id: UnitID) {
-    let is_structure = game.units.is_structure(u_id);
-    match kdtp::get_nearest_enemy(game, wpn, u_id) {
+    let nearest_enemy = if fires_at_will(game, u_id) {
+        kdtp::get_nearest_enemy(game, wpn, u_id)
+    }
+    else {
+        None
+    };
+
+    match nearest_enemy {
         Some(t_id) => {
             wpn.set_target_id(Some(game.units.new_unit_target(t_id)));
             attack_target(game, wpn, u_id, t_id);
         }
         None => {
-            // Return weapon to resting position
-            let unit_facing = game.units.facing(u_id);
-            let wpn_facing = wpn.facing();
-            let turn_rate = wpn.turn_rate();
-            let wpn_lock_angle = wpn.lock_offset();
+            rest_weapon(game, wpn, u_id);
+        }
+    }
+}
+
+// Return weapon to resting position
+fn rest_weapon(game: &Game, wpn: &mut Weapon, u_id: UnitID) {
+    let is_structure = game.units.is_structure(u_id);
+    let unit_facing = game.units.facing(u_id);
+    let wpn_facing = wpn.facing();
+    let turn_rate = wpn.turn_rate();
+    let wpn_lock_angle = wpn.lock_offset();
 
-            wpn.set_target_id(None);
+    wpn.set_target_id(None);
 
-            if !is_structure {
-                wpn.set_facing(mv::turn_towards(wpn_facing, unit_facing + wpn_lock_angle, turn_rate));
-            }
-        }
+    if !is_structure {
+        wpn.set_facing(mv::turn_towards(wpn_facing, unit_facing + wpn_lock_angle, turn_rate));
     }
 }
 
@@ -109,7 +138,7 @@ fn turn_towards_target_and_attempt_to_smack(game: &mut Game, damage: Damage, wpn
     let on_target = turn_weapon_to_point(game, wpn, u_id, enemy_xy);
 
     if on_target && weapon_is_ready_to_fire(wpn) {
-        heatup_weapon(game, wpn);
+        heatup_weapon(wpn);
         match damage {
             Damage::Single(amount) => {
                 unit::damage_unit(game, t_id, amount);
@@ -153,11 +182,12 @@ fn weapon_is_ready_to_fire(wpn: &Weapon) -> bool {
     cooldown <= 0.0 || (salvo_cooldown <= 0.0 && salvo < salvo_size)
 }
 
-fn heatup_weapon(game: &mut Game, wpn: &mut Weapon) -> usize {
+// Cooldowns are kept in seconds so they don't depend on the frame rate.
+fn heatup_weapon(wpn: &mut Weapon) -> usize {
     if wpn.cooldown() <= 0.0 {
         let cooldown = wpn.cooldown();
         let fire_rate = wpn.fire_rate();
-        wpn.set_cooldown(cooldown + game.fps() * fire_rate);
+        wpn.set_cooldown(cooldown + fire_rate);
         wpn.set_salvo(0);
         wpn.set_salvo_cooldown(0.0);
     }
@@ -166,26 +196,26 @@ fn heatup_weapon(game: &mut Game, wpn: &mut Weapon) -> usize {
     wpn.set_salvo(salvo + 1);
     let salvo_cooldown = wpn.salvo_cooldown();
     let salvo_fire_rate = wpn.salvo_fire_rate();
-    wpn.set_salvo_cooldown(salvo_cooldown + game.fps() * salvo_fire_rate);
+    wpn.set_salvo_cooldown(salvo_cooldown + salvo_fire_rate);
     salvo
 }
 
-fn cooldown_weapon(wpn: &mut Weapon) {
+fn cooldown_weapon(wpn: &mut Weapon, frame_time: f64) {
     let cooldown = wpn.cooldown();
     let salvo_cooldown = wpn.salvo_cooldown();
 
     if salvo_cooldown > 0.0 {
-        wpn.set_salvo_cooldown(salvo_cooldown - 1.0);
+        wpn.set_salvo_cooldown(salvo_cooldown - frame_time);
     }
 
     if cooldown > 0.0 {
-        wpn.set_cooldown(cooldown - 1.0);
+        wpn.set_cooldown(cooldown - frame_time);
     }
 }
 
 fn fire_missile_salvo_at_target(game: &mut Game, missile_type: MissileTypeID, wpn: &mut Weapon, u_id: UnitID, t_id: UnitID) {
     if weapon_is_ready_to_fire(wpn) {
-        let salvo = heatup_weapon(game, wpn);
+        let salvo = heatup_weapon(wpn);
         let wpn_facing = wpn.facing();
         let alternating = wpn.alternating();
         let wpn_target_type = wpn.target_type();
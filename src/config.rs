@@ -0,0 +1,452 @@
+/*
+ Everything needed to start a match.
+ A match file (JSON) is read first, then any command line flags override what it set.
+ Anything neither of them mention keeps the default below.
+*/
+
+use std::fs::File;
+use std::io::prelude::*;
+use serde_json;
+use libs::netcom::Role;
+use libs::auth::{Credentials, SecretHash};
+use data::lifecycle::Victory;
+
+pub const USAGE: &'static str = "Usage: rust_rts [options]
+
+Options:
+    --config <file>      JSON match file to start from
+    --address <addr>     Address to bind to
+    --port <port>        Port to listen on
+    --map <file>         Map to play on
+    --units <dir>        Directory of unit definitions
+    --missiles <dir>     Directory of missile definitions
+    --max-units <n>      Most units that can exist at once
+    --max-teams <n>      Most teams that can exist at once
+    --fps <n>            Simulation frames per second
+    --seed <n>           Seed for gameplay randomness (random if not given)
+    --join-tokens        Print a fresh join token for everyone in the roster
+    --victory <rule>     How teams are knocked out: structures, units, commander or score
+    --time-limit <secs>  End the match after this much game time, best score wins
+    --countdown <secs>   Seconds between everyone joining and the match starting
+    --results <file>     Write who won to a file when the match ends
+    --pauses <secs>      Seconds each team can keep the match paused for
+    --admin <name>       Let someone in the roster pause freely and change the speed (repeatable)
+    --lockstep           Relay orders for clients to simulate instead of streaming state
+    --lockstep-delay <n> Frames between an order arriving and running in lockstep
+    --record <file>      Write a replay of the match to a file
+    --save <file>        Save the match to a file every so often
+    --save-every <secs>  Seconds of game time between saves (default 60)
+    --load <file>        Pick up a saved match instead of starting a new one
+    --replay <file>      Replay a recorded match headless and print its checksum
+    --serve              With --replay, stream the replay to spectators instead
+    --matches <file>     Host every match listed in a file, each joined with its code
+    --hash-password      Read a password from stdin and print its hash for a match file
+    --help               Print this message";
+
+// Give either a password or a password_hash (see --hash-password), or neither if join tokens are on.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlayerConfig {
+    pub name: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    pub team: usize,
+}
+
+// Spectators without a team see the whole map
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpectatorConfig {
+    pub name: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    #[serde(default)]
+    pub team: Option<usize>,
+}
+
+// Where a team's starting units are scattered, in tiles.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StartArea {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MatchConfig {
+    pub address: String,
+    pub port: u16,
+    pub map: String,
+    pub unit_dir: String,
+    pub missile_dir: String,
+    pub max_units: usize,
+    pub max_teams: usize,
+    pub fps: f64,
+    pub seed: Option<u64>,
+    pub starting_prime: f64,
+    pub starting_energy: f64,
+    pub max_prime: f64,
+    pub max_energy: f64,
+    // Each team starts with starting_units of starting_unit in its start area, the first area for team 0 and so on
+    pub starting_unit: String,
+    pub starting_units: usize,
+    pub start_areas: Vec<StartArea>,
+    pub players: Vec<PlayerConfig>,
+    pub spectators: Vec<SpectatorConfig>,
+    pub join_tokens: bool,
+    pub lockstep: bool,
+    pub lockstep_delay: u32,
+    pub record: Option<String>,
+    pub save: Option<String>,
+    pub save_seconds: f64,
+    pub victory: Victory,
+    pub time_limit: Option<f64>,
+    pub countdown_seconds: f64,
+    pub results: Option<String>,
+    pub pause_seconds: f64,
+    // Names from the roster who can pause without a budget and change the speed
+    pub admins: Vec<String>,
+}
+
+impl Default for MatchConfig {
+    fn default() -> MatchConfig {
+        MatchConfig {
+            address: "localhost".to_string(),
+            port: 4444,
+            map: "./maps/Map2.json".to_string(),
+            unit_dir: "./src/units/".to_string(),
+            missile_dir: "./src/missiles/".to_string(),
+            max_units: 4096,
+            max_teams: 8,
+            fps: 10.0,
+            seed: None,
+            starting_prime: 1000.0,
+            starting_energy: 1000.0,
+            max_prime: 1000.0,
+            max_energy: 1000.0,
+            starting_unit: "Medium1".to_string(),
+            starting_units: 2000,
+            start_areas: vec![
+                StartArea { x: 0.0, y: 0.0, width: 24.0, height: 96.0 },
+                StartArea { x: 36.0, y: 0.0, width: 72.0, height: 24.0 },
+            ],
+            players: Vec::new(),
+            spectators: Vec::new(),
+            join_tokens: false,
+            lockstep: false,
+            lockstep_delay: 3,
+            record: None,
+            save: None,
+            save_seconds: 60.0,
+            victory: Victory::Units,
+            time_limit: None,
+            countdown_seconds: 5.0,
+            results: None,
+            pause_seconds: 120.0,
+            admins: Vec::new(),
+        }
+    }
+}
+
+impl MatchConfig {
+    pub fn from_file(path: &str) -> Result<MatchConfig, String> {
+        let mut contents = String::new();
+        let mut file = File::open(path).map_err(|e| format!("Couldn't open {}: {}", path, e))?;
+
+        file.read_to_string(&mut contents).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+        serde_json::from_str(&contents).map_err(|e| format!("Couldn't parse {}: {}", path, e))
+    }
+
+    // Expects the arguments without the program name.
+    // Returns Ok(None) when the user only asked for help.
+    pub fn from_args(args: &[String]) -> Result<Option<MatchConfig>, String> {
+        let mut config = MatchConfig::default();
+
+        // The match file goes first so flags override it no matter where they appear
+        if let Some(ix) = args.iter().position(|a| a == "--config") {
+            match args.get(ix + 1) {
+                Some(path) => config = MatchConfig::from_file(path)?,
+                None => return Err("--config needs a value.".to_string()),
+            }
+        }
+
+        let mut ix = 0;
+
+        while ix < args.len() {
+            let flag = args[ix].as_str();
+
+            if flag == "--help" {
+                return Ok(None);
+            }
+
+            if flag == "--join-tokens" {
+                config.join_tokens = true;
+                ix += 1;
+                continue;
+            }
+
+            if flag == "--lockstep" {
+                config.lockstep = true;
+                ix += 1;
+                continue;
+            }
+
+            let value = match args.get(ix + 1) {
+                Some(value) => value.clone(),
+                None => return Err(format!("{} needs a value.", flag)),
+            };
+
+            match flag {
+                "--config" => (),
+                "--address" => config.address = value,
+                "--port" => config.port = parse_flag(flag, &value)?,
+                "--map" => config.map = value,
+                "--units" => config.unit_dir = value,
+                "--missiles" => config.missile_dir = value,
+                "--max-units" => config.max_units = parse_flag(flag, &value)?,
+                "--max-teams" => config.max_teams = parse_flag(flag, &value)?,
+                "--fps" => config.fps = parse_flag(flag, &value)?,
+                "--seed" => config.seed = Some(parse_flag(flag, &value)?),
+                "--lockstep-delay" => config.lockstep_delay = parse_flag(flag, &value)?,
+                "--record" => config.record = Some(value),
+                "--save" => config.save = Some(value),
+                "--save-every" => config.save_seconds = parse_flag(flag, &value)?,
+                "--victory" => config.victory = serde_json::from_value(serde_json::Value::String(value.clone()))
+                    .map_err(|_| format!("{} isn't a victory condition.", value))?,
+                "--time-limit" => config.time_limit = Some(parse_flag(flag, &value)?),
+                "--countdown" => config.countdown_seconds = parse_flag(flag, &value)?,
+                "--results" => config.results = Some(value),
+                "--pauses" => config.pause_seconds = parse_flag(flag, &value)?,
+                "--admin" => config.admins.push(value),
+                other => return Err(format!("Unknown option {}.", other)),
+            }
+
+            ix += 2;
+        }
+
+        config.validate()?;
+        Ok(Some(config))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.fps > 0.0) {
+            return Err("fps must be greater than 0.".to_string());
+        }
+
+        if self.max_units == 0 || self.max_units > 65536 {
+            return Err("max_units must be between 1 and 65536.".to_string());
+        }
+
+        if self.lockstep_delay == 0 {
+            return Err("lockstep_delay must be at least 1.".to_string());
+        }
+
+        if !(self.save_seconds > 0.0) {
+            return Err("save_seconds must be greater than 0.".to_string());
+        }
+
+        match self.time_limit {
+            Some(limit) if !(limit > 0.0) => return Err("time_limit must be greater than 0.".to_string()),
+            None if self.victory == Victory::Score => return Err("The score victory condition needs a time_limit.".to_string()),
+            _ => (),
+        }
+
+        if !(self.countdown_seconds >= 0.0) {
+            return Err("countdown_seconds can't be negative.".to_string());
+        }
+
+        if !(self.pause_seconds >= 0.0) {
+            return Err("pause_seconds can't be negative.".to_string());
+        }
+
+        // Team 255 is how spectators who see everything are told apart on the wire
+        if self.max_teams == 0 || self.max_teams > 255 {
+            return Err("max_teams must be between 1 and 255.".to_string());
+        }
+
+        if self.start_areas.iter().any(|a| !(a.width > 0.0) || !(a.height > 0.0)) {
+            return Err("Start areas need a width and height greater than 0.".to_string());
+        }
+
+        let logins = self.logins();
+
+        for (i, &(name, password, password_hash, role)) in logins.iter().enumerate() {
+            if let Some(team) = role.view_team() {
+                if team >= self.max_teams {
+                    return Err(format!("{} is on team {} but there are only {} teams.", name, team, self.max_teams));
+                }
+            }
+
+            if logins[..i].iter().any(|l| l.0 == name) {
+                return Err(format!("{} is in the roster more than once.", name));
+            }
+
+            if password.is_some() && password_hash.is_some() {
+                return Err(format!("{} has both a password and a password_hash.", name));
+            }
+
+            if password.is_none() && password_hash.is_none() && !self.join_tokens {
+                return Err(format!("{} has no password and join tokens are off.", name));
+            }
+
+            if let Some(hash) = password_hash {
+                SecretHash::parse(hash).map_err(|e| format!("{}: {}", name, e))?;
+            }
+        }
+
+        for admin in &self.admins {
+            if !logins.iter().any(|l| l.0 == admin) {
+                return Err(format!("The admin {} isn't in the roster.", admin));
+            }
+        }
+
+        let teams = self.teams();
+
+        if teams > self.max_teams {
+            return Err(format!("There are {} start areas but only {} teams.", teams, self.max_teams));
+        }
+
+        if teams > self.start_areas.len() {
+            return Err(format!("There are {} teams but only {} start areas.", teams, self.start_areas.len()));
+        }
+
+        let starting = self.starting_teams().len() * self.starting_units;
+
+        if starting > self.max_units {
+            return Err(format!("Teams start with {} units in all but max_units is {}.", starting, self.max_units));
+        }
+
+        Ok(())
+    }
+
+    // How many teams the match is set up with, enough for every team in the roster.
+    // Without a roster there's a team for each start area.
+    pub fn teams(&self) -> usize {
+        self.logins().iter().filter_map(|l| l.3.view_team()).map(|team| team + 1).max().unwrap_or(self.start_areas.len())
+    }
+
+    // The teams that get starting units: those with players, or all of them without a roster.
+    pub fn starting_teams(&self) -> Vec<usize> {
+        if self.players.is_empty() {
+            (0..self.teams()).collect()
+        }
+        else {
+            (0..self.teams()).filter(|&team| self.players.iter().any(|p| p.team == team)).collect()
+        }
+    }
+
+    // Everyone who can log in, players first, with their passwords hashed.
+    // Join tokens are made separately since they're new every match.
+    pub fn roster(&self) -> Vec<(String, Credentials)> {
+        self.logins().into_iter().map(|(name, password, password_hash, role)| {
+            let hash = match (password, password_hash) {
+                (Some(password), _) => Some(SecretHash::of(password)),
+                (None, Some(hash)) => SecretHash::parse(hash).ok(),
+                (None, None) => None,
+            };
+
+            (name.to_string(), Credentials { password: hash, token: None, role: role })
+        }).collect()
+    }
+
+    pub fn is_admin(&self, name: &str) -> bool {
+        self.admins.iter().any(|a| a == name)
+    }
+
+    // The same match with every password and hash left out, for files that get passed around.
+    pub fn without_secrets(&self) -> MatchConfig {
+        let mut config = self.clone();
+
+        for player in config.players.iter_mut() {
+            player.password = None;
+            player.password_hash = None;
+        }
+
+        for spectator in config.spectators.iter_mut() {
+            spectator.password = None;
+            spectator.password_hash = None;
+        }
+
+        config
+    }
+
+    fn logins(&self) -> Vec<(&str, Option<&String>, Option<&String>, Role)> {
+        let players = self.players.iter().map(|p| (p.name.as_str(), p.password.as_ref(), p.password_hash.as_ref(), Role::Player(p.team)));
+        let spectators = self.spectators.iter().map(|s| (s.name.as_str(), s.password.as_ref(), s.password_hash.as_ref(), Role::Spectator(s.team)));
+        players.chain(spectators).collect()
+    }
+}
+
+// A match in a file passed to --matches, which is a JSON list of these.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MatchEntry {
+    // What players give as "match" when logging in
+    pub code: String,
+    // The match file to set it up from
+    pub config: String,
+}
+
+// The matches one server hosts, by join code. Only the address and port of the server's own config are used.
+pub fn read_matches(path: &str) -> Result<Vec<(String, MatchConfig)>, String> {
+    let mut contents = String::new();
+    let mut file = File::open(path).map_err(|e| format!("Couldn't open {}: {}", path, e))?;
+
+    file.read_to_string(&mut contents).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+    let entries: Vec<MatchEntry> = serde_json::from_str(&contents).map_err(|e| format!("Couldn't parse {}: {}", path, e))?;
+    let mut matches: Vec<(String, MatchConfig)> = Vec::new();
+
+    if entries.is_empty() {
+        return Err(format!("{} has no matches in it.", path));
+    }
+
+    for entry in entries {
+        if entry.code.is_empty() {
+            return Err(format!("A match in {} has no code.", path));
+        }
+
+        if matches.iter().any(|m| m.0 == entry.code) {
+            return Err(format!("{} is in {} more than once.", entry.code, path));
+        }
+
+        let config = MatchConfig::from_file(&entry.config)?;
+        config.validate().map_err(|e| format!("{}: {}", entry.code, e))?;
+
+        // Matches writing over each other's files would leave nothing usable behind
+        let outputs = |c: &MatchConfig| vec![c.record.clone(), c.save.clone(), c.results.clone()].into_iter().filter_map(|o| o).collect::<Vec<String>>();
+
+        for output in outputs(&config) {
+            if let Some(other) = matches.iter().find(|m| outputs(&m.1).contains(&output)) {
+                return Err(format!("{} and {} both write to {}.", other.0, entry.code, output));
+            }
+        }
+
+        matches.push((entry.code, config));
+    }
+
+    Ok(matches)
+}
+
+fn parse_flag<T: ::std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} got a bad value: {}", flag, value))
+}
+
+#[test]
+fn setups_that_cant_be_made_are_refused() {
+    let args = |s: &str| s.split_whitespace().map(|a| a.to_string()).collect::<Vec<String>>();
+
+    assert!(MatchConfig::from_args(&args("--max-units 1000")).is_err());
+    assert!(MatchConfig::from_args(&args("--max-units 4000")).is_ok());
+
+    let mut config = MatchConfig::default();
+    config.join_tokens = true;
+    config.players.push(PlayerConfig { name: "p1".to_string(), password: None, password_hash: None, team: 5 });
+    assert!(config.validate().is_err());
+
+    config.players[0].team = 1;
+    assert!(config.validate().is_ok());
+    assert_eq!(config.starting_teams(), vec![1]);
+}
@@ -0,0 +1,305 @@
+/*
+ Admin commands, typed on the server's stdin or sent as WebSocket text frames by anyone in the match's admins.
+
+ Commands are one line of words:
+   spawn <unit type> <team> <x> <y>    a finished unit, or a structure snapped to the grid like a builder would
+   resources <team> <prime> <energy>   sets what a team has banked, up to what it can store
+   reveal <team> [on|off]              shows a team the whole map
+   kill <unit id>
+   speed <multiplier>                  the same as GameSpeed
+   kick <name>
+   stats
+   help
+
+ Everything is handled by the match's own loop between frames. Commands that change the game (spawn, resources,
+ reveal and kill) wait for the next frame that's simulated, so they never land in the middle of one, and are
+ recorded with it so replays play out the same. Lockstep clients simulate the game themselves and would never see
+ them, so those commands are refused in lockstep. The rest happen straight away.
+*/
+
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use data::aliases::*;
+use data::game::Game;
+use data::lifecycle;
+use behavior::unit::core as unit;
+use pathing::terrain;
+use libs::netcom;
+use libs::netcom::Netcom;
+
+pub const HELP: &'static str = "spawn <unit type> <team> <x> <y>, resources <team> <prime> <energy>, reveal <team> [on|off], kill <unit id>, speed <multiplier>, kick <name>, stats, help";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Spawn { unit_type: String, team: usize, x: f64, y: f64 },
+    Resources { team: usize, prime: f64, energy: f64 },
+    Reveal { team: usize, on: bool },
+    Kill { unit: usize },
+    Speed(f64),
+    Kick(String),
+    Stats,
+    Help,
+}
+
+impl Command {
+    pub fn changes_game(&self) -> bool {
+        match *self {
+            Command::Spawn { .. } |
+            Command::Resources { .. } |
+            Command::Reveal { .. } |
+            Command::Kill { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    let arg = |ix: usize| words.get(ix).cloned().ok_or_else(|| format!("{} needs more arguments. Try help.", words[0]));
+    let number = |ix: usize| -> Result<f64, String> {
+        let word = arg(ix)?;
+        word.parse::<f64>().ok().filter(|n| n.is_finite()).ok_or_else(|| format!("{} isn't a number.", word))
+    };
+    let index = |ix: usize| -> Result<usize, String> {
+        let word = arg(ix)?;
+        word.parse::<usize>().map_err(|_| format!("{} isn't a whole number.", word))
+    };
+
+    let command = match words.first().cloned() {
+        Some("spawn") => Command::Spawn { unit_type: arg(1)?.to_string(), team: index(2)?, x: number(3)?, y: number(4)? },
+        Some("resources") => Command::Resources { team: index(1)?, prime: number(2)?, energy: number(3)? },
+        Some("reveal") => {
+            let on = match words.get(2).cloned() {
+                None | Some("on") => true,
+                Some("off") => false,
+                Some(other) => return Err(format!("{} isn't on or off.", other)),
+            };
+            Command::Reveal { team: index(1)?, on: on }
+        }
+        Some("kill") => Command::Kill { unit: index(1)? },
+        Some("speed") => Command::Speed(number(1)?),
+        Some("kick") => Command::Kick(arg(1)?.to_string()),
+        Some("stats") => Command::Stats,
+        Some("help") => Command::Help,
+        Some(other) => return Err(format!("Unknown command {}. Try help.", other)),
+        None => return Err("Empty command.".to_string()),
+    };
+
+    Ok(command)
+}
+
+// Carries out a command that changes the game. Returns what to tell whoever sent it.
+pub fn apply(game: &mut Game, command: &Command) -> Result<String, String> {
+    match *command {
+        Command::Spawn { ref unit_type, team, x, y } => {
+            let team = existing_team(game, team)?;
+            spawn(game, unit_type, team, (x, y))
+        }
+        Command::Resources { team, prime, energy } => {
+            let team = existing_team(game, team)?;
+            game.teams.prime[team] = f64::min(game.teams.max_prime[team], f64::max(0.0, prime));
+            game.teams.energy[team] = f64::min(game.teams.max_energy[team], f64::max(0.0, energy));
+            Ok(format!("Team {} has {} prime and {} energy.", unsafe { team.usize_unwrap() }, game.teams.prime[team], game.teams.energy[team]))
+        }
+        Command::Reveal { team, on } => {
+            let team = existing_team(game, team)?;
+            game.teams.revealed[team] = on;
+            Ok(format!("Team {} {} the whole map.", unsafe { team.usize_unwrap() }, if on { "sees" } else { "no longer sees" }))
+        }
+        Command::Kill { unit: unit_usize } => {
+            let id = game.units.iter().into_iter().find(|&id| unsafe { id.usize_unwrap() } == unit_usize)
+                .ok_or_else(|| format!("There's no unit {}.", unit_usize))?;
+            let health = game.units.health(id);
+
+            unit::damage_unit(game, id, health + 1.0);
+            Ok(format!("Killed unit {}.", unit_usize))
+        }
+        _ => Err("That doesn't change the game.".to_string()),
+    }
+}
+
+fn existing_team(game: &Game, team: usize) -> Result<TeamID, String> {
+    game.teams.iter().into_iter().find(|&t| unsafe { t.usize_unwrap() } == team).ok_or_else(|| format!("There's no team {}.", team))
+}
+
+fn spawn(game: &mut Game, unit_type: &str, team: TeamID, (x, y): (f64, f64)) -> Result<String, String> {
+    let type_id = game.units.type_named(unit_type).ok_or_else(|| format!("There's no unit type {}.", unit_type))?;
+    let proto = game.units.proto(type_id);
+
+    // Structures take up whole tiles, the same as when they're built
+    let (xy, footprint) = match proto.width_and_height() {
+        Some((w, h)) if proto.is_structure() => {
+            let (hw, hh) = (w as f64 * 0.5, h as f64 * 0.5);
+            let (ix, iy) = ((x - hw + 0.0001) as isize, (y - hh + 0.0001) as isize);
+            let tiles: Vec<(isize, isize)> = (ix..ix + w).flat_map(|xo| (iy..iy + h).map(move |yo| (xo, yo))).collect();
+            ((ix as f64 + hw, iy as f64 + hh), tiles)
+        }
+        _ => ((x, y), vec![(x as isize, y as isize)]),
+    };
+
+    if x < 0.0 || y < 0.0 || footprint.iter().any(|&tile| !game.bytegrids[terrain::grid_class(proto.move_type())].is_open(tile)) {
+        return Err(format!("{} doesn't fit at ({}, {}).", unit_type, x, y));
+    }
+
+    let fps = game.fps();
+    let id = game.units.make(fps, type_id).ok_or_else(|| "There are no unit IDs left.".to_string())?;
+    let build_cost = game.units.build_cost(id);
+    let max_health = game.units.max_health(id);
+
+    game.units.set_xy(id, xy);
+    game.units.set_team(id, team);
+    game.units.set_progress(id, build_cost);
+    game.units.set_health(id, max_health);
+
+    if game.units.is_structure(id) {
+        terrain::close_tiles(game, team, &footprint);
+    }
+
+    Ok(format!("Spawned {} as unit {}.", unit_type, unsafe { id.usize_unwrap() }))
+}
+
+pub fn stats(game: &Game, connected: &[(String, netcom::Role)]) -> String {
+    let mut lines = vec![format!(
+        "Frame {}, {:?} at {}x, {} units, {} missiles.",
+        game.frame_number, game.lifecycle.phase(), game.lifecycle.speed(), game.units.iter().len(), game.missiles.iter().len(),
+    )];
+
+    for &team in &game.teams.iter() {
+        let units = game.units.iter().into_iter().filter(|&id| game.units.team(id) == team).count();
+
+        lines.push(format!(
+            "Team {}: {} units, {:.0}/{:.0} prime, {:.0}/{:.0} energy, score {:.0}{}{}",
+            unsafe { team.usize_unwrap() }, units,
+            game.teams.prime[team], game.teams.max_prime[team], game.teams.energy[team], game.teams.max_energy[team],
+            lifecycle::score(game, team),
+            if game.lifecycle.is_defeated(team) { ", out" } else { "" },
+            if game.teams.revealed[team] { ", revealed" } else { "" },
+        ));
+    }
+
+    let names: Vec<&str> = connected.iter().map(|c| c.0.as_str()).collect();
+    lines.push(format!("Connected: {}", if names.is_empty() { "nobody".to_string() } else { names.join(", ") }));
+    lines.join("\n")
+}
+
+// Commands waiting for the next frame that's simulated, with who sent them.
+pub struct Console {
+    pending: Vec<(Option<String>, String, Command)>,
+}
+
+impl Console {
+    pub fn new() -> Console {
+        Console {
+            pending: Vec::new(),
+        }
+    }
+
+    // Takes the commands sent since the last frame. is_admin says who's allowed to send them.
+    pub fn take_commands<F: Fn(&str) -> bool>(&mut self, game: &mut Game, net: &Arc<Mutex<Netcom>>, is_admin: F) {
+        for (from, line) in netcom::get_commands(net) {
+            if let Some(ref name) = from {
+                if !is_admin(name) {
+                    game.sink.strike(name, "Only admins can send commands.");
+                    continue;
+                }
+            }
+
+            let reply = match parse(&line) {
+                Ok(ref command) if command.changes_game() && game.lockstep.is_some() => {
+                    "Lockstep clients simulate the match themselves, so it can't be changed from here.".to_string()
+                }
+                Ok(command) => {
+                    if command.changes_game() {
+                        self.pending.push((from, line, command));
+                        continue;
+                    }
+
+                    self.run(game, net, command)
+                }
+                Err(e) => e,
+            };
+
+            answer(net, &from, &reply);
+        }
+    }
+
+    // Applies the commands that change the game. Call right before a frame is simulated.
+    // Returns the lines that did something, to be recorded with the frame.
+    pub fn apply_pending(&mut self, game: &mut Game, net: &Arc<Mutex<Netcom>>) -> Vec<String> {
+        let mut applied = Vec::new();
+
+        for (from, line, command) in self.pending.drain(..) {
+            let reply = match apply(game, &command) {
+                Ok(reply) => {
+                    applied.push(line);
+                    reply
+                }
+                Err(e) => e,
+            };
+
+            answer(net, &from, &reply);
+        }
+
+        applied
+    }
+
+    fn run(&mut self, game: &mut Game, net: &Arc<Mutex<Netcom>>, command: Command) -> String {
+        match command {
+            Command::Speed(speed) => {
+                game.lifecycle.set_speed(speed);
+                lifecycle::announce_match_state(game);
+                format!("Running at {}x.", game.lifecycle.speed())
+            }
+            Command::Kick(name) => {
+                if netcom::kick(net, &name) {
+                    format!("Kicked {}.", name)
+                }
+                else {
+                    format!("{} isn't connected.", name)
+                }
+            }
+            Command::Stats => stats(game, &netcom::connected(net)),
+            Command::Help => HELP.to_string(),
+            _ => "That changes the game.".to_string(),
+        }
+    }
+}
+
+fn answer(net: &Arc<Mutex<Netcom>>, to: &Option<String>, reply: &str) {
+    match *to {
+        Some(ref name) => netcom::send_text(net, name, reply),
+        None => println!("{}", reply),
+    }
+}
+
+// Hands every line typed on stdin to handle, on a thread of its own.
+pub fn read_stdin<F: Fn(String) + Send + 'static>(handle: F) {
+    thread::spawn(move || {
+        let stdin = io::stdin();
+
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        handle(line.trim().to_string());
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+#[test]
+fn commands_parse() {
+    assert_eq!(parse("spawn Medium1 1 10.5 20"), Ok(Command::Spawn { unit_type: "Medium1".to_string(), team: 1, x: 10.5, y: 20.0 }));
+    assert_eq!(parse("reveal 0"), Ok(Command::Reveal { team: 0, on: true }));
+    assert_eq!(parse("reveal 0 off"), Ok(Command::Reveal { team: 0, on: false }));
+    assert_eq!(parse("  kick   bob "), Ok(Command::Kick("bob".to_string())));
+    assert!(parse("speed NaN").is_err());
+    assert!(parse("kill").is_err());
+    assert!(parse("explode").is_err());
+}
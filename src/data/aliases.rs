@@ -4,14 +4,19 @@
 
 use data::move_groups::MoveGroup;
 use data::build_groups::BuildGroup;
+use data::patrol_groups::PatrolGroup;
+use data::escort_groups::EscortGroup;
 use data::units::UnitTarget;
 use std::rc::Rc;
 use std::collections::HashSet;
 use std::collections::vec_deque::VecDeque;
+use std::io;
+use data::persist::{Persist, Writer, Reader};
 
 pub use data::uid_types::*;
 pub use data::target_type::*;
 pub use data::units::{Missile};
+pub use data::protocol::{ClientMessage, ServerMessage};
 
 pub type AnimID = usize;
 pub type ProducerID = usize;
@@ -19,7 +24,9 @@ pub type AbilityID = usize;
 pub type ProducerTypeID = usize;
 pub type Milliseconds = isize;
 
-pub const FPS: usize = 10;
+// The frame rate unit collision strength was tuned at.
+// Collision pushes are scaled by this over the game's fps so crowds spread at the same pace at any tick rate.
+pub const COLLISION_TUNING_FPS: f64 = 10.0;
 
 #[derive(Clone, Copy, Debug)]
 pub struct Visibility {
@@ -83,6 +90,22 @@ impl Visibility {
 	}
 }
 
+impl Persist for Visibility {
+	fn save(&self, w: &mut Writer) {
+		self.radar.save(w);
+		self.vision.save(w);
+	}
+	fn load(r: &mut Reader) -> io::Result<Visibility> {
+		let radar = Persist::load(r)?;
+		let vision = Persist::load(r)?;
+
+		Ok(Visibility {
+			radar: radar,
+			vision: vision,
+		})
+	}
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum Damage {
     Single(f64),
@@ -115,6 +138,51 @@ pub enum MoveType {
 }
 }
 
+/*
+How far a unit goes out of its way to fight.
+Hold position units only shoot at what comes in range, maneuvering units chase enemies within their engagement range
+while attack moving or patrolling, and roaming units go after anything they see even when they have no orders.
+*/
+enum_from_primitive! {
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum MoveStance {
+    HoldPosition = 0,
+    Maneuver = 1,
+    Roam = 2,
+}
+}
+
+/*
+What a unit shoots at by itself. Targets of attack orders are always shot at.
+Return fire shoots back for RETURN_FIRE_SECONDS after the unit is hurt.
+*/
+enum_from_primitive! {
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum FireStance {
+    HoldFire = 0,
+    ReturnFire = 1,
+    FireAtWill = 2,
+}
+}
+
+pub const RETURN_FIRE_SECONDS: f64 = 5.0;
+
+/*
+How a group arranges itself at the end of a move.
+Cluster heads everyone for the same point, preserve keeps the group laid out the way it was, and line, box and wedge
+form up facing the way the group is going. Groups in any formation but cluster move at the pace of their slowest member.
+*/
+enum_from_primitive! {
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Formation {
+    Cluster = 0,
+    Preserve = 1,
+    Line = 2,
+    Box = 3,
+    Wedge = 4,
+}
+}
+
 #[derive(Clone, Debug)]
 pub enum Attack {
     // A homing or non-homing projectile
@@ -163,6 +231,9 @@ pub enum OrderType {
     Build(BuildGroup),
     Assist(UnitTarget),
 	Stop,
+    Patrol(PatrolGroup),
+    Guard(EscortGroup),
+    Follow(EscortGroup),
 }
 
 enum_from_primitive! {
@@ -175,38 +246,6 @@ pub enum QueueOrder {
 }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub enum ClientMessage {
-    UnitMove,
-    UnitDeath,
-    OrderCompleted,
-    TrainingCompleted,
-    MeleeSmack,
-    MissileMove,
-    MissileExplode,
-    Construction,
-    TeamInfo,
-    MapInfo,
-    UnitInfo,
-    MissileInfo,
-}
-
-enum_from_primitive! {
-#[derive(Clone, Copy, Debug)]
-pub enum ServerMessage {
-    Move,
-    AttackMove,
-    AttackTarget,
-    Build,
-    Train,
-    Assist,
-	Stop,
-    MapInfoRequest,
-    UnitInfoRequest,
-    MissileInfoRequest,
-}
-}
-
 #[derive(Clone, Debug)]
 pub struct Builder {
     rate: f64,
@@ -2,6 +2,8 @@ extern crate core;
 
 use data::aliases::*;
 use data::units::UnitTarget;
+use data::persist::{Persist, Writer, Reader, invalid};
+use std::io;
 use self::core::cell::Cell;
 
 #[derive(Clone, Debug)]
@@ -36,3 +38,41 @@ impl BuildGroup {
         self.build_target.set(target);
     }
 }
+
+impl Persist for BuildTarget {
+    fn save(&self, w: &mut Writer) {
+        match *self {
+            BuildTarget::Point(xy) => {
+                0u8.save(w);
+                xy.save(w);
+            }
+            BuildTarget::Unit(target) => {
+                1u8.save(w);
+                target.save(w);
+            }
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<BuildTarget> {
+        match u8::load(r)? {
+            0 => Ok(BuildTarget::Point(Persist::load(r)?)),
+            1 => Ok(BuildTarget::Unit(Persist::load(r)?)),
+            _ => Err(invalid("build target")),
+        }
+    }
+}
+
+impl Persist for BuildGroup {
+    fn save(&self, w: &mut Writer) {
+        self.build_type.save(w);
+        self.build_target.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<BuildGroup> {
+        let build_type = Persist::load(r)?;
+        let build_target = Persist::load(r)?;
+
+        Ok(BuildGroup {
+            build_type: build_type,
+            build_target: build_target,
+        })
+    }
+}
@@ -0,0 +1,280 @@
+/*
+ Hashing and comparing the authoritative state of a game.
+
+ State is walked in a fixed order: the frame and the next random number, then every unit in UIDPool order with its
+ weapons, orders and train queue, then every missile, then every team. The checksum is an FNV hash of that walk, so
+ two games agree on it only if every value matches bit for bit.
+
+ When two runs that should be identical don't agree, first_divergence walks both and names the first unit (or
+ missile, or team) and field that differs, which usually points straight at whatever broke determinism.
+*/
+
+extern crate fnv;
+
+use self::fnv::FnvHasher;
+use std::fmt;
+use std::hash::Hasher;
+use rand::Rng;
+use data::aliases::*;
+use data::build_groups::BuildTarget;
+use data::game::Game;
+use libs::movement::denormalize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Item {
+    Game,
+    Unit(UnitID),
+    Weapon(UnitID, usize),
+    Order(UnitID, usize),
+    Training(UnitID, usize),
+    Missile(MissileID),
+    Team(TeamID),
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe {
+            match *self {
+                Item::Game => write!(f, "game"),
+                Item::Unit(id) => write!(f, "unit {}", id.usize_unwrap()),
+                Item::Weapon(id, ix) => write!(f, "unit {} weapon {}", id.usize_unwrap(), ix),
+                Item::Order(id, ix) => write!(f, "unit {} order {}", id.usize_unwrap(), ix),
+                Item::Training(id, ix) => write!(f, "unit {} train order {}", id.usize_unwrap(), ix),
+                Item::Missile(id) => write!(f, "missile {}", id.usize_unwrap()),
+                Item::Team(id) => write!(f, "team {}", id.usize_unwrap()),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Value {
+    Int(u64),
+    Float(f64),
+}
+
+impl Value {
+    fn bits(&self) -> u64 {
+        match *self {
+            Value::Int(n) => n,
+            Value::Float(f) => f.to_bits(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{:?}", x),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    pub item: Item,
+    pub field: &'static str,
+    pub value: Value,
+}
+
+// The first place two games differ. Either side is None if that game ran out of state first.
+#[derive(Clone, Copy, Debug)]
+pub struct Divergence {
+    pub frame: u32,
+    pub a: Option<Entry>,
+    pub b: Option<Entry>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.a, self.b) {
+            (Some(a), Some(b)) if a.item == b.item && a.field == b.field => {
+                write!(f, "Frame {}: {} {} is {} in one game and {} in the other.", self.frame, a.item, a.field, a.value, b.value)
+            }
+            (Some(a), Some(b)) => {
+                write!(f, "Frame {}: one game has {} {} where the other has {} {}.", self.frame, a.item, a.field, b.item, b.field)
+            }
+            (Some(a), None) | (None, Some(a)) => {
+                write!(f, "Frame {}: only one game has {} {}.", self.frame, a.item, a.field)
+            }
+            (None, None) => write!(f, "Frame {}: no difference.", self.frame),
+        }
+    }
+}
+
+pub fn checksum(game: &Game) -> u64 {
+    let mut hasher = FnvHasher::default();
+
+    walk_state(game, &mut |entry| {
+        hasher.write_u64(entry.value.bits());
+    });
+
+    hasher.finish()
+}
+
+// Every value the checksum covers, in the order it covers them.
+pub fn dump(game: &Game) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    walk_state(game, &mut |entry| entries.push(entry));
+    entries
+}
+
+pub fn first_divergence(a: &Game, b: &Game) -> Option<Divergence> {
+    let dump_a = dump(a);
+    let dump_b = dump(b);
+
+    for ix in 0..usize::max(dump_a.len(), dump_b.len()) {
+        let entry_a = dump_a.get(ix).cloned();
+        let entry_b = dump_b.get(ix).cloned();
+
+        let same = match (entry_a, entry_b) {
+            (Some(x), Some(y)) => x.item == y.item && x.field == y.field && x.value.bits() == y.value.bits(),
+            _ => false,
+        };
+
+        if !same {
+            return Some(Divergence {
+                frame: a.frame_number,
+                a: entry_a,
+                b: entry_b,
+            });
+        }
+    }
+
+    None
+}
+
+fn walk_state<F: FnMut(Entry)>(game: &Game, emit: &mut F) {
+    let mut put = |item: Item, field: &'static str, value: Value| {
+        emit(Entry {
+            item: item,
+            field: field,
+            value: value,
+        });
+    };
+    let int = |n: usize| Value::Int(n as u64);
+    let float = |f: f64| Value::Float(f);
+
+    // Peeking at the next number catches games whose random streams have drifted apart
+    put(Item::Game, "frame", int(game.frame_number as usize));
+    put(Item::Game, "rng", Value::Int(game.rng.clone().gen::<u64>()));
+
+    for &id in &game.units.iter() {
+        let unit = Item::Unit(id);
+        let (x, y) = game.units.xy(id);
+
+        unsafe {
+            put(unit, "unit_type", int(game.units.unit_type(id).map_or(usize::max_value(), |t| t.usize_unwrap())));
+            put(unit, "soul_id", int(game.units.soul_id(id)));
+            put(unit, "team", int(game.units.team(id).usize_unwrap()));
+        }
+        put(unit, "x", float(x));
+        put(unit, "y", float(y));
+        put(unit, "facing", float(denormalize(game.units.facing(id))));
+        put(unit, "speed", float(game.units.speed(id)));
+        put(unit, "health", float(game.units.health(id)));
+        put(unit, "progress", float(game.units.progress(id)));
+        put(unit, "train_progress", float(game.units.train_progress(id)));
+        put(unit, "path_len", int(game.units.path(id).len()));
+        put(unit, "move_stance", int(game.units.move_stance(id) as usize));
+        put(unit, "fire_stance", int(game.units.fire_stance(id) as usize));
+        put(unit, "provoked", float(game.units.provoked(id)));
+
+        for (ix, wpn) in game.units.weapons(id).iter().enumerate() {
+            let weapon = Item::Weapon(id, ix);
+            put(weapon, "facing", float(denormalize(wpn.facing())));
+            put(weapon, "cooldown", float(wpn.cooldown()));
+            put(weapon, "salvo", int(wpn.salvo()));
+            put(weapon, "salvo_cooldown", float(wpn.salvo_cooldown()));
+        }
+
+        for (ix, order) in game.units.orders(id).iter().enumerate() {
+            let item = Item::Order(id, ix);
+            put(item, "order_id", int(unsafe { order.order_id.usize_unwrap() }));
+
+            match order.order_type {
+                OrderType::Move(ref mg) | OrderType::AttackMove(ref mg) | OrderType::AttackTarget(ref mg, _) => {
+                    let (gx, gy) = mg.goal();
+                    let (sx, sy) = mg.goal_of(game.units.new_unit_target(id));
+                    put(item, "goal_x", float(gx));
+                    put(item, "goal_y", float(gy));
+                    put(item, "slot_x", float(sx));
+                    put(item, "slot_y", float(sy));
+                    put(item, "top_speed", float(mg.top_speed().unwrap_or(0.0)));
+                    put(item, "dist_to_group", float(mg.dist_to_group()));
+                }
+                OrderType::Build(ref bg) => {
+                    put(item, "build_type", int(unsafe { bg.build_type().usize_unwrap() }));
+
+                    match bg.build_target() {
+                        BuildTarget::Point((bx, by)) => {
+                            put(item, "build_x", float(bx));
+                            put(item, "build_y", float(by));
+                        }
+                        BuildTarget::Unit(target) => {
+                            put(item, "build_unit", int(game.units.target_id(target).map_or(usize::max_value(), |t| unsafe { t.usize_unwrap() })));
+                        }
+                    }
+                }
+                OrderType::Assist(target) => {
+                    put(item, "assist", int(game.units.target_id(target).map_or(usize::max_value(), |t| unsafe { t.usize_unwrap() })));
+                }
+                OrderType::Stop => {
+                    put(item, "stop", int(0));
+                }
+                OrderType::Patrol(ref pg) => {
+                    let (gx, gy) = pg.move_group().goal();
+                    put(item, "patrol_leg", int(pg.leg()));
+                    put(item, "goal_x", float(gx));
+                    put(item, "goal_y", float(gy));
+                    put(item, "dist_to_group", float(pg.move_group().dist_to_group()));
+                    put(item, "waypoints", int(pg.waypoints().len()));
+
+                    for &(wx, wy) in pg.waypoints() {
+                        put(item, "waypoint_x", float(wx));
+                        put(item, "waypoint_y", float(wy));
+                    }
+                    put(item, "arrived", int(pg.has_arrived(game.units.new_unit_target(id)) as usize));
+                }
+                OrderType::Guard(ref eg) | OrderType::Follow(ref eg) => {
+                    let field = match order.order_type { OrderType::Guard(_) => "guard", _ => "follow" };
+                    let (sx, sy) = eg.offset_of(game.units.new_unit_target(id)).unwrap_or((0.0, 0.0));
+                    put(item, field, int(game.units.target_id(eg.target()).map_or(usize::max_value(), |t| unsafe { t.usize_unwrap() })));
+                    put(item, "slot_x", float(sx));
+                    put(item, "slot_y", float(sy));
+                }
+            }
+        }
+
+        for (ix, train_order) in game.units.train_queue(id).iter().enumerate() {
+            let item = Item::Training(id, ix);
+
+            unsafe {
+                put(item, "order_id", int(train_order.order_id.usize_unwrap()));
+                put(item, "unit_type", int(train_order.unit_type.usize_unwrap()));
+            }
+            put(item, "repeat", int(train_order.repeat as usize));
+        }
+    }
+
+    for &id in &game.missiles.iter() {
+        let missile = Item::Missile(id);
+        let (x, y) = game.missiles.xy(id);
+
+        unsafe {
+            put(missile, "missile_type", int(game.missiles.missile_type_id(id).map_or(usize::max_value(), |t| t.usize_unwrap())));
+            put(missile, "team", int(game.missiles.team(id).usize_unwrap()));
+        }
+        put(missile, "x", float(x));
+        put(missile, "y", float(y));
+        put(missile, "facing", float(denormalize(game.missiles.facing(id))));
+        put(missile, "travel_dist", float(game.missiles.travel_dist(id)));
+    }
+
+    for &team in &game.teams.iter() {
+        let item = Item::Team(team);
+        put(item, "prime", float(game.teams.prime[team]));
+        put(item, "energy", float(game.teams.energy[team]));
+    }
+}
@@ -0,0 +1,107 @@
+/* Units guarding or following another unit spread out on a ring around it, each in a slot of its own.
+Slots are measured from the way the escorted unit faces, so the ring turns with it and everybody keeps their place.
+Each slot goes to the escort nearest it when the order is given, so whoever started out in front stays in front.
+*/
+use std::f64;
+use std::f64::consts::PI;
+use std::collections::HashMap;
+use std::io;
+use data::units::UnitTarget;
+use data::move_groups::assign_nearest;
+use data::persist::{Persist, Writer, Reader};
+
+// Guards fight enemies that come this close to whoever they guard, and no further
+pub const GUARD_LEASH: f64 = 12.0;
+// Space between the escorted unit and the ring
+const ESCORT_GAP: f64 = 1.0;
+
+#[derive(Clone, Debug)]
+pub struct EscortGroup {
+    target: UnitTarget,
+    slots: HashMap<UnitTarget, (f64, f64)>,
+}
+
+impl EscortGroup {
+    // members are the escorts with where they are and their radii. The escorted unit is at xy facing facing (in radians).
+    pub fn new(target: UnitTarget, (tx, ty): (f64, f64), facing: f64, target_radius: f64, members: &[(UnitTarget, (f64, f64), f64)]) -> EscortGroup {
+        let n = members.len().max(1) as f64;
+        let widest = members.iter().fold(0.0, |widest, &(_, _, radius)| f64::max(widest, radius));
+        // Far enough out that the escorts fit side by side
+        let ring = f64::max(target_radius + widest + ESCORT_GAP, n * widest * 2.0 / (2.0 * PI));
+
+        // Where everyone is, measured the same way as the slots
+        let (sin, cos) = facing.sin_cos();
+        let from_target: Vec<(f64, f64)> = members.iter().map(|&(_, (x, y), _)| {
+            let (dx, dy) = (x - tx, y - ty);
+            (dx * cos + dy * sin, dy * cos - dx * sin)
+        }).collect();
+
+        // Evenly round the ring, each to whoever's nearest it
+        let slot_xys: Vec<(f64, f64)> = (0..members.len()).map(|ix| {
+            let angle = 2.0 * PI * ix as f64 / n;
+            (ring * f64::cos(angle), ring * f64::sin(angle))
+        }).collect();
+        let slots = slot_xys.iter().zip(assign_nearest(&from_target, &slot_xys)).map(|(&slot, member)| {
+            (members[member].0, slot)
+        }).collect();
+
+        EscortGroup {
+            target: target,
+            slots: slots,
+        }
+    }
+
+    pub fn target(&self) -> UnitTarget {
+        self.target
+    }
+
+    // Where member's slot is from the escorted unit, before turning it the way the unit faces.
+    pub fn offset_of(&self, member: UnitTarget) -> Option<(f64, f64)> {
+        self.slots.get(&member).cloned()
+    }
+
+    // Where member should be when the escorted unit is at xy facing facing (in radians).
+    pub fn slot(&self, member: UnitTarget, (x, y): (f64, f64), facing: f64) -> Option<(f64, f64)> {
+        self.slots.get(&member).map(|&(ox, oy)| {
+            let (sin, cos) = facing.sin_cos();
+            (x + ox * cos - oy * sin, y + ox * sin + oy * cos)
+        })
+    }
+}
+
+impl Persist for EscortGroup {
+    fn save(&self, w: &mut Writer) {
+        self.target.save(w);
+        self.slots.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<EscortGroup> {
+        let target = Persist::load(r)?;
+        let slots = Persist::load(r)?;
+
+        Ok(EscortGroup {
+            target: target,
+            slots: slots,
+        })
+    }
+}
+
+#[test]
+fn escorts_take_the_slot_nearest_them() {
+    use simulation::test_game;
+
+    let game = &mut test_game(10.0, 0, &[(20.5, 40.5), (26.5, 40.5), (14.5, 40.5)]);
+    let ids = game.units.iter();
+    let targets: Vec<UnitTarget> = ids.iter().map(|&id| game.units.new_unit_target(id)).collect();
+    let (ahead, behind) = (targets[1], targets[2]);
+
+    // Listed the other way round, so going in order would put the one behind in front
+    let eg = EscortGroup::new(targets[0], (20.5, 40.5), 0.0, 0.5, &[(behind, (14.5, 40.5), 0.5), (ahead, (26.5, 40.5), 0.5)]);
+
+    assert!(eg.slot(ahead, (20.5, 40.5), 0.0).unwrap().0 > 20.5);
+    assert!(eg.slot(behind, (20.5, 40.5), 0.0).unwrap().0 < 20.5);
+
+    // Facing the other way, each still gets the slot on their own side
+    let eg = EscortGroup::new(targets[0], (20.5, 40.5), PI, 0.5, &[(ahead, (26.5, 40.5), 0.5), (behind, (14.5, 40.5), 0.5)]);
+
+    assert!(eg.slot(ahead, (20.5, 40.5), PI).unwrap().0 > 20.5);
+}
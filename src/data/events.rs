@@ -0,0 +1,156 @@
+/*
+ One-shot events (deaths, explosions, completed orders...) only happen once, so unlike unit state they can't be
+ repaired by the next frame. Each frame's events for a team become a numbered batch that is kept until the team
+ acknowledges it, and resent every so often until then.
+
+ Sequence numbers start at 1 and go up by one per batch. Clients acknowledge the highest sequence number they have
+ received every batch up to, and ignore batches they have already applied.
+*/
+
+extern crate byteorder;
+
+use self::byteorder::{WriteBytesExt, BigEndian};
+use std::collections::vec_deque::VecDeque;
+use std::io::Cursor;
+use std::io::prelude::*;
+use data::aliases::*;
+use data::protocol::write_record;
+
+// Past this the team is assumed to be gone and its oldest batches are thrown away
+pub const MAX_UNACKED_BATCHES: usize = 1024;
+// How long to wait for an acknowledgement before sending a batch again
+pub const EVENT_RESEND_SECONDS: f64 = 0.5;
+
+#[derive(Clone, Debug)]
+struct EventBatch {
+    seq: u32,
+    frame: u32,
+    last_sent: Option<u32>,
+    records: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct EventChannels {
+    next_seq: VecUID<TeamID, u32>,
+    unacked: VecUID<TeamID, VecDeque<EventBatch>>,
+}
+
+impl EventChannels {
+    pub fn new(max_teams: usize) -> EventChannels {
+        EventChannels {
+            next_seq: VecUID::full_vec(max_teams, 1),
+            unacked: VecUID::full_vec(max_teams, VecDeque::new()),
+        }
+    }
+
+    // Queues a frame's encoded event records. Frames without events don't use up a sequence number.
+    pub fn push(&mut self, team: TeamID, frame: u32, records: Vec<u8>) {
+        if records.is_empty() {
+            return;
+        }
+
+        let seq = self.next_seq[team];
+        self.next_seq[team] += 1;
+
+        let unacked = &mut self.unacked[team];
+        unacked.push_back(EventBatch {
+            seq: seq,
+            frame: frame,
+            last_sent: None,
+            records: records,
+        });
+
+        if unacked.len() > MAX_UNACKED_BATCHES {
+            unacked.pop_front();
+        }
+    }
+
+    // The sequence number of the newest batch, 0 before there are any.
+    pub fn last_seq(&self, team: TeamID) -> u32 {
+        self.next_seq[team] - 1
+    }
+
+    // Everything up to and including seq has arrived.
+    pub fn acknowledge(&mut self, team: TeamID, seq: u32) {
+        let unacked = &mut self.unacked[team];
+
+        while unacked.front().map_or(false, |b| b.seq <= seq) {
+            unacked.pop_front();
+        }
+    }
+
+    // Resends every batch, for a client that's starting over.
+    pub fn resend_all(&mut self, team: TeamID) {
+        for batch in self.unacked[team].iter_mut() {
+            batch.last_sent = None;
+        }
+    }
+
+    // Writes the batches that are new or haven't been acknowledged in resend_after frames.
+    pub fn encode(&mut self, team: TeamID, frame: u32, resend_after: u32, vec: &mut Cursor<Vec<u8>>) {
+        for batch in self.unacked[team].iter_mut() {
+            let due = match batch.last_sent {
+                Some(last_sent) => frame.wrapping_sub(last_sent) >= resend_after,
+                None => true,
+            };
+
+            if due {
+                batch.last_sent = Some(frame);
+
+                write_record(vec, ClientMessage::EventBatch, |rec| {
+                    let _ = rec.write_u32::<BigEndian>(batch.seq);
+                    let _ = rec.write_u32::<BigEndian>(batch.frame);
+                    let _ = rec.write_all(&batch.records);
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn batch_seqs(frame: Vec<u8>) -> Vec<u32> {
+    use self::byteorder::ReadBytesExt;
+    use data::protocol::read_varint;
+
+    let len = frame.len() as u64;
+    let mut bytes = Cursor::new(frame);
+    let mut seqs = Vec::new();
+    bytes.set_position(4);
+
+    while bytes.position() < len {
+        assert_eq!(bytes.read_u8().unwrap(), ClientMessage::EventBatch as u8);
+        let rec_len = read_varint(&mut bytes).unwrap() as u64;
+        let pos = bytes.position();
+        seqs.push(bytes.read_u32::<BigEndian>().unwrap());
+        bytes.set_position(pos + rec_len);
+    }
+
+    seqs
+}
+
+#[test]
+fn events_are_resent_until_acknowledged() {
+    use data::protocol::new_frame;
+
+    let team = unsafe { TeamID::usize_wrap(0) };
+    let mut events = EventChannels::new(1);
+    let send = |events: &mut EventChannels, frame: u32| {
+        let mut msg = new_frame(frame);
+        events.encode(team, frame, 3, &mut msg);
+        batch_seqs(msg.into_inner())
+    };
+
+    events.push(team, 0, vec![1, 2, 3]);
+    events.push(team, 0, Vec::new());
+    assert_eq!(send(&mut events, 0), vec![1]);
+
+    events.push(team, 1, vec![4]);
+    assert_eq!(send(&mut events, 1), vec![2]);
+    assert_eq!(send(&mut events, 2), Vec::<u32>::new());
+
+    // Batch 1 is due again, batch 2 isn't yet
+    assert_eq!(send(&mut events, 3), vec![1]);
+
+    events.acknowledge(team, 2);
+    assert_eq!(send(&mut events, 10), Vec::<u32>::new());
+}
@@ -1,50 +1,71 @@
 
-use rand::prelude::ThreadRng;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64Mcg;
 use libs::kdt::KDTree;
 use libs::bytegrid::ByteGrid;
-use libs::netcom::{Netcom, send_message_to_player};
+use pathing::terrain;
+use libs::netcom::{MessageSink, Role};
 use libs::tmx_decode::MapData;
+use libs::movement as mv;
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 use num::FromPrimitive;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::io::Cursor;
 use std::io;
+use std::io::prelude::*;
 use data::logger::Logger;
-use data::units::{Units, Unit, Missiles, Missile};
+use data::snapshots::Snapshots;
+use data::events::EventChannels;
+use data::keyframe::send_keyframe;
+use data::lockstep::{self, Lockstep};
+use data::lifecycle::Lifecycle;
+use data::checksum;
+use data::units::{Units, Unit, Missiles, Missile, UnitTarget};
 use data::kdt_point::{KDTUnit, KDTMissile};
 use data::teams::Teams;
 use data::move_groups::MoveGroup;
+use data::patrol_groups::PatrolGroup;
+use data::escort_groups::EscortGroup;
 use data::build_groups::{BuildGroup, BuildTarget};
 use std::collections::{HashSet};
 use std::iter::FromIterator;
 use std::rc::Rc;
 use data::aliases::*;
+use data::protocol::{new_frame, write_record, RejectReason, OBSERVER_TEAM};
 
 #[derive(Clone)]
 pub struct Game {
     fps: f64,
+    seed: u64,
     max_units: usize,
+    max_teams: usize,
     max_weapons: usize,
     max_missiles: usize,
     encoded_map_data: Vec<u8>,
     encoded_unit_info: Vec<u8>,
     encoded_missile_info: Vec<u8>,
-    pub rng: ThreadRng,
+    pub rng: Pcg64Mcg,
     pub map_data: MapData,
     pub units: Units,
     pub missiles: Missiles,
     pub teams: Teams,
     pub unit_kdt: KDTree<KDTUnit>,
     pub missile_kdt: KDTree<KDTMissile>,
-    pub bytegrid: ByteGrid,
+    // One for each movement class in pathing::terrain
+    pub bytegrids: Vec<ByteGrid>,
     pub logger: Logger,
-    pub netcom: Arc<Mutex<Netcom>>,
+    pub snapshots: Snapshots,
+    pub events: EventChannels,
+    pub sink: Arc<dyn MessageSink>,
+    pub lockstep: Option<Lockstep>,
+    pub lifecycle: Lifecycle,
     pub frame_number: u32,
 }
 
 impl Game {
     pub fn new(
+        fps: f64,
+        seed: u64,
         max_units: usize,
         max_teams: usize,
         map_data: MapData,
@@ -54,16 +75,18 @@ impl Game {
         missile_id_map: UIDMapping<MissileTypeID>,
         encoded_unit_info: Vec<u8>,
         encoded_missile_info: Vec<u8>,
-        netcom: Arc<Mutex<Netcom>>,
+        sink: Arc<dyn MessageSink>,
     ) -> Game {
         let (width, height) = map_data.width_and_height();
 
         Game {
-            fps: 10.0,
+            fps: fps,
+            seed: seed,
             max_units: max_units,
+            max_teams: max_teams,
             max_weapons: max_units * 2,
             max_missiles: max_units * 4,
-            rng: rand::thread_rng(),
+            rng: Pcg64Mcg::seed_from_u64(seed),
             encoded_map_data: map_data.encode(),
             encoded_unit_info: encoded_unit_info,
             encoded_missile_info: encoded_missile_info,
@@ -73,9 +96,13 @@ impl Game {
             teams: Teams::new(max_units, max_teams, width, height),
             unit_kdt: KDTree::new(Vec::new()),
             missile_kdt: KDTree::new(Vec::new()),
-            bytegrid: ByteGrid::new(width as isize, height as isize),
+            bytegrids: vec![ByteGrid::new(width as isize, height as isize); terrain::MOVE_CLASSES],
             logger: Logger::new(),
-            netcom: netcom,
+            snapshots: Snapshots::new(max_teams * 2 + 1),
+            events: EventChannels::new(max_teams * 2 + 1),
+            sink: sink,
+            lockstep: None,
+            lifecycle: Lifecycle::new(),
             frame_number: 0,
         }
     }
@@ -84,6 +111,17 @@ impl Game {
         self.fps
     }
 
+    // Seconds of game time that pass each frame.
+    pub fn frame_time(&self) -> f64 {
+        1.0 / self.fps
+    }
+
+    // Every bit of gameplay randomness comes from rng, which is built from this seed.
+    // Two games with the same seed, prototypes and inputs play out identically.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
     pub fn max_units(&self) -> usize {
         self.max_units
     }
@@ -94,6 +132,29 @@ impl Game {
         self.max_missiles
     }
 
+    // Snapshots and event channels have a slot for every team, then this one for spectators who see the whole map.
+    pub fn observer_slot(&self) -> TeamID {
+        unsafe { TeamID::usize_wrap(self.max_teams) }
+    }
+
+    // Then one for the spectators of each team, kept apart so they never touch what the team's players were sent.
+    pub fn spectator_slot(&self, team: TeamID) -> TeamID {
+        unsafe { TeamID::usize_wrap(self.max_teams + 1 + team.usize_unwrap()) }
+    }
+
+    // A hash of the state every client should agree on at the end of a frame, see data::checksum.
+    pub fn checksum(&self) -> u64 {
+        checksum::checksum(self)
+    }
+
+    // A copy that shares nothing with this game, so stepping one leaves the other alone.
+    // Plain clones share orders, and with them the move and build groups inside.
+    pub fn checkpoint(&self) -> Game {
+        let mut game = self.clone();
+        game.units.unshare_orders();
+        game
+    }
+
     // Produces a tiny random offset.
     // This is useful to avoid units occupying the same spot and being unable to collide correctly.
     pub fn get_random_collision_offset(&mut self) -> f64 {
@@ -101,68 +162,259 @@ impl Game {
     }
 }
 
-pub fn incorporate_messages(game: &mut Game, msgs: Vec<(String, usize, Vec<u8>)>) {
+pub fn incorporate_messages(game: &mut Game, msgs: Vec<(String, Role, Vec<u8>)>) {
     for msg in msgs {
-        let (name, team, data) = msg;
-        let bytes = &mut Cursor::new(data);
-
-        if let Ok(Some(msg_type)) = bytes.read_u8().map(ServerMessage::from_u8) {
-            let opt_order_id = unsafe {
-                bytes.read_u32::<BigEndian>().map(|a| {
-                    OrderID::usize_wrap(a as usize)
-                })
+        let (name, role, data) = msg;
+
+        // Anything a client sends that doesn't make sense counts against them
+        if let Err(e) = incorporate_message(game, &name, role, data) {
+            game.sink.strike(&name, &e.to_string());
+        }
+    }
+}
+
+// In lockstep the orders that run this frame came in a few frames ago.
+pub fn incorporate_due_orders(game: &mut Game) {
+    let due = match game.lockstep {
+        Some(ref mut lockstep) => lockstep.take_due(game.frame_number),
+        None => Vec::new(),
+    };
+
+    for (name, team_id, data) in due {
+        if let Err(e) = incorporate_order(game, team_id, data) {
+            game.sink.strike(&name, &e.to_string());
+        }
+    }
+}
+
+// Runs an order that was already checked to come from a player of team_id.
+fn incorporate_order(game: &mut Game, team_id: TeamID, data: Vec<u8>) -> io::Result<()> {
+    let bytes = &mut Cursor::new(data);
+    let msg_type = ServerMessage::from_u8(bytes.read_u8()?).ok_or_else(|| invalid("Unknown message type."))?;
+    let order_id = unsafe { OrderID::usize_wrap(bytes.read_u32::<BigEndian>()? as usize) };
+
+    match msg_type {
+        ServerMessage::Move => {
+            read_move_message(game, order_id, team_id, bytes)
+        }
+        ServerMessage::AttackTarget => {
+            read_attack_target_message(game, order_id, team_id, bytes)
+        }
+        ServerMessage::Build => {
+            read_build_message(game, order_id, team_id, bytes)
+        }
+        ServerMessage::Train => {
+            read_train_message(game, order_id, team_id, bytes)
+        }
+        ServerMessage::Assist => {
+            read_assist_message(game, order_id, team_id, bytes)
+        }
+        ServerMessage::Stop => {
+            read_stop_message(game, order_id, team_id, bytes)
+        }
+        ServerMessage::AttackMove => {
+            read_attack_move_message(game, order_id, team_id, bytes)
+        }
+        ServerMessage::Patrol => {
+            read_patrol_message(game, order_id, team_id, bytes)
+        }
+        ServerMessage::Stance => {
+            read_stance_message(game, team_id, bytes)
+        }
+        ServerMessage::Guard => {
+            read_escort_message(game, order_id, team_id, bytes, true)
+        }
+        ServerMessage::Follow => {
+            read_escort_message(game, order_id, team_id, bytes, false)
+        }
+        _ => Err(invalid("Not an order.")),
+    }
+}
+
+fn incorporate_message(game: &mut Game, name: &str, role: Role, data: Vec<u8>) -> io::Result<()> {
+    let bytes = &mut Cursor::new(data);
+    let msg_type = ServerMessage::from_u8(bytes.read_u8()?).ok_or_else(|| invalid("Unknown message type."))?;
+    // Orders read their id again when they run
+    bytes.read_u32::<BigEndian>()?;
+    let name = name.to_string();
+
+    if msg_type.is_order() && !role.can_give_orders() {
+        return Err(invalid("Spectators can't give orders."));
+    }
+
+    // Spectators see what whoever they watch sees, but have slots of their own. Those are always sent in full
+    // since several spectators can share one, so whatever they acknowledge is ignored.
+    let team_id = match role.view_team() {
+        Some(team) => unsafe { TeamID::usize_wrap(team) },
+        None => game.observer_slot(),
+    };
+    let spectating = !role.can_give_orders();
+    let slot = match role.view_team() {
+        Some(_) if spectating => game.spectator_slot(team_id),
+        _ => team_id,
+    };
+
+    match msg_type {
+        ServerMessage::Move |
+        ServerMessage::AttackMove |
+        ServerMessage::AttackTarget |
+        ServerMessage::Build |
+        ServerMessage::Train |
+        ServerMessage::Assist |
+        ServerMessage::Stop |
+        ServerMessage::Patrol |
+        ServerMessage::Stance |
+        ServerMessage::Guard |
+        ServerMessage::Follow => {
+            let frame_number = game.frame_number;
+
+            // Orders from before the match starts, while it's paused or after a team is out are dropped
+            if !game.lifecycle.takes_orders_from(team_id) {
+                return Ok(());
+            }
+
+            match game.lockstep {
+                Some(ref mut lockstep) => {
+                    lockstep.schedule(frame_number, name, team_id, bytes.get_ref().clone());
+                    Ok(())
+                }
+                None => incorporate_order(game, team_id, bytes.get_ref().clone()),
+            }
+        }
+        ServerMessage::MapInfoRequest => {
+            // A client asking for the map is starting from scratch
+            if !spectating {
+                game.snapshots.reset(team_id);
+                game.events.resend_all(team_id);
+            }
+            send_tilegrid_info(game, team_id, name.clone());
+            send_match_seed(game, name);
+            Ok(())
+        }
+        ServerMessage::UnitInfoRequest => {
+            send_unit_info(game, name);
+            Ok(())
+        }
+        ServerMessage::MissileInfoRequest => {
+            send_missile_info(game, name);
+            Ok(())
+        }
+        // Netcom answers handshakes before the game ever sees them
+        ServerMessage::Handshake => {
+            Err(invalid("Handshake after handshaking."))
+        }
+        ServerMessage::SnapshotAck => {
+            let frame = bytes.read_u32::<BigEndian>()?;
+
+            if !spectating {
+                game.snapshots.acknowledge(team_id, frame);
+            }
+            Ok(())
+        }
+        ServerMessage::KeyframeRequest => {
+            if game.lockstep.is_some() {
+                lockstep::send_history(game, team_id, name);
+            }
+            else {
+                send_keyframe(game, team_id, slot, name);
+            }
+            Ok(())
+        }
+        // Replays take these out before the game sees them
+        ServerMessage::ReplayControl => {
+            Err(invalid("Not watching a replay."))
+        }
+        // So does the main loop, see lifecycle::incorporate_control
+        ServerMessage::Pause |
+        ServerMessage::Resume |
+        ServerMessage::GameSpeed => {
+            Err(invalid("The match can't be paused here."))
+        }
+        ServerMessage::Checksum => {
+            let frame = bytes.read_u32::<BigEndian>()?;
+            let checksum = bytes.read_u64::<BigEndian>()?;
+            let expected = match game.lockstep {
+                Some(ref lockstep) => lockstep.checksum_at(frame),
+                None => return Err(invalid("Checksums are only sent in lockstep.")),
             };
 
-            if let Ok(order_id) = opt_order_id {
-                let team_id = unsafe { TeamID::usize_wrap(team) };
-
-                match msg_type {
-                    ServerMessage::Move => {
-                        let _ = read_move_message(game, order_id, team_id, bytes);
-                    }
-                    ServerMessage::AttackTarget => {
-                        let _ = read_attack_target_message(game, order_id, team_id, bytes);
-                    }
-                    ServerMessage::Build => {
-                        let _ = read_build_message(game, order_id, team_id, bytes);
-                    }
-                    ServerMessage::Train => {
-                        let _ = read_train_message(game, order_id, team_id, bytes);
-                    }
-                    ServerMessage::Assist => {
-                        let _ = read_assist_message(game, order_id, team_id, bytes);
-                    }
-					ServerMessage::Stop => {
-                        let _ = read_stop_message(game, order_id, team_id, bytes);
-                    }
-                    ServerMessage::AttackMove => {
-                        let _ = read_attack_move_message(game, order_id, team_id, bytes);
-                    }
-                    ServerMessage::MapInfoRequest => {
-                        send_tilegrid_info(game, team_id, name);
-                    }
-                    ServerMessage::UnitInfoRequest => {
-                        send_unit_info(game, name);
-                    }
-                    ServerMessage::MissileInfoRequest => {
-                        send_missile_info(game, name);
-                    }
+            if let Some(expected) = expected {
+                if expected != checksum {
+                    println!("{} is out of sync as of frame {}.", name, frame);
+                    let mut msg = new_frame(game.frame_number);
+                    write_record(&mut msg, ClientMessage::Desync, |rec| {
+                        let _ = rec.write_u32::<BigEndian>(frame);
+                        let _ = rec.write_u64::<BigEndian>(expected);
+                    });
+                    game.sink.send_message_to_player(msg.into_inner(), &name);
                 }
             }
+            Ok(())
         }
+        ServerMessage::EventAck => {
+            let seq = bytes.read_u32::<BigEndian>()?;
+
+            if !spectating {
+                game.events.acknowledge(team_id, seq);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn read_queue_order(bytes: &mut Cursor<Vec<u8>>) -> io::Result<QueueOrder> {
+    QueueOrder::from_u8(bytes.read_u8()?).ok_or_else(|| invalid("Unknown queue order."))
+}
+
+// A point that has to be on the map.
+fn read_point(game: &Game, bytes: &mut Cursor<Vec<u8>>) -> io::Result<(f64, f64)> {
+    let x = bytes.read_f64::<BigEndian>()?;
+    let y = bytes.read_f64::<BigEndian>()?;
+    let (w, h) = game.map_data.width_and_height();
+
+    if x.is_finite() && y.is_finite() && x >= 0.0 && y >= 0.0 && x < w as f64 && y < h as f64 {
+        Ok((x, y))
+    }
+    else {
+        Err(invalid("Point is off the map."))
+    }
+}
+
+fn read_unit_id(game: &Game, bytes: &mut Cursor<Vec<u8>>) -> io::Result<UnitID> {
+    let id = bytes.read_u16::<BigEndian>()? as usize;
+
+    if id < game.max_units {
+        Ok(unsafe { UnitID::usize_wrap(id) })
+    }
+    else {
+        Err(invalid("Unit ID out of range."))
+    }
+}
+
+fn read_unit_type(game: &Game, bytes: &mut Cursor<Vec<u8>>) -> io::Result<UnitTypeID> {
+    let unit_type = bytes.read_u16::<BigEndian>()? as usize;
+
+    if unit_type < game.units.num_unit_types() {
+        Ok(unsafe { UnitTypeID::usize_wrap(unit_type) })
+    }
+    else {
+        Err(invalid("Unit type out of range."))
     }
 }
 
 fn get_order_units(game: &Game, team_id: TeamID, bytes: &mut Cursor<Vec<u8>>) -> io::Result<Vec<UnitID>> {
     let mut vec = Vec::new();
+    let len = bytes.get_ref().len() as u64;
 
-    while let Ok(uid) = bytes.read_u16::<BigEndian>() {
-        let unit_id = unsafe { UnitID::usize_wrap(uid as usize) };
+    while bytes.position() < len {
+        let unit_id = read_unit_id(game, bytes)?;
 
-        if (uid as usize) < game.max_units &&
-            game.units.team(unit_id) == team_id &&
-            !game.units.is_automatic(unit_id)
-        {
+        // Units can die or change hands before an order arrives, so those are skipped rather than refused
+        if game.units.team(unit_id) == team_id && !game.units.is_automatic(unit_id) {
             vec.push(unit_id);
         }
     }
@@ -198,48 +450,82 @@ fn add_order_to_units(game: &mut Game, team_id: TeamID, order: Rc<Order>, units:
 }
 
 fn send_unit_info(game: &Game, name: String) {
-    // We add 5 bytes to the encoded data for the frame number and message tag
-    let len = game.encoded_unit_info.len() + 5;
-    let mut msg = Cursor::new(Vec::with_capacity(len));
-    let _ = msg.write_u32::<BigEndian>(game.frame_number);
+    // The encoded data is already a list of records so it only needs the frame number in front
+    let len = game.encoded_unit_info.len() + 4;
+    let mut msg = new_frame(game.frame_number);
+    msg.get_mut().reserve(len);
     let mut bytes = msg.into_inner();
     bytes.append(&mut game.encoded_unit_info.clone());
 
-    send_message_to_player(game.netcom.clone(), bytes, name);
+    game.sink.send_message_to_player(bytes, &name);
 }
 
 fn send_missile_info(game: &Game, name: String) {
-    // We add 5 bytes to the encoded data for the frame number and message tag
-    let len = game.encoded_missile_info.len() + 5;
-    let mut msg = Cursor::new(Vec::with_capacity(len));
-    let _ = msg.write_u32::<BigEndian>(game.frame_number);
+    // The encoded data is already a list of records so it only needs the frame number in front
+    let len = game.encoded_missile_info.len() + 4;
+    let mut msg = new_frame(game.frame_number);
+    msg.get_mut().reserve(len);
     let mut bytes = msg.into_inner();
     bytes.append(&mut game.encoded_missile_info.clone());
 
-    send_message_to_player(game.netcom.clone(), bytes, name);
+    game.sink.send_message_to_player(bytes, &name);
 }
 
 fn send_tilegrid_info(game: &Game, team: TeamID, name: String) {
-    // We add 6 bytes to the encoded data for the frame number, tag, & team
+    let mut msg = new_frame(game.frame_number);
+    encode_map_info(game, team, &mut msg);
+    game.sink.send_message_to_player(msg.into_inner(), &name);
+}
+
+fn send_match_seed(game: &Game, name: String) {
+    let mut msg = new_frame(game.frame_number);
+    encode_match_seed(game, &mut msg);
+    game.sink.send_message_to_player(msg.into_inner(), &name);
+}
+
+pub fn encode_map_info(game: &Game, team: TeamID, vec: &mut Cursor<Vec<u8>>) {
+    let team_byte = if team == game.observer_slot() {
+        OBSERVER_TEAM
+    }
+    else {
+        unsafe { team.usize_unwrap() as u8 }
+    };
+
+    write_record(vec, ClientMessage::MapInfo, |rec| {
+        let _ = rec.write_u8(team_byte);
+        let _ = rec.write_all(&game.encoded_map_data);
+    });
+}
+
+pub fn encode_match_seed(game: &Game, vec: &mut Cursor<Vec<u8>>) {
+    write_record(vec, ClientMessage::MatchSeed, |rec| {
+        let _ = rec.write_u64::<BigEndian>(game.seed);
+    });
+}
+
+pub fn encode_team_info(game: &Game, team: TeamID, vec: &mut Cursor<Vec<u8>>) {
     let team_usize = unsafe { team.usize_unwrap() };
-    let len = game.encoded_map_data.len() + 6;
-    let mut msg = Cursor::new(Vec::with_capacity(len));
-    let _ = msg.write_u32::<BigEndian>(game.frame_number);
-    let _ = msg.write_u8(ClientMessage::MapInfo as u8);
-    let _ = msg.write_u8(team_usize as u8);
-    let mut bytes = msg.into_inner();
-    bytes.append(&mut game.encoded_map_data.clone());
 
-    send_message_to_player(game.netcom.clone(), bytes, name);
+    write_record(vec, ClientMessage::TeamInfo, |rec| {
+        let _ = rec.write_u8(team_usize as u8);
+        let _ = rec.write_u32::<BigEndian>(game.teams.max_prime[team] as u32);
+        let _ = rec.write_u32::<BigEndian>(game.teams.prime[team] as u32);
+        let _ = rec.write_f64::<BigEndian>(game.teams.prime_output[team] * game.fps());
+        let _ = rec.write_f64::<BigEndian>(game.teams.prime_drain[team] * game.fps());
+
+        let _ = rec.write_u32::<BigEndian>(game.teams.max_energy[team] as u32);
+        let _ = rec.write_u32::<BigEndian>(game.teams.energy[team] as u32);
+        let _ = rec.write_f64::<BigEndian>(game.teams.energy_output[team] * game.fps());
+        let _ = rec.write_f64::<BigEndian>(game.teams.energy_drain[team] * game.fps());
+    });
 }
 
 fn read_move_message(game: &mut Game, order_id: OrderID, team_id: TeamID, bytes: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-    let x = bytes.read_f64::<BigEndian>()?;
-    let y = bytes.read_f64::<BigEndian>()?;
-    let queue_order = QueueOrder::from_u8(bytes.read_u8()?).unwrap();
+    let xy = read_point(game, bytes)?;
+    let formation = read_formation(bytes)?;
+    let queue_order = read_queue_order(bytes)?;
     let units = get_order_units(game, team_id, bytes)?;
-    let membership = HashSet::from_iter(units.iter().cloned().map(|id| game.units.new_unit_target(id)));
-    let order_type = OrderType::Move(MoveGroup::new((x as f64, y as f64), membership));
+    let order_type = OrderType::Move(move_group_in_formation(game, xy, formation, &units));
     let order = Rc::new(Order {
         order_type: order_type,
         order_id: order_id,
@@ -251,12 +537,67 @@ fn read_move_message(game: &mut Game, order_id: OrderID, team_id: TeamID, bytes:
 }
 
 fn read_attack_move_message(game: &mut Game, order_id: OrderID, team_id: TeamID, bytes: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-    let x = bytes.read_f64::<BigEndian>()?;
-    let y = bytes.read_f64::<BigEndian>()?;
-    let queue_order = QueueOrder::from_u8(bytes.read_u8()?).unwrap();
+    let xy = read_point(game, bytes)?;
+    let formation = read_formation(bytes)?;
+    let queue_order = read_queue_order(bytes)?;
+    let units = get_order_units(game, team_id, bytes)?;
+    let order_type = OrderType::AttackMove(move_group_in_formation(game, xy, formation, &units));
+    let order = Rc::new(Order {
+        order_type: order_type,
+        order_id: order_id,
+    });
+
+    add_order_to_units(game, team_id, order, units, queue_order);
+
+    Ok(())
+}
+
+fn read_formation(bytes: &mut Cursor<Vec<u8>>) -> io::Result<Formation> {
+    Formation::from_u8(bytes.read_u8()?).ok_or_else(|| invalid("Unknown formation."))
+}
+
+fn move_group_in_formation(game: &Game, xy: (f64, f64), formation: Formation, units: &[UnitID]) -> MoveGroup {
+    let members: Vec<(UnitTarget, (f64, f64), f64)> = units.iter().map(|&id| {
+        (game.units.new_unit_target(id), game.units.xy(id), game.units.radius(id))
+    }).collect();
+    // Units that can't move don't hold the others up
+    let top_speed = units.iter().map(|&id| game.units.top_speed(id)).filter(|&s| s > 0.0).fold(f64::INFINITY, f64::min);
+
+    MoveGroup::in_formation(xy, formation, &members, top_speed)
+}
+
+fn read_patrol_message(game: &mut Game, order_id: OrderID, team_id: TeamID, bytes: &mut Cursor<Vec<u8>>) -> io::Result<()> {
+    let waypoint_count = bytes.read_u8()?;
+
+    if waypoint_count == 0 {
+        return Err(invalid("Patrols need a waypoint."));
+    }
+
+    let mut waypoints = Vec::with_capacity(waypoint_count as usize);
+
+    for _ in 0..waypoint_count {
+        waypoints.push(read_point(game, bytes)?);
+    }
+
+    let queue_order = read_queue_order(bytes)?;
     let units = get_order_units(game, team_id, bytes)?;
+
+    if units.is_empty() {
+        return Ok(());
+    }
+
+    // One waypoint patrols between it and where the units are now
+    if waypoints.len() == 1 {
+        let n = units.len() as f64;
+        let (sx, sy) = units.iter().fold((0.0, 0.0), |(sx, sy), &id| {
+            let (x, y) = game.units.xy(id);
+            (sx + x, sy + y)
+        });
+        waypoints.push((sx / n, sy / n));
+    }
+
     let membership = HashSet::from_iter(units.iter().cloned().map(|id| game.units.new_unit_target(id)));
-    let order_type = OrderType::AttackMove(MoveGroup::new((x as f64, y as f64), membership));
+    let order_type = OrderType::Patrol(PatrolGroup::new(waypoints, membership));
     let order = Rc::new(Order {
         order_type: order_type,
         order_id: order_id,
@@ -267,12 +608,34 @@ fn read_attack_move_message(game: &mut Game, order_id: OrderID, team_id: TeamID,
     Ok(())
 }
 
+fn read_stance_message(game: &mut Game, team_id: TeamID, bytes: &mut Cursor<Vec<u8>>) -> io::Result<()> {
+    let move_stance = match bytes.read_u8()? {
+        255 => None,
+        n => Some(MoveStance::from_u8(n).ok_or_else(|| invalid("Unknown move stance."))?),
+    };
+    let fire_stance = match bytes.read_u8()? {
+        255 => None,
+        n => Some(FireStance::from_u8(n).ok_or_else(|| invalid("Unknown fire stance."))?),
+    };
+    let units = get_order_units(game, team_id, bytes)?;
+
+    for id in units {
+        if let Some(stance) = move_stance {
+            game.units.set_move_stance(id, stance);
+        }
+        if let Some(stance) = fire_stance {
+            game.units.set_fire_stance(id, stance);
+        }
+    }
+
+    Ok(())
+}
+
 fn read_attack_target_message(game: &mut Game, order_id: OrderID, team_id: TeamID, bytes: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-    let target_id_num = bytes.read_u16::<BigEndian>()?;
-    let target_id = unsafe { UnitID::usize_wrap(target_id_num as usize) };
+    let target_id = read_unit_id(game, bytes)?;
     let (x, y) = game.units.xy(target_id);
     let unit_target = game.units.new_unit_target(target_id);
-    let queue_order = QueueOrder::from_u8(bytes.read_u8()?).unwrap();
+    let queue_order = read_queue_order(bytes)?;
     let units = get_order_units(game, team_id, bytes)?;
     let membership = HashSet::from_iter(units.iter().cloned().map(|id| game.units.new_unit_target(id)));
     let order_type = OrderType::AttackTarget(MoveGroup::new((x as f64, y as f64), membership), unit_target);
@@ -287,10 +650,9 @@ fn read_attack_target_message(game: &mut Game, order_id: OrderID, team_id: TeamI
 }
 
 fn read_assist_message(game: &mut Game, order_id: OrderID, team_id: TeamID, bytes: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-    let target_id_num = bytes.read_u16::<BigEndian>()?;
-    let target_id = unsafe { UnitID::usize_wrap(target_id_num as usize) };
+    let target_id = read_unit_id(game, bytes)?;
     let unit_target = game.units.new_unit_target(target_id);
-    let queue_order = QueueOrder::from_u8(bytes.read_u8()?).unwrap();
+    let queue_order = read_queue_order(bytes)?;
     let units = get_order_units(game, team_id, bytes)?;
     let order_type = OrderType::Assist(unit_target);
     let order = Rc::new(Order {
@@ -303,8 +665,45 @@ fn read_assist_message(game: &mut Game, order_id: OrderID, team_id: TeamID, byte
     Ok(())
 }
 
+// Guard when guard is true, Follow otherwise.
+fn read_escort_message(game: &mut Game, order_id: OrderID, team_id: TeamID, bytes: &mut Cursor<Vec<u8>>, guard: bool) -> io::Result<()> {
+    let target_id = read_unit_id(game, bytes)?;
+    let unit_target = game.units.new_unit_target(target_id);
+    let queue_order = read_queue_order(bytes)?;
+    // Nobody escorts themselves
+    let units: Vec<UnitID> = get_order_units(game, team_id, bytes)?.into_iter().filter(|&id| id != target_id).collect();
+    let target_team = game.units.team(target_id);
+    let units = keep_capable_units(game, order_id, team_id, units, queue_order, |_, _| {
+        if guard && target_team != team_id {
+            Err(RejectReason::NotAnAlly)
+        }
+        else {
+            Ok(())
+        }
+    });
+
+    if units.is_empty() {
+        return Ok(());
+    }
+
+    let members: Vec<(UnitTarget, (f64, f64), f64)> = units.iter().map(|&id| {
+        (game.units.new_unit_target(id), game.units.xy(id), game.units.radius(id))
+    }).collect();
+    let facing = mv::denormalize(game.units.facing(target_id));
+    let escort_group = EscortGroup::new(unit_target, game.units.xy(target_id), facing, game.units.radius(target_id), &members);
+    let order_type = if guard { OrderType::Guard(escort_group) } else { OrderType::Follow(escort_group) };
+    let order = Rc::new(Order {
+        order_type: order_type,
+        order_id: order_id,
+    });
+
+    add_order_to_units(game, team_id, order, units, queue_order);
+
+    Ok(())
+}
+
 fn read_stop_message(game: &mut Game, order_id: OrderID, team_id: TeamID, bytes: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-    let queue_order = QueueOrder::from_u8(bytes.read_u8()?).unwrap();
+    let queue_order = read_queue_order(bytes)?;
     let units = get_order_units(game, team_id, bytes)?;
     let order_type = OrderType::Stop;
     let order = Rc::new(Order {
@@ -318,28 +717,58 @@ fn read_stop_message(game: &mut Game, order_id: OrderID, team_id: TeamID, bytes:
 }
 
 fn read_build_message(game: &mut Game, order_id: OrderID, team_id: TeamID, bytes: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-    let unit_type_id_num = bytes.read_u16::<BigEndian>()?;
-    let x = bytes.read_f64::<BigEndian>()?;
-    let y = bytes.read_f64::<BigEndian>()?;
-    let unit_type_id = unsafe { UnitTypeID::usize_wrap(unit_type_id_num as usize) };
+    let unit_type_id = read_unit_type(game, bytes)?;
+    let (x, y) = read_point(game, bytes)?;
     let order_type = OrderType::Build(BuildGroup::new(unit_type_id, BuildTarget::Point((x, y))));
     let order = Rc::new(Order {
         order_type: order_type,
         order_id: order_id,
     });
-    let queue_order = QueueOrder::from_u8(bytes.read_u8()?).unwrap();
+    let queue_order = read_queue_order(bytes)?;
     let units = get_order_units(game, team_id, bytes)?;
+    let is_structure = game.units.proto(unit_type_id).is_structure();
+    let units = keep_capable_units(game, order_id, team_id, units, queue_order, |units, id| {
+        if units.build_rate(id) <= 0.0 {
+            Err(RejectReason::CantBuild)
+        }
+        else if !units.build_roster_ids(id).contains(&unit_type_id) {
+            Err(RejectReason::NotInBuildRoster)
+        }
+        else if !is_structure {
+            Err(RejectReason::NotAStructure)
+        }
+        else {
+            Ok(())
+        }
+    });
     add_order_to_units(game, team_id, order, units, queue_order);
 
     Ok(())
 }
 
 fn read_train_message(game: &mut Game, order_id: OrderID, team_id: TeamID, bytes: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-    let unit_type_id_num = bytes.read_u16::<BigEndian>()?;
-    let unit_type_id = unsafe { UnitTypeID::usize_wrap(unit_type_id_num as usize) };
-    let repeat: bool = bytes.read_u8()? == 1;
-    let queue_order = QueueOrder::from_u8(bytes.read_u8()?).unwrap();
+    let unit_type_id = read_unit_type(game, bytes)?;
+    let repeat: bool = match bytes.read_u8()? {
+        0 => false,
+        1 => true,
+        _ => return Err(invalid("Repeat has to be 0 or 1.")),
+    };
+    let queue_order = read_queue_order(bytes)?;
     let trainers = get_order_units(game, team_id, bytes)?;
+    let trainers = keep_capable_units(game, order_id, team_id, trainers, queue_order, |units, id| {
+        if !units.is_structure(id) {
+            Err(RejectReason::NotAFactory)
+        }
+        else if units.train_rate(id) <= 0.0 {
+            Err(RejectReason::CantTrain)
+        }
+        else if !units.train_roster_ids(id).contains(&unit_type_id) {
+            Err(RejectReason::NotInTrainRoster)
+        }
+        else {
+            Ok(())
+        }
+    });
     let train_order = TrainOrder {
         order_id: order_id,
         unit_type: unit_type_id,
@@ -351,6 +780,27 @@ fn read_train_message(game: &mut Game, order_id: OrderID, team_id: TeamID, bytes
     Ok(())
 }
 
+// Drops the units that can't carry out an order and tells their team why.
+// Clearing a queue is something every unit can do.
+fn keep_capable_units<F>(game: &mut Game, order_id: OrderID, team_id: TeamID, units: Vec<UnitID>, queue_order: QueueOrder, check: F) -> Vec<UnitID>
+    where F: Fn(&Units, UnitID) -> Result<(), RejectReason>
+{
+    if let QueueOrder::Clear = queue_order {
+        return units;
+    }
+
+    let mut capable = Vec::with_capacity(units.len());
+
+    for unit_id in units {
+        match check(&game.units, unit_id) {
+            Ok(()) => capable.push(unit_id),
+            Err(reason) => game.logger.log_order_rejected(team_id, unit_id, order_id, reason),
+        }
+    }
+
+    capable
+}
+
 fn add_training_to_units(game: &mut Game, team_id: TeamID, train_order: TrainOrder, units: Vec<UnitID>, queue_order: QueueOrder) {
     for unit_id in units {
         let uid = unsafe {
@@ -151,6 +151,10 @@ pub fn all_enemies_in_range(game: &Game, u_id: UnitID, range: f64) -> Vec<KDTUni
 
 pub fn weapon_targets_in_active_range(game: &Game, u_id: UnitID, wpn: &Weapon) -> Vec<KDTUnit> {
     let active_range = game.units.engagement_range(u_id);
+    weapon_targets_within(game, u_id, wpn, active_range)
+}
+
+pub fn weapon_targets_within(game: &Game, u_id: UnitID, wpn: &Weapon, active_range: f64) -> Vec<KDTUnit> {
     let target_type = wpn.target_type();
     let xy = game.units.xy(u_id);
     let team = game.units.team(u_id);
@@ -218,6 +222,11 @@ pub fn get_nearest_enemy(game: &Game, wpn: &Weapon, u_id: UnitID) -> Option<Unit
 }
 
 pub fn nearest_visible_enemy_in_active_range(game: &Game, u_id: UnitID) -> Option<UnitID> {
+    let active_range = game.units.engagement_range(u_id);
+    nearest_visible_enemy_within(game, u_id, active_range)
+}
+
+pub fn nearest_visible_enemy_within(game: &Game, u_id: UnitID, range: f64) -> Option<UnitID> {
     let no_weapon = game.units.weapons(u_id).is_empty();
     let xy = game.units.xy(u_id);
 
@@ -225,12 +234,50 @@ pub fn nearest_visible_enemy_in_active_range(game: &Game, u_id: UnitID) -> Optio
         None
     } else {
         let wpn = &game.units.weapons(u_id)[0];
-        let enemies = weapon_targets_in_active_range(game, u_id, wpn);
+        let enemies = weapon_targets_within(game, u_id, wpn, range);
 
         nearest_in_group(&game.units, xy, &enemies)
     }
 }
 
+// The enemy the unit's weapon can hit that's nearest to it, out of those within range of xy rather than of the unit.
+pub fn nearest_visible_enemy_near(game: &Game, u_id: UnitID, xy: (f64, f64), range: f64) -> Option<UnitID> {
+    if game.units.weapons(u_id).is_empty() {
+        return None;
+    }
+
+    let target_type = game.units.weapons(u_id)[0].target_type();
+    let team = game.units.team(u_id);
+    let enemies = get_range_matching(game, xy, team, range, (true, false, true), target_type);
+
+    nearest_in_group(&game.units, game.units.xy(u_id), &enemies)
+}
+
+// Allies within the unit's engagement range that are finished but hurt.
+pub fn nearest_damaged_ally_in_active_range(game: &Game, u_id: UnitID) -> Option<UnitID> {
+    let active_range = game.units.engagement_range(u_id);
+    let xy = game.units.xy(u_id);
+    let team = game.units.team(u_id);
+    let allies: Vec<KDTUnit> = get_range_matching(
+        game,
+        xy,
+        team,
+        active_range,
+        (false, true, false),
+        TargetType::new_all_set(),
+    ).into_iter()
+    .filter(|kdtp| match game.units.target_id(kdtp.target) {
+        Some(a_id) => {
+            a_id != u_id && game.units.health(a_id) < game.units.max_health(a_id) &&
+            game.units.progress(a_id) >= game.units.build_cost(a_id)
+        }
+        None => false,
+    })
+    .collect();
+
+    nearest_in_group(&game.units, xy, &allies)
+}
+
 fn nearest_in_group(units: &Units, (xa, ya): (f64, f64), group: &[KDTUnit]) -> Option<UnitID> {
     if !group.is_empty() {
         let mut nearest_unit = None;
@@ -0,0 +1,167 @@
+/*
+ Everything a client needs to rebuild its view of the match from nothing, for players who just joined or reconnected.
+
+ The keyframe itself carries the map, the match seed, where the match is at, the team's resources and every order and train queue of the
+ team's units (or of every team, for spectators who see the whole map). Unit state isn't repeated here: the team's snapshot history is reset, so the unit message of the same
+ frame lists every visible unit in full and deltas carry on from there.
+*/
+
+extern crate byteorder;
+
+use self::byteorder::{WriteBytesExt, BigEndian};
+use std::io::Cursor;
+use data::aliases::*;
+use data::game::{Game, encode_map_info, encode_match_seed, encode_team_info};
+use data::build_groups::BuildTarget;
+use data::units::UnitTarget;
+use data::protocol::{new_frame, write_record};
+use data::lifecycle::encode_lifecycle;
+
+// Written where a unit id would go when the unit no longer exists
+pub const NO_UNIT: u16 = u16::max_value();
+
+// team is whose view the client has and slot whose snapshots and events it gets, the same for players.
+pub fn send_keyframe(game: &mut Game, team: TeamID, slot: TeamID, name: String) {
+    game.snapshots.reset(slot);
+
+    let mut msg = new_frame(game.frame_number);
+
+    // Events up to here are already reflected in the keyframe, so the client can ignore those batches
+    let events_through = game.events.last_seq(slot);
+    write_record(&mut msg, ClientMessage::Keyframe, |rec| {
+        let _ = rec.write_u32::<BigEndian>(events_through);
+    });
+
+    encode_map_info(game, team, &mut msg);
+    encode_match_seed(game, &mut msg);
+    encode_lifecycle(game, &mut msg);
+
+    // Spectators who see the whole map get every team's resources and orders
+    let observing = team == game.observer_slot();
+
+    for &info_team in &game.teams.iter() {
+        if observing || info_team == team {
+            encode_team_info(game, info_team, &mut msg);
+        }
+    }
+
+    for &id in &game.units.iter() {
+        if observing || game.units.team(id) == team {
+            encode_unit_orders(game, id, &mut msg);
+            encode_train_queue(game, id, &mut msg);
+        }
+    }
+
+    game.sink.send_message_to_player(msg.into_inner(), &name);
+}
+
+fn encode_unit_orders(game: &Game, id: UnitID, vec: &mut Cursor<Vec<u8>>) {
+    let orders = game.units.orders(id);
+
+    if orders.is_empty() {
+        return;
+    }
+
+    write_record(vec, ClientMessage::UnitOrders, |rec| unsafe {
+        let _ = rec.write_u16::<BigEndian>(id.usize_unwrap() as u16);
+        let _ = rec.write_u8(usize::min(orders.len(), 255) as u8);
+
+        for order in orders.iter().take(255) {
+            let _ = rec.write_u32::<BigEndian>(order.order_id.usize_unwrap() as u32);
+
+            match order.order_type {
+                OrderType::Move(ref mg) => {
+                    let (x, y) = mg.goal();
+                    let (sx, sy) = mg.goal_of(game.units.new_unit_target(id));
+                    let _ = rec.write_u8(ServerMessage::Move as u8);
+                    let _ = rec.write_f64::<BigEndian>(x);
+                    let _ = rec.write_f64::<BigEndian>(y);
+                    let _ = rec.write_u8(mg.formation() as u8);
+                    let _ = rec.write_f64::<BigEndian>(sx);
+                    let _ = rec.write_f64::<BigEndian>(sy);
+                }
+                OrderType::AttackMove(ref mg) => {
+                    let (x, y) = mg.goal();
+                    let (sx, sy) = mg.goal_of(game.units.new_unit_target(id));
+                    let _ = rec.write_u8(ServerMessage::AttackMove as u8);
+                    let _ = rec.write_f64::<BigEndian>(x);
+                    let _ = rec.write_f64::<BigEndian>(y);
+                    let _ = rec.write_u8(mg.formation() as u8);
+                    let _ = rec.write_f64::<BigEndian>(sx);
+                    let _ = rec.write_f64::<BigEndian>(sy);
+                }
+                OrderType::AttackTarget(_, target) => {
+                    let _ = rec.write_u8(ServerMessage::AttackTarget as u8);
+                    let _ = rec.write_u16::<BigEndian>(encode_target(game, target));
+                }
+                OrderType::Build(ref bg) => {
+                    let _ = rec.write_u8(ServerMessage::Build as u8);
+                    let _ = rec.write_u16::<BigEndian>(bg.build_type().usize_unwrap() as u16);
+
+                    match bg.build_target() {
+                        BuildTarget::Point((x, y)) => {
+                            let _ = rec.write_u8(0);
+                            let _ = rec.write_f64::<BigEndian>(x);
+                            let _ = rec.write_f64::<BigEndian>(y);
+                        }
+                        BuildTarget::Unit(target) => {
+                            let _ = rec.write_u8(1);
+                            let _ = rec.write_u16::<BigEndian>(encode_target(game, target));
+                        }
+                    }
+                }
+                OrderType::Assist(target) => {
+                    let _ = rec.write_u8(ServerMessage::Assist as u8);
+                    let _ = rec.write_u16::<BigEndian>(encode_target(game, target));
+                }
+                OrderType::Stop => {
+                    let _ = rec.write_u8(ServerMessage::Stop as u8);
+                }
+                OrderType::Patrol(ref pg) => {
+                    let _ = rec.write_u8(ServerMessage::Patrol as u8);
+                    let _ = rec.write_u8(pg.leg() as u8);
+                    let _ = rec.write_u8(pg.waypoints().len() as u8);
+
+                    for &(x, y) in pg.waypoints() {
+                        let _ = rec.write_f64::<BigEndian>(x);
+                        let _ = rec.write_f64::<BigEndian>(y);
+                    }
+                }
+                OrderType::Guard(ref eg) => {
+                    let _ = rec.write_u8(ServerMessage::Guard as u8);
+                    let _ = rec.write_u16::<BigEndian>(encode_target(game, eg.target()));
+                }
+                OrderType::Follow(ref eg) => {
+                    let _ = rec.write_u8(ServerMessage::Follow as u8);
+                    let _ = rec.write_u16::<BigEndian>(encode_target(game, eg.target()));
+                }
+            }
+        }
+    });
+}
+
+fn encode_train_queue(game: &Game, id: UnitID, vec: &mut Cursor<Vec<u8>>) {
+    let train_queue = game.units.train_queue(id);
+
+    if train_queue.is_empty() {
+        return;
+    }
+
+    write_record(vec, ClientMessage::TrainQueue, |rec| unsafe {
+        let _ = rec.write_u16::<BigEndian>(id.usize_unwrap() as u16);
+        let _ = rec.write_u8(usize::min(train_queue.len(), 255) as u8);
+
+        for train_order in train_queue.iter().take(255) {
+            let _ = rec.write_u32::<BigEndian>(train_order.order_id.usize_unwrap() as u32);
+            let _ = rec.write_u16::<BigEndian>(train_order.unit_type.usize_unwrap() as u16);
+            let _ = rec.write_u8(train_order.repeat as u8);
+        }
+    });
+}
+
+fn encode_target(game: &Game, target: UnitTarget) -> u16 {
+    match game.units.target_id(target) {
+        Some(id) => unsafe { id.usize_unwrap() as u16 },
+        None => NO_UNIT,
+    }
+}
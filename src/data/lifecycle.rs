@@ -0,0 +1,513 @@
+/*
+ A match from the lobby to the end.
+
+ A live match waits until every player in the roster has joined, counts down and then runs. Nothing is simulated
+ and frames don't count up until it's running, so frame 0 is the first frame anyone could give an order in.
+ While waiting, counting down, paused or finished the server only answers requests and sends MatchState.
+
+ After every frame each team still in the match is checked against the victory condition and is knocked out once
+ it fails it. A team that has never had a structure (or commander) is held to having any units at all instead, so a
+ setup that starts without them doesn't knock everyone out on the first frame. The match ends when one team is left (or none, if the last ones went out together), or when the time
+ limit runs out, in which case whoever has the best score among the teams left wins. Teams that are out keep their
+ units but can't give orders any more.
+
+ A team's score is what it has: the prime and energy cost of its finished units plus the prime and energy it has
+ banked.
+
+ Any player can pause a running match and any player can resume it, but each team can only keep it paused for so
+ long in total. Once the team that paused runs out the match resumes by itself. Admins pause without a budget and
+ only admins resume what they paused. Admins can also run the match faster or slower. Neither changes what happens
+ in the match, only how long it takes, so Pause, Resume and GameSpeed are taken out of the messages by the main
+ loop before the game or a recording sees them and handled by incorporate_control.
+*/
+
+extern crate byteorder;
+
+use self::byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
+use std::io;
+use std::io::Cursor;
+use num::FromPrimitive;
+use data::aliases::*;
+use data::game::Game;
+use data::persist::{Persist, Writer, Reader, invalid};
+use data::protocol::{new_frame, write_record, MatchPhase, OBSERVER_TEAM};
+use libs::netcom::Role;
+
+pub const MIN_SPEED: f64 = 0.25;
+pub const MAX_SPEED: f64 = 4.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Victory {
+    // Out once it has no structures left, finished or not (or no units, before it ever had a structure)
+    Structures,
+    // Out once it has no units left at all
+    Units,
+    // Out once every unit marked is_commander is dead (or every unit, before it ever had a commander)
+    Commander,
+    // Nobody is knocked out. The time limit decides.
+    Score,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    LastTeamStanding,
+    TimeLimit,
+}
+
+// What's written to the results file when a match ends.
+#[derive(Clone, Debug, Serialize)]
+pub struct MatchResults {
+    pub frame: u32,
+    pub seconds: f64,
+    pub reason: Option<EndReason>,
+    pub teams: Vec<TeamResult>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TeamResult {
+    pub team: usize,
+    pub players: Vec<String>,
+    pub victorious: bool,
+    pub defeated_frame: Option<u32>,
+    pub score: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Lifecycle {
+    phase: MatchPhase,
+    countdown: u32,
+    victory: Victory,
+    end_frame: Option<u32>,
+    defeated: Vec<(TeamID, u32)>,
+    // Teams that have met the victory condition at some point
+    qualified: Vec<TeamID>,
+    winners: Vec<TeamID>,
+    reason: Option<EndReason>,
+    pause_budget: u32,
+    // None while paused means an admin paused it
+    paused_by: Option<TeamID>,
+    paused_frames: Vec<(TeamID, u32)>,
+    speed: f64,
+}
+
+impl Lifecycle {
+    // Already running and never over, which is what tests, bots and replays want unless they say otherwise.
+    pub fn new() -> Lifecycle {
+        Lifecycle {
+            phase: MatchPhase::Running,
+            countdown: 0,
+            victory: Victory::Score,
+            end_frame: None,
+            defeated: Vec::new(),
+            qualified: Vec::new(),
+            winners: Vec::new(),
+            reason: None,
+            pause_budget: 0,
+            paused_by: None,
+            paused_frames: Vec::new(),
+            speed: 1.0,
+        }
+    }
+
+    // time_limit and pause_budget are in frames. The pause budget is per team.
+    pub fn set_rules(&mut self, victory: Victory, time_limit: Option<u32>, pause_budget: u32) {
+        self.victory = victory;
+        self.end_frame = time_limit;
+        self.pause_budget = pause_budget;
+    }
+
+    pub fn phase(&self) -> MatchPhase {
+        self.phase
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.phase == MatchPhase::Running
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.phase == MatchPhase::Finished
+    }
+
+    pub fn wait_for_players(&mut self) {
+        self.phase = MatchPhase::Waiting;
+        self.countdown = 0;
+        self.paused_by = None;
+    }
+
+    // How many more frames a team can keep the match paused for.
+    pub fn pause_left(&self, team: TeamID) -> u32 {
+        let used = self.paused_frames.iter().find(|p| p.0 == team).map_or(0, |p| p.1);
+        self.pause_budget.saturating_sub(used)
+    }
+
+    // How many times faster than its fps the match is being run.
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    // Kept between MIN_SPEED and MAX_SPEED.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = f64::min(MAX_SPEED, f64::max(MIN_SPEED, speed));
+    }
+
+    fn pause(&mut self, by: Option<TeamID>) {
+        self.phase = MatchPhase::Paused;
+        self.paused_by = by;
+    }
+
+    fn resume(&mut self) {
+        self.phase = MatchPhase::Running;
+        self.paused_by = None;
+    }
+
+    fn charge_pause(&mut self, team: TeamID) {
+        match self.paused_frames.iter().position(|p| p.0 == team) {
+            Some(ix) => self.paused_frames[ix].1 += 1,
+            None => self.paused_frames.push((team, 1)),
+        }
+    }
+
+    pub fn is_defeated(&self, team: TeamID) -> bool {
+        self.defeated.iter().any(|d| d.0 == team)
+    }
+
+    // The frame a team was knocked out in.
+    pub fn defeated_at(&self, team: TeamID) -> Option<u32> {
+        self.defeated.iter().find(|d| d.0 == team).map(|d| d.1)
+    }
+
+    pub fn winners(&self) -> &[TeamID] {
+        &self.winners
+    }
+
+    pub fn end_reason(&self) -> Option<EndReason> {
+        self.reason
+    }
+
+    pub fn takes_orders_from(&self, team: TeamID) -> bool {
+        self.is_running() && !self.is_defeated(team)
+    }
+
+    // The rules aren't saved. They come from the match config like everything else that isn't state.
+    pub fn save_state(&self, w: &mut Writer) {
+        (self.phase as u8).save(w);
+        self.countdown.save(w);
+        self.defeated.save(w);
+        self.qualified.save(w);
+        self.winners.save(w);
+        self.reason.map(|r| r as u8).save(w);
+        self.paused_by.save(w);
+        self.paused_frames.save(w);
+        self.speed.save(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.phase = MatchPhase::from_u8(u8::load(r)?).ok_or_else(|| invalid("match phase"))?;
+        self.countdown = Persist::load(r)?;
+        self.defeated = Persist::load(r)?;
+        self.qualified = Persist::load(r)?;
+        self.winners = Persist::load(r)?;
+        self.reason = match Option::<u8>::load(r)? {
+            None => None,
+            Some(0) => Some(EndReason::LastTeamStanding),
+            Some(1) => Some(EndReason::TimeLimit),
+            Some(_) => return Err(invalid("end reason")),
+        };
+        self.paused_by = Persist::load(r)?;
+        self.paused_frames = Persist::load(r)?;
+        self.speed = Persist::load(r)?;
+        Ok(())
+    }
+}
+
+// Moves a match that isn't running along, once a frame. everyone_here is whether every player is connected.
+// Someone leaving during the countdown puts the match back to waiting for them. A pause is charged to the team
+// that asked for it.
+pub fn tick(game: &mut Game, everyone_here: bool, countdown_frames: u32) {
+    match game.lifecycle.phase {
+        MatchPhase::Waiting if everyone_here => {
+            game.lifecycle.phase = MatchPhase::Countdown;
+            game.lifecycle.countdown = countdown_frames;
+        }
+        MatchPhase::Countdown if !everyone_here => {
+            game.lifecycle.wait_for_players();
+        }
+        MatchPhase::Paused => {
+            if let Some(team) = game.lifecycle.paused_by {
+                if game.lifecycle.pause_left(team) == 0 {
+                    game.lifecycle.resume();
+                }
+                else {
+                    game.lifecycle.charge_pause(team);
+                }
+            }
+        }
+        _ => (),
+    }
+
+    if game.lifecycle.phase == MatchPhase::Countdown {
+        if game.lifecycle.countdown == 0 {
+            game.lifecycle.phase = MatchPhase::Running;
+        } else {
+            game.lifecycle.countdown -= 1;
+        }
+    }
+
+    announce(game, |game, msg| encode_match_state(game, msg));
+}
+
+// Knocks out teams that no longer meet the victory condition and ends the match once it's decided.
+// Runs at the end of every frame the match is running.
+pub fn check_victory(game: &mut Game) {
+    if !game.lifecycle.is_running() {
+        return;
+    }
+
+    let frame = game.frame_number;
+    let teams = game.teams.iter();
+    let mut knocked_out = Vec::new();
+
+    if teams.is_empty() {
+        return;
+    }
+
+    for &team in &teams {
+        if game.lifecycle.is_defeated(team) {
+            continue;
+        }
+
+        if meets_condition(game, team) {
+            if !game.lifecycle.qualified.contains(&team) {
+                game.lifecycle.qualified.push(team);
+            }
+        }
+        else if game.lifecycle.qualified.contains(&team) || !has_units(game, team) {
+            knocked_out.push(team);
+            game.lifecycle.defeated.push((team, frame));
+        }
+    }
+
+    let left: Vec<TeamID> = teams.iter().cloned().filter(|&t| !game.lifecycle.is_defeated(t)).collect();
+    let time_up = game.lifecycle.end_frame.map_or(false, |end| frame + 1 >= end);
+
+    // A team playing alone only ends its match by losing
+    if left.is_empty() || (left.len() == 1 && teams.len() > 1) {
+        game.lifecycle.winners = left;
+        game.lifecycle.reason = Some(EndReason::LastTeamStanding);
+    }
+    else if time_up {
+        let best = left.iter().map(|&t| score(game, t)).fold(0.0, f64::max);
+
+        for &team in &left {
+            if score(game, team) < best {
+                knocked_out.push(team);
+                game.lifecycle.defeated.push((team, frame));
+            }
+            else {
+                game.lifecycle.winners.push(team);
+            }
+        }
+
+        game.lifecycle.reason = Some(EndReason::TimeLimit);
+    }
+
+    if game.lifecycle.reason.is_some() {
+        game.lifecycle.phase = MatchPhase::Finished;
+    }
+
+    if knocked_out.is_empty() && !game.lifecycle.is_finished() {
+        return;
+    }
+
+    announce(game, |game, msg| {
+        for &team in &knocked_out {
+            encode_team_result(ClientMessage::TeamDefeated, team, msg);
+        }
+
+        if game.lifecycle.is_finished() {
+            for &team in game.lifecycle.winners() {
+                encode_team_result(ClientMessage::TeamVictorious, team, msg);
+            }
+
+            encode_match_state(game, msg);
+        }
+    });
+}
+
+pub fn is_control(msg: &[u8]) -> bool {
+    match msg.first().and_then(|&tag| ServerMessage::from_u8(tag)) {
+        Some(ServerMessage::Pause) |
+        Some(ServerMessage::Resume) |
+        Some(ServerMessage::GameSpeed) => true,
+        _ => false,
+    }
+}
+
+// Handles a Pause, Resume or GameSpeed and tells everyone where the match is at.
+pub fn incorporate_control(game: &mut Game, role: Role, is_admin: bool, data: Vec<u8>) -> io::Result<()> {
+    let bytes = &mut Cursor::new(data);
+    let msg_type = ServerMessage::from_u8(bytes.read_u8()?).ok_or_else(|| invalid("Unknown message type."))?;
+    bytes.read_u32::<BigEndian>()?;
+
+    let team = match role {
+        Role::Player(team) => Some(unsafe { TeamID::usize_wrap(team) }),
+        Role::Spectator(_) => None,
+    };
+
+    if team.is_none() && !is_admin {
+        return Err(invalid("Spectators can't pause or change the speed."));
+    }
+
+    let lifecycle = &mut game.lifecycle;
+
+    match msg_type {
+        ServerMessage::Pause => {
+            // Asking too late or with nothing left isn't misbehaving, it just doesn't do anything
+            if lifecycle.is_running() {
+                if is_admin {
+                    lifecycle.pause(None);
+                }
+                else if let Some(team) = team {
+                    if !lifecycle.is_defeated(team) && lifecycle.pause_left(team) > 0 {
+                        lifecycle.pause(Some(team));
+                    }
+                }
+            }
+        }
+        ServerMessage::Resume => {
+            let can_resume = is_admin || (lifecycle.paused_by.is_some() && !team.map_or(true, |t| lifecycle.is_defeated(t)));
+
+            if lifecycle.phase == MatchPhase::Paused && can_resume {
+                lifecycle.resume();
+            }
+        }
+        ServerMessage::GameSpeed => {
+            let speed = bytes.read_f64::<BigEndian>()?;
+
+            if !is_admin {
+                return Err(invalid("Only admins can change the speed."));
+            }
+
+            if !speed.is_finite() {
+                return Err(invalid("Speed has to be a number."));
+            }
+
+            lifecycle.set_speed(speed);
+        }
+        _ => return Err(invalid("Not a control message.")),
+    }
+
+    announce(game, |game, msg| encode_match_state(game, msg));
+    Ok(())
+}
+
+fn meets_condition(game: &Game, team: TeamID) -> bool {
+    let mut units = game.units.iter().into_iter().filter(|&id| game.units.team(id) == team);
+
+    match game.lifecycle.victory {
+        Victory::Structures => units.any(|id| game.units.is_structure(id)),
+        Victory::Units => units.next().is_some(),
+        Victory::Commander => units.any(|id| game.units.is_commander(id)),
+        Victory::Score => true,
+    }
+}
+
+fn has_units(game: &Game, team: TeamID) -> bool {
+    game.units.iter().into_iter().any(|id| game.units.team(id) == team)
+}
+
+pub fn score(game: &Game, team: TeamID) -> f64 {
+    let mut score = game.teams.prime[team] + game.teams.energy[team];
+
+    for id in game.units.iter() {
+        if game.units.team(id) == team && game.units.is_active(id) {
+            score += game.units.prime_cost(id) + game.units.energy_cost(id);
+        }
+    }
+
+    score
+}
+
+// players are the names in the roster along with their teams.
+pub fn results(game: &Game, players: &[(String, usize)]) -> MatchResults {
+    let teams = game.teams.iter().into_iter().map(|team| {
+        let team_usize = unsafe { team.usize_unwrap() };
+
+        TeamResult {
+            team: team_usize,
+            players: players.iter().filter(|p| p.1 == team_usize).map(|p| p.0.clone()).collect(),
+            victorious: game.lifecycle.winners.contains(&team),
+            defeated_frame: game.lifecycle.defeated_at(team),
+            score: score(game, team),
+        }
+    }).collect();
+
+    MatchResults {
+        frame: game.frame_number,
+        seconds: game.frame_number as f64 * game.frame_time(),
+        reason: game.lifecycle.reason,
+        teams: teams,
+    }
+}
+
+pub fn encode_match_state(game: &Game, vec: &mut Cursor<Vec<u8>>) {
+    let lifecycle = &game.lifecycle;
+
+    write_record(vec, ClientMessage::MatchState, |rec| {
+        let _ = rec.write_u8(lifecycle.phase as u8);
+        let _ = rec.write_u32::<BigEndian>(lifecycle.countdown);
+        let _ = rec.write_u32::<BigEndian>(lifecycle.end_frame.unwrap_or(0));
+
+        let paused_by = match lifecycle.paused_by {
+            Some(team) if lifecycle.phase == MatchPhase::Paused => unsafe { team.usize_unwrap() as u8 },
+            _ => OBSERVER_TEAM,
+        };
+        let _ = rec.write_u8(paused_by);
+        let _ = rec.write_f64::<BigEndian>(lifecycle.speed);
+
+        for &team in &game.teams.iter() {
+            let _ = rec.write_u8(unsafe { team.usize_unwrap() } as u8);
+            let _ = rec.write_u32::<BigEndian>(lifecycle.pause_left(team));
+        }
+    });
+}
+
+// Everything a client that just joined needs to know about where the match is at.
+pub fn encode_lifecycle(game: &Game, vec: &mut Cursor<Vec<u8>>) {
+    for &(team, _) in &game.lifecycle.defeated {
+        encode_team_result(ClientMessage::TeamDefeated, team, vec);
+    }
+
+    for &team in game.lifecycle.winners() {
+        encode_team_result(ClientMessage::TeamVictorious, team, vec);
+    }
+
+    encode_match_state(game, vec);
+}
+
+fn encode_team_result(tag: ClientMessage, team: TeamID, vec: &mut Cursor<Vec<u8>>) {
+    write_record(vec, tag, |rec| {
+        let _ = rec.write_u8(unsafe { team.usize_unwrap() } as u8);
+    });
+}
+
+pub fn announce_match_state(game: &Game) {
+    announce(game, |game, msg| encode_match_state(game, msg));
+}
+
+// Sends a frame to every team and to spectators who see everything.
+fn announce<F: FnOnce(&Game, &mut Cursor<Vec<u8>>)>(game: &Game, encode: F) {
+    let mut msg = new_frame(game.frame_number);
+    encode(game, &mut msg);
+    let msg = msg.into_inner();
+
+    for &team in &game.teams.iter() {
+        game.sink.send_message_to_team(msg.clone(), unsafe { team.usize_unwrap() });
+    }
+
+    if game.sink.has_spectators(None) {
+        game.sink.send_message_to_spectators(msg, None);
+    }
+}
@@ -0,0 +1,298 @@
+/*
+ Lockstep mode: instead of streaming unit state, the server relays orders and every client runs the simulation itself.
+
+ An order received during frame N is put off until frame N + delay, and at the end of frame N everyone is sent the
+ LockstepTurn for frame N + delay with every order that will run in it. A client may only step frame F once it has
+ F's turn. Frames before the first delay frames have no turns and no orders. The server still steps the game so it
+ can check everyone's checksums against its own, and applies the orders at the same frame the clients do.
+
+ Every turn carries the server's checksum of an earlier frame, and clients send theirs back with Checksum. A client
+ whose checksum differs from the server's is sent a Desync.
+
+ Orders are relayed as they were sent. One that doesn't make sense fails the same way everywhere it runs, and
+ counts as a strike against its sender when it does.
+
+ Late joiners are sent every turn since frame 0 and catch up by simulating from the start. Once the turns add up to
+ more than MAX_HISTORY_BYTES the server stops keeping them, and anyone joining after that is told they can't catch up.
+ Every client simulates every unit, so fog of war is only as good as the client's honesty.
+*/
+
+extern crate byteorder;
+
+use self::byteorder::{WriteBytesExt, BigEndian};
+use std::collections::vec_deque::VecDeque;
+use std::io;
+use std::io::Cursor;
+use std::io::prelude::*;
+use data::aliases::*;
+use data::game::{Game, encode_map_info, encode_match_seed};
+use data::protocol::{new_frame, write_record, write_varint};
+use data::persist::{Persist, Writer, Reader};
+use data::lifecycle::encode_lifecycle;
+
+// How many of the server's own checksums are kept around to compare against
+pub const CHECKSUM_HISTORY: usize = 256;
+// How much of the turns already sent is kept for late joiners, over 12 hours of turns without orders at 10 fps
+pub const MAX_HISTORY_BYTES: usize = 8 * 1024 * 1024;
+
+#[derive(Clone, Debug)]
+struct Turn {
+    frame: u32,
+    orders: Vec<(String, TeamID, Vec<u8>)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Lockstep {
+    delay: u32,
+    pending: VecDeque<Turn>,
+    sent: Vec<Vec<u8>>,
+    sent_bytes: usize,
+    // Set once sent outgrew MAX_HISTORY_BYTES and was dropped
+    history_full: bool,
+    checksums: VecDeque<(u32, u64)>,
+}
+
+impl Lockstep {
+    pub fn new(delay: u32) -> Lockstep {
+        Lockstep {
+            delay: u32::max(1, delay),
+            pending: VecDeque::new(),
+            sent: Vec::new(),
+            sent_bytes: 0,
+            history_full: false,
+            checksums: VecDeque::new(),
+        }
+    }
+
+    pub fn delay(&self) -> u32 {
+        self.delay
+    }
+
+    // Puts off an order received during frame until it's everyone's turn to run it.
+    pub fn schedule(&mut self, frame: u32, name: String, team: TeamID, order: Vec<u8>) {
+        let turn_frame = frame + self.delay;
+
+        if self.pending.back().map_or(true, |turn| turn.frame != turn_frame) {
+            self.pending.push_back(Turn {
+                frame: turn_frame,
+                orders: Vec::new(),
+            });
+        }
+
+        if let Some(turn) = self.pending.back_mut() {
+            turn.orders.push((name, team, order));
+        }
+    }
+
+    // The orders that run this frame, along with who sent them.
+    pub fn take_due(&mut self, frame: u32) -> Vec<(String, TeamID, Vec<u8>)> {
+        match self.pending.front() {
+            Some(turn) if turn.frame == frame => (),
+            _ => return Vec::new(),
+        }
+
+        self.pending.pop_front().map_or(Vec::new(), |turn| turn.orders)
+    }
+
+    pub fn record_checksum(&mut self, frame: u32, checksum: u64) {
+        self.checksums.push_back((frame, checksum));
+
+        if self.checksums.len() > CHECKSUM_HISTORY {
+            self.checksums.pop_front();
+        }
+    }
+
+    // The server's checksum of frame, if it's recent enough to remember.
+    pub fn checksum_at(&self, frame: u32) -> Option<u64> {
+        self.checksums.iter().find(|c| c.0 == frame).map(|c| c.1)
+    }
+
+    // Whether a client joining now can still catch up.
+    pub fn joinable(&self) -> bool {
+        !self.history_full
+    }
+
+    // Writes the turn for frame + delay, which closes once frame is done, and keeps it for late joiners.
+    pub fn encode_turn(&mut self, frame: u32, checksum: u64, vec: &mut Cursor<Vec<u8>>) {
+        let turn_frame = frame + self.delay;
+        let orders = match self.pending.back() {
+            Some(turn) if turn.frame == turn_frame => turn.orders.as_slice(),
+            _ => &[],
+        };
+
+        let mut record = Cursor::new(Vec::new());
+        write_record(&mut record, ClientMessage::LockstepTurn, |rec| unsafe {
+            let _ = rec.write_u32::<BigEndian>(turn_frame);
+            let _ = rec.write_u32::<BigEndian>(frame);
+            let _ = rec.write_u64::<BigEndian>(checksum);
+
+            for &(_, team, ref order) in orders {
+                let _ = rec.write_u8(team.usize_unwrap() as u8);
+                write_varint(rec, order.len() as u32);
+                let _ = rec.write_all(order);
+            }
+        });
+
+        let record = record.into_inner();
+        let _ = vec.write_all(&record);
+
+        if self.history_full {
+            return;
+        }
+
+        if self.sent_bytes + record.len() > MAX_HISTORY_BYTES {
+            self.history_full = true;
+            self.sent = Vec::new();
+            self.sent_bytes = 0;
+        }
+        else {
+            self.sent_bytes += record.len();
+            self.sent.push(record);
+        }
+    }
+}
+
+impl Persist for Turn {
+    fn save(&self, w: &mut Writer) {
+        self.frame.save(w);
+        self.orders.len().save(w);
+
+        for &(ref name, team, ref order) in &self.orders {
+            name.save(w);
+            team.save(w);
+            order.save(w);
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<Turn> {
+        let frame = Persist::load(r)?;
+        let mut orders = Vec::new();
+
+        for _ in 0..usize::load(r)? {
+            let name = Persist::load(r)?;
+            let team = Persist::load(r)?;
+            let order = Persist::load(r)?;
+            orders.push((name, team, order));
+        }
+
+        Ok(Turn {
+            frame: frame,
+            orders: orders,
+        })
+    }
+}
+
+// The turns already sent are kept too, since a client joining later still needs every one of them.
+impl Persist for Lockstep {
+    fn save(&self, w: &mut Writer) {
+        self.delay.save(w);
+        self.pending.save(w);
+        self.sent.save(w);
+        self.history_full.save(w);
+        self.checksums.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<Lockstep> {
+        let delay = Persist::load(r)?;
+        let pending = Persist::load(r)?;
+        let sent: Vec<Vec<u8>> = Persist::load(r)?;
+        let history_full = Persist::load(r)?;
+        let checksums = Persist::load(r)?;
+
+        Ok(Lockstep {
+            delay: delay,
+            pending: pending,
+            sent_bytes: sent.iter().map(|turn| turn.len()).sum(),
+            sent: sent,
+            history_full: history_full,
+            checksums: checksums,
+        })
+    }
+}
+
+// Closes this frame's turn and sends it to everyone, players of every team and spectators alike.
+pub fn send_turn(game: &mut Game) {
+    let frame = game.frame_number;
+    let checksum = game.checksum();
+    let mut msg = new_frame(frame);
+
+    if let Some(ref mut lockstep) = game.lockstep {
+        lockstep.record_checksum(frame, checksum);
+        lockstep.encode_turn(frame, checksum, &mut msg);
+    }
+
+    let msg = msg.into_inner();
+
+    for &team in &game.teams.iter() {
+        let team_usize = unsafe { team.usize_unwrap() };
+        game.sink.send_message_to_team(msg.clone(), team_usize);
+    }
+
+    if game.sink.has_spectators(None) {
+        game.sink.send_message_to_spectators(msg, None);
+    }
+}
+
+// What a client joining a lockstep match gets instead of a keyframe. Just a LockstepStart saying it can't catch up
+// if the match's history has grown too long to keep.
+pub fn send_history(game: &Game, team: TeamID, name: String) {
+    let lockstep = match game.lockstep {
+        Some(ref lockstep) => lockstep,
+        None => return,
+    };
+
+    let mut msg = new_frame(game.frame_number);
+
+    write_record(&mut msg, ClientMessage::LockstepStart, |rec| {
+        let _ = rec.write_u32::<BigEndian>(lockstep.delay);
+        let _ = rec.write_u8(lockstep.joinable() as u8);
+    });
+
+    if !lockstep.joinable() {
+        game.sink.send_message_to_player(msg.into_inner(), &name);
+        return;
+    }
+
+    encode_map_info(game, team, &mut msg);
+    encode_match_seed(game, &mut msg);
+    encode_lifecycle(game, &mut msg);
+
+    for turn in &lockstep.sent {
+        let _ = msg.write_all(turn);
+    }
+
+    game.sink.send_message_to_player(msg.into_inner(), &name);
+}
+
+#[test]
+fn orders_wait_for_their_turn() {
+    let team = unsafe { TeamID::usize_wrap(0) };
+    let mut lockstep = Lockstep::new(3);
+
+    lockstep.schedule(10, "p1".to_string(), team, vec![1]);
+    lockstep.schedule(10, "p1".to_string(), team, vec![2]);
+    lockstep.schedule(11, "p2".to_string(), team, vec![3]);
+
+    assert!(lockstep.take_due(12).is_empty());
+    assert_eq!(lockstep.take_due(13).len(), 2);
+    assert_eq!(lockstep.take_due(14)[0].2, vec![3]);
+    assert!(lockstep.take_due(15).is_empty());
+}
+
+#[test]
+fn history_stops_growing_once_too_long() {
+    let team = unsafe { TeamID::usize_wrap(0) };
+    let mut lockstep = Lockstep::new(1);
+    let big_order = vec![0; MAX_HISTORY_BYTES / 4];
+
+    for frame in 0..3 {
+        lockstep.schedule(frame, "p1".to_string(), team, big_order.clone());
+        lockstep.encode_turn(frame, 0, &mut Cursor::new(Vec::new()));
+    }
+    assert!(lockstep.joinable());
+
+    for frame in 3..5 {
+        lockstep.schedule(frame, "p1".to_string(), team, big_order.clone());
+        lockstep.encode_turn(frame, 0, &mut Cursor::new(Vec::new()));
+    }
+    assert!(!lockstep.joinable());
+    assert!(lockstep.sent.is_empty());
+}
@@ -5,6 +5,7 @@ extern crate byteorder;
 use data::aliases::*;
 use data::game::Game;
 use data::units::UnitTarget;
+use data::protocol::{write_record, RejectReason};
 use self::byteorder::{WriteBytesExt, BigEndian};
 use std::io::Cursor;
 
@@ -46,6 +47,14 @@ pub struct OrderCompleted {
     order_id: OrderID,
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct OrderRejected {
+    team: TeamID,
+    unit: UnitID,
+    order_id: OrderID,
+    reason: RejectReason,
+}
+
 #[derive(Clone, Debug)]
 pub struct Logger {
     pub unit_deaths: Vec<UnitDeath>,
@@ -54,6 +63,7 @@ pub struct Logger {
     orders_completed: Vec<OrderCompleted>,
     training_completed: Vec<Training>,
     construction: Vec<Construction>,
+    orders_rejected: Vec<OrderRejected>,
 }
 
 impl Logger {
@@ -65,6 +75,7 @@ impl Logger {
             orders_completed: Vec::new(),
             training_completed: Vec::new(),
             construction: Vec::new(),
+            orders_rejected: Vec::new(),
         }
     }
 
@@ -96,6 +107,15 @@ impl Logger {
         self.missile_booms.push(boom);
     }
 
+    pub fn log_order_rejected(&mut self, team: TeamID, unit: UnitID, order_id: OrderID, reason: RejectReason) {
+        self.orders_rejected.push(OrderRejected {
+            team: team,
+            unit: unit,
+            order_id: order_id,
+            reason: reason,
+        });
+    }
+
     pub fn log_construction(&mut self, builder: UnitID, buildee: UnitID) {
         self.construction.push(Construction {
             builder: builder,
@@ -117,89 +137,99 @@ impl Logger {
         self.orders_completed.clear();
         self.construction.clear();
         self.training_completed.clear();
+        self.orders_rejected.clear();
     }
 }
 
-pub fn encode_order_completed(game: &Game, team: TeamID, vec: &mut Cursor<Vec<u8>>) {
+// The encoders below only write what a client seeing through view's eyes should know about.
+// A view of None is a spectator who sees everything.
+
+pub fn encode_order_completed(game: &Game, view: Option<TeamID>, vec: &mut Cursor<Vec<u8>>) {
     for completed in &game.logger.orders_completed {
         if let Some(unit_id) = game.units.target_id(completed.unit_target) {
-            if game.units.team(unit_id) == team {
-                let _ = vec.write_u8(ClientMessage::OrderCompleted as u8);
-                unsafe {
-                    let _ = vec.write_u16::<BigEndian>(unit_id.usize_unwrap() as u16);
-                    let _ = vec.write_u32::<BigEndian>(completed.order_id.usize_unwrap() as u32);
-                }
+            if view.map_or(true, |team| game.units.team(unit_id) == team) {
+                write_record(vec, ClientMessage::OrderCompleted, |rec| unsafe {
+                    let _ = rec.write_u16::<BigEndian>(unit_id.usize_unwrap() as u16);
+                    let _ = rec.write_u32::<BigEndian>(completed.order_id.usize_unwrap() as u32);
+                });
             }
         }
     }
 }
 
-pub fn encode_training_completed(game: &Game, team: TeamID, vec: &mut Cursor<Vec<u8>>) {
+pub fn encode_orders_rejected(game: &Game, view: Option<TeamID>, vec: &mut Cursor<Vec<u8>>) {
+    for rejected in &game.logger.orders_rejected {
+        if view.map_or(true, |team| rejected.team == team) {
+            write_record(vec, ClientMessage::OrderRejected, |rec| unsafe {
+                let _ = rec.write_u16::<BigEndian>(rejected.unit.usize_unwrap() as u16);
+                let _ = rec.write_u32::<BigEndian>(rejected.order_id.usize_unwrap() as u32);
+                let _ = rec.write_u8(rejected.reason as u8);
+            });
+        }
+    }
+}
+
+pub fn encode_training_completed(game: &Game, view: Option<TeamID>, vec: &mut Cursor<Vec<u8>>) {
     for completed in &game.logger.training_completed {
-        if game.units.team(completed.builder) == team {
-            let _ = vec.write_u8(ClientMessage::TrainingCompleted as u8);
-            unsafe {
-                let _ = vec.write_u16::<BigEndian>(completed.builder.usize_unwrap() as u16);
-                let _ = vec.write_u16::<BigEndian>(completed.buildee.usize_unwrap() as u16);
-                let _ = vec.write_u32::<BigEndian>(completed.order_id.usize_unwrap() as u32);
-            }
+        if view.map_or(true, |team| game.units.team(completed.builder) == team) {
+            write_record(vec, ClientMessage::TrainingCompleted, |rec| unsafe {
+                let _ = rec.write_u16::<BigEndian>(completed.builder.usize_unwrap() as u16);
+                let _ = rec.write_u16::<BigEndian>(completed.buildee.usize_unwrap() as u16);
+                let _ = rec.write_u32::<BigEndian>(completed.order_id.usize_unwrap() as u32);
+            });
         }
     }
 }
 
-pub fn encode_missile_booms(game: &mut Game, team: TeamID, vec: &mut Cursor<Vec<u8>>) {
+pub fn encode_missile_booms(game: &Game, view: Option<TeamID>, vec: &mut Cursor<Vec<u8>>) {
     for ref boom in &game.logger.missile_booms {
-        let visible = game.teams.visible_missiles[team][boom.id].is_visible();
+        let visible = view.map_or(true, |team| game.teams.visible_missiles[team][boom.id].is_visible());
 
         if visible {
-            unsafe {
-                let _ = vec.write_u8(ClientMessage::MissileExplode as u8);
-                let _ = vec.write_u8(MissileTypeID::usize_unwrap(boom.missile_type) as u8);
-                let _ = vec.write_u16::<BigEndian>(boom.id.usize_unwrap() as u16);
-                let _ = vec.write_u16::<BigEndian>((boom.x * 64.0) as u16);
-                let _ = vec.write_u16::<BigEndian>((boom.y * 64.0) as u16);
-                let _ = vec.write_u8(boom.team.usize_unwrap() as u8);
-            }
+            write_record(vec, ClientMessage::MissileExplode, |rec| unsafe {
+                let _ = rec.write_u8(MissileTypeID::usize_unwrap(boom.missile_type) as u8);
+                let _ = rec.write_u16::<BigEndian>(boom.id.usize_unwrap() as u16);
+                let _ = rec.write_u16::<BigEndian>((boom.x * 64.0) as u16);
+                let _ = rec.write_u16::<BigEndian>((boom.y * 64.0) as u16);
+                let _ = rec.write_u8(boom.team.usize_unwrap() as u8);
+            });
         }
     }
 }
 
-pub fn encode_unit_deaths(game: &mut Game, team: TeamID, vec: &mut Cursor<Vec<u8>>) {
+pub fn encode_unit_deaths(game: &Game, view: Option<TeamID>, vec: &mut Cursor<Vec<u8>>) {
     for &death in &game.logger.unit_deaths {
-        let visible = game.teams.visible[team][death.id].is_visible();
+        let visible = view.map_or(true, |team| game.teams.visible[team][death.id].is_visible());
 
         if visible {
-            let _ = vec.write_u8(ClientMessage::UnitDeath as u8);
-            unsafe {
-                let _ = vec.write_u16::<BigEndian>(death.id.usize_unwrap() as u16);
-            }
+            write_record(vec, ClientMessage::UnitDeath, |rec| unsafe {
+                let _ = rec.write_u16::<BigEndian>(death.id.usize_unwrap() as u16);
+            });
         }
     }
 }
 
-pub fn encode_construction(game: &mut Game, team: TeamID, vec: &mut Cursor<Vec<u8>>) {
+pub fn encode_construction(game: &Game, view: Option<TeamID>, vec: &mut Cursor<Vec<u8>>) {
     for &construction in &game.logger.construction {
-        let visible = game.teams.visible[team][construction.builder].is_visible();
+        let visible = view.map_or(true, |team| game.teams.visible[team][construction.builder].is_visible());
 
         if visible {
-            let _ = vec.write_u8(ClientMessage::Construction as u8);
-            unsafe {
-                let _ = vec.write_u16::<BigEndian>(construction.builder.usize_unwrap() as u16);
-                let _ = vec.write_u16::<BigEndian>(construction.buildee.usize_unwrap() as u16);
-            }
+            write_record(vec, ClientMessage::Construction, |rec| unsafe {
+                let _ = rec.write_u16::<BigEndian>(construction.builder.usize_unwrap() as u16);
+                let _ = rec.write_u16::<BigEndian>(construction.buildee.usize_unwrap() as u16);
+            });
         }
     }
 }
 
-pub fn encode_melee_smacks(game: &mut Game, team: TeamID, vec: &mut Cursor<Vec<u8>>) {
+pub fn encode_melee_smacks(game: &Game, view: Option<TeamID>, vec: &mut Cursor<Vec<u8>>) {
     for &smack in &game.logger.melee_smacks {
-        let visible = game.teams.visible[team][smack.id].is_visible();
+        let visible = view.map_or(true, |team| game.teams.visible[team][smack.id].is_visible());
 
         if visible {
-            let _ = vec.write_u8(ClientMessage::MeleeSmack as u8);
-            unsafe {
-                let _ = vec.write_u16::<BigEndian>(smack.id.usize_unwrap() as u16);
-            }
+            write_record(vec, ClientMessage::MeleeSmack, |rec| unsafe {
+                let _ = rec.write_u16::<BigEndian>(smack.id.usize_unwrap() as u16);
+            });
         }
     }
-}
\ No newline at end of file
+}
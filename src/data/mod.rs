@@ -7,6 +7,16 @@ pub mod kdt_point;
 pub mod teams;
 pub mod move_groups;
 pub mod build_groups;
+pub mod patrol_groups;
+pub mod escort_groups;
 pub mod logger;
+pub mod protocol;
+pub mod snapshots;
+pub mod events;
+pub mod keyframe;
+pub mod lockstep;
+pub mod checksum;
+pub mod persist;
+pub mod lifecycle;
 mod target_type;
 mod move_stats;
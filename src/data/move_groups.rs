@@ -3,12 +3,21 @@ This is natural, but what is not natural is they will keep trying to reach the e
 To prevent this, we put units into a move group. As they reach their destination, the move group records how many
 have reached the destination and adds up their total area (plus 25% extra). Units then only have to move within the
 circular area to complete their movement.
+
+Groups moving in formation give each member a slot of its own, kept as an offset from the goal, so they spread out
+before they get there instead of all heading for the same tile.
 */
 extern crate core;
 use std::f64;
 use self::core::cell::Cell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use data::aliases::Formation;
 use data::units::UnitTarget;
+use data::persist::{Persist, Writer, Reader};
+
+// Space between neighbours in a formation
+const FORMATION_GAP: f64 = 0.5;
 
 #[derive(Clone, Debug)]
 pub struct MoveGroup {
@@ -16,6 +25,9 @@ pub struct MoveGroup {
     dist: Cell<f64>,
     xy: Cell<(f64, f64)>,
     membership: HashSet<UnitTarget>,
+    formation: Formation,
+    slots: HashMap<UnitTarget, (f64, f64)>,
+    top_speed: Option<f64>,
 }
 
 impl MoveGroup {
@@ -25,9 +37,50 @@ impl MoveGroup {
             dist: Cell::new(0.0),
             xy: Cell::new(xy),
             membership: membership,
+            formation: Formation::Cluster,
+            slots: HashMap::new(),
+            top_speed: None,
         }
     }
 
+    // members are each unit with where it is and its radius. top_speed is how fast the slowest of them goes.
+    pub fn in_formation(xy: (f64, f64), formation: Formation, members: &[(UnitTarget, (f64, f64), f64)], top_speed: f64) -> MoveGroup {
+        let mut mg = MoveGroup::new(xy, members.iter().map(|m| m.0).collect());
+
+        if formation == Formation::Cluster || members.is_empty() {
+            return mg;
+        }
+
+        let n = members.len() as f64;
+        let (cx, cy) = members.iter().fold((0.0, 0.0), |(sx, sy), &(_, (x, y), _)| (sx + x / n, sy + y / n));
+        let from_middle: Vec<(f64, f64)> = members.iter().map(|&(_, (x, y), _)| (x - cx, y - cy)).collect();
+
+        mg.formation = formation;
+        mg.top_speed = Some(top_speed);
+
+        if formation == Formation::Preserve {
+            mg.slots = members.iter().map(|m| m.0).zip(from_middle).collect();
+            return mg;
+        }
+
+        let widest = members.iter().fold(0.0, |widest, &(_, _, radius)| f64::max(widest, radius));
+        let spacing = widest * 2.0 + FORMATION_GAP;
+        let heading = f64::atan2(xy.1 - cy, xy.0 - cx);
+        let (sin, cos) = heading.sin_cos();
+
+        // Turned from (ahead, to the left) to the way the group is going
+        let slots: Vec<(f64, f64)> = formation_slots(formation, members.len(), spacing).into_iter().map(|(ahead, left)| {
+            (ahead * cos - left * sin, ahead * sin + left * cos)
+        }).collect();
+
+        // So members cross each other's paths as little as possible
+        for (&slot, member) in slots.iter().zip(assign_nearest(&from_middle, &slots)) {
+            mg.slots.insert(members[member].0, slot);
+        }
+
+        mg
+    }
+
     pub fn done_moving(&self, unit_target: UnitTarget, radius: f64) {
         if self.membership.contains(&unit_target) {
             self.area.set(self.area.get() + radius * radius);
@@ -46,4 +99,109 @@ impl MoveGroup {
     pub fn set_goal(&self, xy: (f64, f64)) {
         self.xy.set(xy);
     }
-}
\ No newline at end of file
+
+    // Sends the group somewhere new as if nobody had arrived yet.
+    pub fn restart(&self, xy: (f64, f64)) {
+        self.area.set(0.0);
+        self.dist.set(0.0);
+        self.xy.set(xy);
+    }
+
+    pub fn membership(&self) -> &HashSet<UnitTarget> {
+        &self.membership
+    }
+
+    pub fn formation(&self) -> Formation {
+        self.formation
+    }
+
+    // Where member's slot is. Members without one head for the goal.
+    pub fn goal_of(&self, member: UnitTarget) -> (f64, f64) {
+        let (x, y) = self.xy.get();
+
+        match self.slots.get(&member) {
+            Some(&(ox, oy)) => (x + ox, y + oy),
+            None => (x, y),
+        }
+    }
+
+    // How fast members may go to keep together.
+    pub fn top_speed(&self) -> Option<f64> {
+        self.top_speed
+    }
+}
+
+// Which of the points at from each slot goes to, in slot order. Each slot goes to whoever's nearest it out of those
+// left over, so there have to be at least as many points as slots.
+pub fn assign_nearest(from: &[(f64, f64)], slots: &[(f64, f64)]) -> Vec<usize> {
+    let mut unassigned: Vec<usize> = (0..from.len()).collect();
+
+    slots.iter().map(|&(sx, sy)| {
+        let dist = |ix: usize| {
+            let (x, y) = from[ix];
+            (x - sx) * (x - sx) + (y - sy) * (y - sy)
+        };
+        let nearest = (0..unassigned.len()).fold(0, |best, ix| {
+            if dist(unassigned[ix]) < dist(unassigned[best]) { ix } else { best }
+        });
+
+        unassigned.remove(nearest)
+    }).collect()
+}
+
+// Slots as (ahead, to the left) of the middle of the formation, facing the way it moves.
+fn formation_slots(formation: Formation, count: usize, spacing: f64) -> Vec<(f64, f64)> {
+    let across = |ix: usize, of: usize| (ix as f64 - (of as f64 - 1.0) * 0.5) * spacing;
+
+    match formation {
+        Formation::Line => {
+            (0..count).map(|ix| (0.0, across(ix, count))).collect()
+        }
+        Formation::Box => {
+            let cols = (count as f64).sqrt().ceil() as usize;
+            let rows = (count + cols - 1) / cols;
+            (0..count).map(|ix| (-across(ix / cols, rows), across(ix % cols, cols))).collect()
+        }
+        // One at the tip, then a pair more each rank back
+        Formation::Wedge => {
+            let ranks = count / 2;
+            (0..count).map(|ix| {
+                let rank = (ix + 1) / 2;
+                let side = if ix % 2 == 1 { 1.0 } else { -1.0 };
+                ((ranks as f64 * 0.5 - rank as f64) * spacing, side * rank as f64 * spacing)
+            }).collect()
+        }
+        Formation::Cluster | Formation::Preserve => vec![(0.0, 0.0); count],
+    }
+}
+
+impl Persist for MoveGroup {
+    fn save(&self, w: &mut Writer) {
+        self.area.save(w);
+        self.dist.save(w);
+        self.xy.save(w);
+        self.membership.save(w);
+        self.formation.save(w);
+        self.slots.save(w);
+        self.top_speed.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<MoveGroup> {
+        let area = Persist::load(r)?;
+        let dist = Persist::load(r)?;
+        let xy = Persist::load(r)?;
+        let membership = Persist::load(r)?;
+        let formation = Persist::load(r)?;
+        let slots = Persist::load(r)?;
+        let top_speed = Persist::load(r)?;
+
+        Ok(MoveGroup {
+            area: area,
+            dist: dist,
+            xy: xy,
+            membership: membership,
+            formation: formation,
+            slots: slots,
+            top_speed: top_speed,
+        })
+    }
+}
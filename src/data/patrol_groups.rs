@@ -0,0 +1,91 @@
+/* Units on patrol go round their waypoints as a group, the same way units moving together share a move group.
+Whoever gets to the current waypoint waits there, and once everyone still on the patrol has caught up they all set
+off for the next one, starting over from the first after the last.
+*/
+extern crate core;
+use std::io;
+use std::collections::HashSet;
+use self::core::cell::{Cell, RefCell};
+use data::move_groups::MoveGroup;
+use data::units::UnitTarget;
+use data::persist::{Persist, Writer, Reader, invalid};
+
+#[derive(Clone, Debug)]
+pub struct PatrolGroup {
+    waypoints: Vec<(f64, f64)>,
+    leg: Cell<usize>,
+    move_group: MoveGroup,
+    arrived: RefCell<HashSet<UnitTarget>>,
+}
+
+impl PatrolGroup {
+    pub fn new(waypoints: Vec<(f64, f64)>, membership: HashSet<UnitTarget>) -> PatrolGroup {
+        PatrolGroup {
+            move_group: MoveGroup::new(waypoints[0], membership),
+            waypoints: waypoints,
+            leg: Cell::new(0),
+            arrived: RefCell::new(HashSet::new()),
+        }
+    }
+
+    pub fn waypoints(&self) -> &[(f64, f64)] {
+        &self.waypoints
+    }
+
+    // Which waypoint the group is heading for.
+    pub fn leg(&self) -> usize {
+        self.leg.get()
+    }
+
+    pub fn move_group(&self) -> &MoveGroup {
+        &self.move_group
+    }
+
+    // Returns false if the unit had already arrived.
+    pub fn arrive(&self, unit_target: UnitTarget, radius: f64) -> bool {
+        if !self.move_group.membership().contains(&unit_target) || !self.arrived.borrow_mut().insert(unit_target) {
+            return false;
+        }
+
+        self.move_group.done_moving(unit_target, radius);
+        true
+    }
+
+    pub fn has_arrived(&self, unit_target: UnitTarget) -> bool {
+        self.arrived.borrow().contains(&unit_target)
+    }
+
+    pub fn next_leg(&self) {
+        let leg = (self.leg.get() + 1) % self.waypoints.len();
+
+        self.leg.set(leg);
+        self.arrived.borrow_mut().clear();
+        self.move_group.restart(self.waypoints[leg]);
+    }
+}
+
+impl Persist for PatrolGroup {
+    fn save(&self, w: &mut Writer) {
+        self.waypoints.save(w);
+        self.leg.save(w);
+        self.move_group.save(w);
+        self.arrived.borrow().save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<PatrolGroup> {
+        let waypoints: Vec<(f64, f64)> = Persist::load(r)?;
+        let leg: Cell<usize> = Persist::load(r)?;
+        let move_group = Persist::load(r)?;
+        let arrived = Persist::load(r)?;
+
+        if leg.get() >= waypoints.len() {
+            return Err(invalid("patrol leg"));
+        }
+
+        Ok(PatrolGroup {
+            waypoints: waypoints,
+            leg: leg,
+            move_group: move_group,
+            arrived: RefCell::new(arrived),
+        })
+    }
+}
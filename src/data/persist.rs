@@ -0,0 +1,626 @@
+/*
+ Writing the state of a game out byte for byte and reading it back.
+
+ Values are written in a fixed order without names, so a reader has to ask for exactly what the writer wrote, in
+ the same order. Floats go out as their bits: a game that is read back has to be the same game, not one that's a
+ rounding error away from it, or it wouldn't play out the same.
+
+ Orders shared by several units are written the first time one of them comes up and referred to by index after
+ that, so units that shared an order before still share one after.
+*/
+
+extern crate byteorder;
+extern crate bit_vec;
+
+use self::byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
+use self::bit_vec::BitVec;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::collections::vec_deque::VecDeque;
+use std::hash::Hash;
+use std::io;
+use std::io::Cursor;
+use std::io::prelude::*;
+use std::rc::Rc;
+use num::FromPrimitive;
+use rand_pcg::Pcg64Mcg;
+use serde_json;
+use libs::movement::{Angle, denormalize, make_from};
+use data::aliases::*;
+use data::kdt_point::{KDTUnit, KDTMissile};
+
+pub struct Writer {
+    vec: Cursor<Vec<u8>>,
+    orders: HashMap<*const Order, u32>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer {
+            vec: Cursor::new(Vec::new()),
+            orders: HashMap::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.vec.into_inner()
+    }
+}
+
+pub struct Reader<'a> {
+    cursor: Cursor<&'a [u8]>,
+    orders: Vec<Rc<Order>>,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader {
+            cursor: Cursor::new(bytes),
+            orders: Vec::new(),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.cursor.position() as usize == self.cursor.get_ref().len()
+    }
+}
+
+pub fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {} in saved state.", what))
+}
+
+pub trait Persist: Sized {
+    fn save(&self, w: &mut Writer);
+    fn load(r: &mut Reader) -> io::Result<Self>;
+}
+
+impl Persist for u8 {
+    fn save(&self, w: &mut Writer) {
+        let _ = w.vec.write_u8(*self);
+    }
+    fn load(r: &mut Reader) -> io::Result<u8> {
+        r.cursor.read_u8()
+    }
+}
+
+impl Persist for u16 {
+    fn save(&self, w: &mut Writer) {
+        let _ = w.vec.write_u16::<BigEndian>(*self);
+    }
+    fn load(r: &mut Reader) -> io::Result<u16> {
+        r.cursor.read_u16::<BigEndian>()
+    }
+}
+
+impl Persist for u32 {
+    fn save(&self, w: &mut Writer) {
+        let _ = w.vec.write_u32::<BigEndian>(*self);
+    }
+    fn load(r: &mut Reader) -> io::Result<u32> {
+        r.cursor.read_u32::<BigEndian>()
+    }
+}
+
+impl Persist for u64 {
+    fn save(&self, w: &mut Writer) {
+        let _ = w.vec.write_u64::<BigEndian>(*self);
+    }
+    fn load(r: &mut Reader) -> io::Result<u64> {
+        r.cursor.read_u64::<BigEndian>()
+    }
+}
+
+impl Persist for usize {
+    fn save(&self, w: &mut Writer) {
+        (*self as u64).save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<usize> {
+        u64::load(r).map(|n| n as usize)
+    }
+}
+
+impl Persist for isize {
+    fn save(&self, w: &mut Writer) {
+        let _ = w.vec.write_i64::<BigEndian>(*self as i64);
+    }
+    fn load(r: &mut Reader) -> io::Result<isize> {
+        r.cursor.read_i64::<BigEndian>().map(|n| n as isize)
+    }
+}
+
+impl Persist for f64 {
+    fn save(&self, w: &mut Writer) {
+        self.to_bits().save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<f64> {
+        u64::load(r).map(f64::from_bits)
+    }
+}
+
+impl Persist for bool {
+    fn save(&self, w: &mut Writer) {
+        (*self as u8).save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<bool> {
+        match u8::load(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("bool")),
+        }
+    }
+}
+
+impl Persist for String {
+    fn save(&self, w: &mut Writer) {
+        (self.len() as u32).save(w);
+        let _ = w.vec.write_all(self.as_bytes());
+    }
+    fn load(r: &mut Reader) -> io::Result<String> {
+        let len = u32::load(r)? as usize;
+        let left = r.cursor.get_ref().len() - r.cursor.position() as usize;
+
+        if len > left {
+            return Err(invalid("string"));
+        }
+
+        let mut bytes = vec![0; len];
+        r.cursor.read_exact(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|_| invalid("string"))
+    }
+}
+
+impl<T: Persist> Persist for Option<T> {
+    fn save(&self, w: &mut Writer) {
+        match *self {
+            Some(ref x) => {
+                true.save(w);
+                x.save(w);
+            }
+            None => false.save(w),
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<Option<T>> {
+        if bool::load(r)? {
+            Ok(Some(T::load(r)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: Persist, E: Persist> Persist for Result<T, E> {
+    fn save(&self, w: &mut Writer) {
+        match *self {
+            Ok(ref x) => {
+                true.save(w);
+                x.save(w);
+            }
+            Err(ref e) => {
+                false.save(w);
+                e.save(w);
+            }
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<Result<T, E>> {
+        if bool::load(r)? {
+            Ok(Ok(T::load(r)?))
+        } else {
+            Ok(Err(E::load(r)?))
+        }
+    }
+}
+
+impl<A: Persist, B: Persist> Persist for (A, B) {
+    fn save(&self, w: &mut Writer) {
+        self.0.save(w);
+        self.1.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<(A, B)> {
+        let a = A::load(r)?;
+        let b = B::load(r)?;
+        Ok((a, b))
+    }
+}
+
+impl<T: Persist> Persist for Vec<T> {
+    fn save(&self, w: &mut Writer) {
+        (self.len() as u32).save(w);
+        for x in self {
+            x.save(w);
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<Vec<T>> {
+        let len = u32::load(r)?;
+        let mut vec = Vec::new();
+        for _ in 0..len {
+            vec.push(T::load(r)?);
+        }
+        Ok(vec)
+    }
+}
+
+impl<T: Persist> Persist for VecDeque<T> {
+    fn save(&self, w: &mut Writer) {
+        (self.len() as u32).save(w);
+        for x in self {
+            x.save(w);
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<VecDeque<T>> {
+        Vec::load(r).map(VecDeque::from)
+    }
+}
+
+impl<T: Persist + Eq + Hash> Persist for HashSet<T> {
+    fn save(&self, w: &mut Writer) {
+        (self.len() as u32).save(w);
+        for x in self {
+            x.save(w);
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<HashSet<T>> {
+        Vec::load(r).map(|vec| vec.into_iter().collect())
+    }
+}
+
+impl<K: Persist + Eq + Hash, V: Persist> Persist for HashMap<K, V> {
+    fn save(&self, w: &mut Writer) {
+        (self.len() as u32).save(w);
+        for (k, v) in self {
+            k.save(w);
+            v.save(w);
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<HashMap<K, V>> {
+        Vec::<(K, V)>::load(r).map(|vec| vec.into_iter().collect())
+    }
+}
+
+impl<T: Persist + Copy> Persist for Cell<T> {
+    fn save(&self, w: &mut Writer) {
+        self.get().save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<Cell<T>> {
+        T::load(r).map(Cell::new)
+    }
+}
+
+impl Persist for BitVec {
+    fn save(&self, w: &mut Writer) {
+        self.len().save(w);
+        self.to_bytes().save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<BitVec> {
+        let len = usize::load(r)?;
+        let mut bits = BitVec::from_bytes(&Vec::<u8>::load(r)?);
+
+        if bits.len() < len {
+            return Err(invalid("bit vector"));
+        }
+
+        bits.truncate(len);
+        Ok(bits)
+    }
+}
+
+// The generator's state is private to rand_pcg, which only hands it out through serde.
+impl Persist for Pcg64Mcg {
+    fn save(&self, w: &mut Writer) {
+        serde_json::to_string(self).unwrap_or_default().save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<Pcg64Mcg> {
+        serde_json::from_str(&String::load(r)?).map_err(|_| invalid("random number generator"))
+    }
+}
+
+impl Persist for Angle {
+    fn save(&self, w: &mut Writer) {
+        denormalize(*self).save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<Angle> {
+        f64::load(r).map(|f| unsafe { make_from(f) })
+    }
+}
+
+impl Persist for MoveType {
+    fn save(&self, w: &mut Writer) {
+        (*self as u32).save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<MoveType> {
+        MoveType::from_u32(u32::load(r)?).ok_or_else(|| invalid("move type"))
+    }
+}
+
+impl Persist for MoveStance {
+    fn save(&self, w: &mut Writer) {
+        (*self as u8).save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<MoveStance> {
+        MoveStance::from_u8(u8::load(r)?).ok_or_else(|| invalid("move stance"))
+    }
+}
+
+impl Persist for Formation {
+    fn save(&self, w: &mut Writer) {
+        (*self as u8).save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<Formation> {
+        Formation::from_u8(u8::load(r)?).ok_or_else(|| invalid("formation"))
+    }
+}
+
+impl Persist for FireStance {
+    fn save(&self, w: &mut Writer) {
+        (*self as u8).save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<FireStance> {
+        FireStance::from_u8(u8::load(r)?).ok_or_else(|| invalid("fire stance"))
+    }
+}
+
+impl Persist for Damage {
+    fn save(&self, w: &mut Writer) {
+        match *self {
+            Damage::Single(amount) => {
+                0u8.save(w);
+                amount.save(w);
+            }
+            Damage::Splash(amount, radius) => {
+                1u8.save(w);
+                amount.save(w);
+                radius.save(w);
+            }
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<Damage> {
+        match u8::load(r)? {
+            0 => Ok(Damage::Single(f64::load(r)?)),
+            1 => {
+                let amount = f64::load(r)?;
+                let radius = f64::load(r)?;
+                Ok(Damage::Splash(amount, radius))
+            }
+            _ => Err(invalid("damage")),
+        }
+    }
+}
+
+impl Persist for Target {
+    fn save(&self, w: &mut Writer) {
+        match *self {
+            Target::Point(x, y) => {
+                0u8.save(w);
+                (x, y).save(w);
+            }
+            Target::Unit(target) => {
+                1u8.save(w);
+                target.save(w);
+            }
+            Target::None => 2u8.save(w),
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<Target> {
+        match u8::load(r)? {
+            0 => {
+                let (x, y) = <(f64, f64)>::load(r)?;
+                Ok(Target::Point(x, y))
+            }
+            1 => Ok(Target::Unit(Persist::load(r)?)),
+            2 => Ok(Target::None),
+            _ => Err(invalid("target")),
+        }
+    }
+}
+
+impl Persist for Attack {
+    fn save(&self, w: &mut Writer) {
+        match *self {
+            Attack::Missile(ref missile) => {
+                0u8.save(w);
+                missile.save(w);
+            }
+            Attack::Melee(dmg) => {
+                1u8.save(w);
+                dmg.save(w);
+            }
+            Attack::Suicide(dmg) => {
+                2u8.save(w);
+                dmg.save(w);
+            }
+            Attack::Laser(dmg) => {
+                3u8.save(w);
+                dmg.save(w);
+            }
+            Attack::Bomb(ref missile) => {
+                4u8.save(w);
+                missile.save(w);
+            }
+            Attack::LaserBomb(dmg) => {
+                5u8.save(w);
+                dmg.save(w);
+            }
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<Attack> {
+        match u8::load(r)? {
+            0 => Ok(Attack::Missile(Persist::load(r)?)),
+            1 => Ok(Attack::Melee(Persist::load(r)?)),
+            2 => Ok(Attack::Suicide(Persist::load(r)?)),
+            3 => Ok(Attack::Laser(Persist::load(r)?)),
+            4 => Ok(Attack::Bomb(Persist::load(r)?)),
+            5 => Ok(Attack::LaserBomb(Persist::load(r)?)),
+            _ => Err(invalid("attack")),
+        }
+    }
+}
+
+impl Persist for OrderType {
+    fn save(&self, w: &mut Writer) {
+        match *self {
+            OrderType::Move(ref mg) => {
+                0u8.save(w);
+                mg.save(w);
+            }
+            OrderType::AttackMove(ref mg) => {
+                1u8.save(w);
+                mg.save(w);
+            }
+            OrderType::AttackTarget(ref mg, target) => {
+                2u8.save(w);
+                mg.save(w);
+                target.save(w);
+            }
+            OrderType::Build(ref bg) => {
+                3u8.save(w);
+                bg.save(w);
+            }
+            OrderType::Assist(target) => {
+                4u8.save(w);
+                target.save(w);
+            }
+            OrderType::Stop => 5u8.save(w),
+            OrderType::Patrol(ref pg) => {
+                6u8.save(w);
+                pg.save(w);
+            }
+            OrderType::Guard(ref eg) => {
+                7u8.save(w);
+                eg.save(w);
+            }
+            OrderType::Follow(ref eg) => {
+                8u8.save(w);
+                eg.save(w);
+            }
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<OrderType> {
+        match u8::load(r)? {
+            0 => Ok(OrderType::Move(Persist::load(r)?)),
+            1 => Ok(OrderType::AttackMove(Persist::load(r)?)),
+            2 => {
+                let mg = Persist::load(r)?;
+                let target = Persist::load(r)?;
+                Ok(OrderType::AttackTarget(mg, target))
+            }
+            3 => Ok(OrderType::Build(Persist::load(r)?)),
+            4 => Ok(OrderType::Assist(Persist::load(r)?)),
+            5 => Ok(OrderType::Stop),
+            6 => Ok(OrderType::Patrol(Persist::load(r)?)),
+            7 => Ok(OrderType::Guard(Persist::load(r)?)),
+            8 => Ok(OrderType::Follow(Persist::load(r)?)),
+            _ => Err(invalid("order type")),
+        }
+    }
+}
+
+impl Persist for Order {
+    fn save(&self, w: &mut Writer) {
+        self.order_id.save(w);
+        self.order_type.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<Order> {
+        let order_id = Persist::load(r)?;
+        let order_type = Persist::load(r)?;
+
+        Ok(Order {
+            order_id: order_id,
+            order_type: order_type,
+        })
+    }
+}
+
+// Written as an index into the orders seen so far, followed by the order itself if it's new.
+impl Persist for Rc<Order> {
+    fn save(&self, w: &mut Writer) {
+        let key = &**self as *const Order;
+        let next = w.orders.len() as u32;
+
+        match w.orders.get(&key).cloned() {
+            Some(ix) => ix.save(w),
+            None => {
+                w.orders.insert(key, next);
+                next.save(w);
+                (**self).save(w);
+            }
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<Rc<Order>> {
+        let ix = u32::load(r)? as usize;
+
+        if ix < r.orders.len() {
+            Ok(r.orders[ix].clone())
+        } else if ix == r.orders.len() {
+            let order = Rc::new(Order::load(r)?);
+            r.orders.push(order.clone());
+            Ok(order)
+        } else {
+            Err(invalid("order index"))
+        }
+    }
+}
+
+impl Persist for TrainOrder {
+    fn save(&self, w: &mut Writer) {
+        self.order_id.save(w);
+        self.unit_type.save(w);
+        self.repeat.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<TrainOrder> {
+        let order_id = Persist::load(r)?;
+        let unit_type = Persist::load(r)?;
+        let repeat = Persist::load(r)?;
+
+        Ok(TrainOrder {
+            order_id: order_id,
+            unit_type: unit_type,
+            repeat: repeat,
+        })
+    }
+}
+
+impl Persist for KDTUnit {
+    fn save(&self, w: &mut Writer) {
+        self.target.save(w);
+        self.x.save(w);
+        self.y.save(w);
+        self.radius.save(w);
+        self.collision_radius.save(w);
+        self.weight.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<KDTUnit> {
+        let target = Persist::load(r)?;
+        let x = Persist::load(r)?;
+        let y = Persist::load(r)?;
+        let radius = Persist::load(r)?;
+        let collision_radius = Persist::load(r)?;
+        let weight = Persist::load(r)?;
+
+        Ok(KDTUnit {
+            target: target,
+            x: x,
+            y: y,
+            radius: radius,
+            collision_radius: collision_radius,
+            weight: weight,
+        })
+    }
+}
+
+impl Persist for KDTMissile {
+    fn save(&self, w: &mut Writer) {
+        self.id.save(w);
+        self.x.save(w);
+        self.y.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<KDTMissile> {
+        let id = Persist::load(r)?;
+        let x = Persist::load(r)?;
+        let y = Persist::load(r)?;
+
+        Ok(KDTMissile {
+            id: id,
+            x: x,
+            y: y,
+        })
+    }
+}
@@ -0,0 +1,517 @@
+/*
+ Every message that crosses the wire, in one place.
+
+ Connecting:
+   1. The client logs in with a JSON text frame: {"name": ..., "password": ...} or {"name": ..., "token": ...}.
+      A server hosting more than one match also needs "match": the join code of the one to join.
+      A bad login is closed with Policy (1008), too many attempts from one address with Again (1013).
+   2. The client sends a binary Handshake: u8 tag, u16 oldest version it speaks, u16 newest version it speaks.
+   3. The server answers with a frame holding HandshakeAccepted (and the Schema) or HandshakeRejected.
+      A rejected client, or one that sends anything else before handshaking, is disconnected.
+   4. The server sends a Keyframe with everything the client needs to start over, see data::keyframe.
+      Logging in again with the same name takes over the old connection and gets a new keyframe.
+
+ Server -> client (all big endian):
+   Each binary message is a frame.
+     u32     frame number
+     records until the end of the message
+   Each record is
+     u8      ClientMessage tag
+     varint  payload length in bytes (LEB128, 7 bits per byte, high bit set on all but the last byte)
+     payload
+   Clients skip records with tags they don't know, so new records can be added without breaking old clients.
+
+ Client -> server:
+   Each binary message is one ServerMessage record.
+     u8      ServerMessage tag
+     u32     order id
+     payload
+   Messages that are too big, too many in one frame, or that don't make sense (unknown tags, points off the map,
+   IDs out of range...) count as strikes against the player, who is disconnected after netcom::MAX_STRIKES
+   of them in netcom::STRIKE_WINDOW_SECS. Older strikes wear off.
+
+ Unit state is sent as deltas against acknowledged snapshots, see data::snapshots.
+ Clients acknowledge every frame's Snapshot record with a SnapshotAck.
+ One-shot events (UnitDeath, MissileExplode, OrderCompleted, OrderRejected, TrainingCompleted, MeleeSmack, Construction) only
+ arrive inside EventBatch records, which are resent until an EventAck covers them, see data::events.
+ Spectators' acknowledgements are ignored. They get every Snapshot in full and every EventBatch once.
+
+ Matches started with --lockstep send none of the above. Clients get a LockstepStart instead of a Keyframe and
+ simulate the match themselves from the orders in LockstepTurn records, see data::lockstep. A LockstepStart with
+ joinable 0 means the match has gone on too long to catch up with and no turns follow.
+
+ Frames only count up while the match is running. Before it starts, while it's paused and after it ends the server
+ sends MatchState records instead, and orders are dropped. TeamDefeated and TeamVictorious are sent the frame a team
+ is knocked out or wins, and a keyframe repeats all three for anyone who missed them, see data::lifecycle.
+ Players can Pause and Resume a running match, within a pause budget per team. Admins can pause without one and
+ change the match's speed with GameSpeed. Every change is answered with a MatchState to everyone.
+
+ Text frames after logging in are admin commands and get text frames back, see console.
+
+ A server playing back a replay looks the same as a live one, except that ReplayControl messages from anyone
+ watching pause, resume, seek or change its speed, and each is answered with a ReplayStatus to everyone.
+
+ Change PROTOCOL_VERSION whenever a payload changes shape.
+*/
+
+use std::io::Cursor;
+use std::io::prelude::*;
+use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
+use serde_json;
+
+pub const PROTOCOL_VERSION: u16 = 15;
+pub const MIN_PROTOCOL_VERSION: u16 = 15;
+
+// The team in MapInfo for spectators who see the whole map
+pub const OBSERVER_TEAM: u8 = 255;
+
+// Tags of server -> client records.
+// New tags only ever go on the end.
+#[derive(Clone, Copy, Debug)]
+pub enum ClientMessage {
+    UnitMove = 0,
+    UnitDeath = 1,
+    OrderCompleted = 2,
+    TrainingCompleted = 3,
+    MeleeSmack = 4,
+    MissileMove = 5,
+    MissileExplode = 6,
+    Construction = 7,
+    TeamInfo = 8,
+    MapInfo = 9,
+    UnitInfo = 10,
+    MissileInfo = 11,
+    MatchSeed = 12,
+    HandshakeAccepted = 13,
+    HandshakeRejected = 14,
+    Schema = 15,
+    Snapshot = 16,
+    UnitDelta = 17,
+    UnitLeave = 18,
+    EventBatch = 19,
+    Keyframe = 20,
+    UnitOrders = 21,
+    TrainQueue = 22,
+    OrderRejected = 23,
+    LockstepStart = 24,
+    LockstepTurn = 25,
+    Desync = 26,
+    ReplayStatus = 27,
+    MatchState = 28,
+    TeamDefeated = 29,
+    TeamVictorious = 30,
+}
+
+// Why a unit was left out of an order, sent in OrderRejected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RejectReason {
+    CantBuild = 0,
+    NotInBuildRoster = 1,
+    NotAStructure = 2,
+    CantTrain = 3,
+    NotInTrainRoster = 4,
+    NotAFactory = 5,
+    NotAnAlly = 6,
+}
+
+// Where a match is at, sent in MatchState.
+enum_from_primitive! {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatchPhase {
+    Waiting = 0,
+    Countdown = 1,
+    Running = 2,
+    Paused = 3,
+    Finished = 4,
+}
+}
+
+// Tags of client -> server records.
+// New tags only ever go on the end.
+enum_from_primitive! {
+#[derive(Clone, Copy, Debug)]
+pub enum ServerMessage {
+    Move = 0,
+    AttackMove = 1,
+    AttackTarget = 2,
+    Build = 3,
+    Train = 4,
+    Assist = 5,
+    Stop = 6,
+    MapInfoRequest = 7,
+    UnitInfoRequest = 8,
+    MissileInfoRequest = 9,
+    Handshake = 10,
+    SnapshotAck = 11,
+    EventAck = 12,
+    KeyframeRequest = 13,
+    Checksum = 14,
+    ReplayControl = 15,
+    Pause = 16,
+    Resume = 17,
+    GameSpeed = 18,
+    Patrol = 19,
+    Stance = 20,
+    Guard = 21,
+    Follow = 22,
+}
+}
+
+// What a ReplayControl asks for.
+enum_from_primitive! {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplayAction {
+    Pause = 0,
+    Resume = 1,
+    Seek = 2,
+    Speed = 3,
+}
+}
+
+impl ServerMessage {
+    // Orders change the game, so only players may send them
+    pub fn is_order(&self) -> bool {
+        match *self {
+            ServerMessage::Move |
+            ServerMessage::AttackMove |
+            ServerMessage::AttackTarget |
+            ServerMessage::Build |
+            ServerMessage::Train |
+            ServerMessage::Assist |
+            ServerMessage::Stop |
+            ServerMessage::Patrol |
+            ServerMessage::Stance |
+            ServerMessage::Guard |
+            ServerMessage::Follow => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Field {
+    pub name: &'static str,
+    pub kind: &'static str,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct MessageSchema {
+    pub name: &'static str,
+    pub tag: u8,
+    pub fields: &'static [Field],
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Schema {
+    pub version: u16,
+    pub client_messages: &'static [MessageSchema],
+    pub server_messages: &'static [MessageSchema],
+}
+
+macro_rules! fields {
+    ( $( ($name: expr, $kind: expr) ),* $(,)* ) => {
+        &[ $( Field { name: $name, kind: $kind } ),* ]
+    }
+}
+
+// Positions are in 64ths of a tile. Angles are in 255ths of a turn. Health and progress are in 255ths.
+// "?" marks fields that are only present under the named condition.
+// "[n]" marks fields repeated n times.
+pub const CLIENT_MESSAGES: &'static [MessageSchema] = &[
+    MessageSchema { name: "UnitMove", tag: ClientMessage::UnitMove as u8, fields: fields![
+        ("unit_type", "u8"), ("id", "u16"), ("x", "u16"), ("y", "u16"), ("anim", "u8"), ("team", "u8"),
+        ("facing", "u8"), ("health", "u8"), ("progress", "u8"), ("weapon_facings", "u8[weapons]"),
+        ("passenger_count", "u8?capacity>0"), ("passengers", "u16[passenger_count]"),
+        ("train_progress", "u8?train_rate>0"), ("move_stance", "u8"), ("fire_stance", "u8"),
+    ]},
+    MessageSchema { name: "UnitDeath", tag: ClientMessage::UnitDeath as u8, fields: fields![("id", "u16")] },
+    MessageSchema { name: "OrderCompleted", tag: ClientMessage::OrderCompleted as u8, fields: fields![("id", "u16"), ("order_id", "u32")] },
+    MessageSchema { name: "TrainingCompleted", tag: ClientMessage::TrainingCompleted as u8, fields: fields![("trainer", "u16"), ("trainee", "u16"), ("order_id", "u32")] },
+    MessageSchema { name: "MeleeSmack", tag: ClientMessage::MeleeSmack as u8, fields: fields![("id", "u16")] },
+    MessageSchema { name: "MissileMove", tag: ClientMessage::MissileMove as u8, fields: fields![
+        ("missile_type", "u8"), ("id", "u16"), ("x", "u16"), ("y", "u16"), ("team", "u8"),
+    ]},
+    MessageSchema { name: "MissileExplode", tag: ClientMessage::MissileExplode as u8, fields: fields![
+        ("missile_type", "u8"), ("id", "u16"), ("x", "u16"), ("y", "u16"), ("team", "u8"),
+    ]},
+    MessageSchema { name: "Construction", tag: ClientMessage::Construction as u8, fields: fields![("builder", "u16"), ("buildee", "u16")] },
+    MessageSchema { name: "TeamInfo", tag: ClientMessage::TeamInfo as u8, fields: fields![
+        ("team", "u8"), ("max_prime", "u32"), ("prime", "u32"), ("prime_output", "f64"), ("prime_drain", "f64"),
+        ("max_energy", "u32"), ("energy", "u32"), ("energy_output", "f64"), ("energy_drain", "f64"),
+    ]},
+    MessageSchema { name: "MapInfo", tag: ClientMessage::MapInfo as u8, fields: fields![
+        ("team", "u8"), ("width", "u16"), ("height", "u16"), ("tiles", "u8u8[width*height]"),
+        ("collisions", "u8[width*height]"), ("start_location_count", "u8"),
+        ("start_locations", "u16u16[start_location_count]"), ("prime_node_count", "u32"),
+        ("prime_nodes", "u16u16[prime_node_count]"),
+    ]},
+    MessageSchema { name: "UnitInfo", tag: ClientMessage::UnitInfo as u8, fields: fields![("json", "utf8[rest]")] },
+    MessageSchema { name: "MissileInfo", tag: ClientMessage::MissileInfo as u8, fields: fields![("json", "utf8[rest]")] },
+    MessageSchema { name: "MatchSeed", tag: ClientMessage::MatchSeed as u8, fields: fields![("seed", "u64")] },
+    MessageSchema { name: "HandshakeAccepted", tag: ClientMessage::HandshakeAccepted as u8, fields: fields![("version", "u16")] },
+    MessageSchema { name: "HandshakeRejected", tag: ClientMessage::HandshakeRejected as u8, fields: fields![("min_version", "u16"), ("max_version", "u16")] },
+    MessageSchema { name: "Schema", tag: ClientMessage::Schema as u8, fields: fields![("json", "utf8[rest]")] },
+    MessageSchema { name: "Snapshot", tag: ClientMessage::Snapshot as u8, fields: fields![("baseline_frame", "u32")] },
+    MessageSchema { name: "UnitDelta", tag: ClientMessage::UnitDelta as u8, fields: fields![
+        ("id", "u16"), ("mask", "u16"), ("x", "u16?mask&1"), ("y", "u16?mask&1"), ("anim", "u8?mask&2"),
+        ("team", "u8?mask&4"), ("facing", "u8?mask&8"), ("health", "u8?mask&16"), ("progress", "u8?mask&32"),
+        ("weapon_facings", "u8[weapons]?mask&64"), ("passenger_count", "u8?mask&128"),
+        ("passengers", "u16[passenger_count]?mask&128"), ("train_progress", "u8?mask&256"),
+        ("move_stance", "u8?mask&512"), ("fire_stance", "u8?mask&512"),
+    ]},
+    MessageSchema { name: "UnitLeave", tag: ClientMessage::UnitLeave as u8, fields: fields![("id", "u16")] },
+    MessageSchema { name: "EventBatch", tag: ClientMessage::EventBatch as u8, fields: fields![
+        ("seq", "u32"), ("frame", "u32"), ("events", "record[rest]"),
+    ]},
+    MessageSchema { name: "Keyframe", tag: ClientMessage::Keyframe as u8, fields: fields![("events_through", "u32")] },
+    MessageSchema { name: "UnitOrders", tag: ClientMessage::UnitOrders as u8, fields: fields![
+        ("id", "u16"), ("order_count", "u8"), ("orders", "order[order_count]"),
+    ]},
+    MessageSchema { name: "OrderRejected", tag: ClientMessage::OrderRejected as u8, fields: fields![
+        ("id", "u16"), ("order_id", "u32"), ("reason", "u8"),
+    ]},
+    MessageSchema { name: "TrainQueue", tag: ClientMessage::TrainQueue as u8, fields: fields![
+        ("id", "u16"), ("order_count", "u8"), ("orders", "u32u16u8[order_count]"),
+    ]},
+    MessageSchema { name: "LockstepStart", tag: ClientMessage::LockstepStart as u8, fields: fields![("delay", "u32"), ("joinable", "u8")] },
+    MessageSchema { name: "LockstepTurn", tag: ClientMessage::LockstepTurn as u8, fields: fields![
+        ("frame", "u32"), ("checked_frame", "u32"), ("checksum", "u64"), ("orders", "turn_order[rest]"),
+    ]},
+    MessageSchema { name: "Desync", tag: ClientMessage::Desync as u8, fields: fields![("frame", "u32"), ("checksum", "u64")] },
+    MessageSchema { name: "ReplayStatus", tag: ClientMessage::ReplayStatus as u8, fields: fields![
+        ("frame", "u32"), ("last_frame", "u32"), ("paused", "u8"), ("speed", "f64"),
+    ]},
+    MessageSchema { name: "MatchState", tag: ClientMessage::MatchState as u8, fields: fields![
+        ("phase", "u8"), ("countdown", "u32"), ("end_frame", "u32"), ("paused_by", "u8"), ("speed", "f64"),
+        ("pause_left", "u8u32[rest]"),
+    ]},
+    MessageSchema { name: "TeamDefeated", tag: ClientMessage::TeamDefeated as u8, fields: fields![("team", "u8")] },
+    MessageSchema { name: "TeamVictorious", tag: ClientMessage::TeamVictorious as u8, fields: fields![("team", "u8")] },
+];
+
+// OrderRejected reasons are
+//   0 CantBuild: the unit has no build rate
+//   1 NotInBuildRoster: the unit can't build that type
+//   2 NotAStructure: Build only places structures
+//   3 CantTrain: the unit has no train rate
+//   4 NotInTrainRoster: the unit can't train that type
+//   5 NotAFactory: only structures train units
+//   6 NotAnAlly: Guard only protects units of the same team
+
+// MatchState phases are
+//   0 Waiting: not every player has joined yet
+//   1 Countdown: starts in countdown frames
+//   2 Running
+//   3 Paused
+//   4 Finished: the TeamDefeated and TeamVictorious records before it say how
+// end_frame is the frame the time limit runs out on, or 0 without one.
+// paused_by is the team that paused the match, or 255 if it isn't paused or an admin paused it.
+// speed is how many times faster than its fps the match runs. pause_left is each team and the frames it can still
+// keep the match paused for.
+
+// An order in UnitOrders is a u32 order id and a u8 ServerMessage tag followed by
+//   Move, AttackMove: f64 x, f64 y, u8 formation, f64 x, f64 y of the unit's own slot
+//   AttackTarget, Assist, Guard, Follow: u16 target (65535 if it's gone)
+//   Build: u16 unit type, u8 0 then f64 x, f64 y or u8 1 then u16 target
+//   Stop: nothing
+//   Patrol: u8 the waypoint it's heading for, u8 waypoint count, f64 x, f64 y for each waypoint
+
+// An order in LockstepTurn is a u8 team, a varint length and then the order exactly as its player sent it.
+
+// Unit lists run to the end of the message.
+// A Patrol with one waypoint goes back and forth between it and the middle of where its units were.
+// Stance takes effect straight away rather than being queued. 255 leaves a stance as it is.
+// Guard and Follow keep their units in a ring around the target. Guards also fight off whoever attacks it.
+
+// Formations are 0 Cluster, 1 Preserve, 2 Line, 3 Box and 4 Wedge. Line, box and wedge face the way the group moves.
+
+// Move stances are 0 HoldPosition, 1 Maneuver and 2 Roam. Fire stances are 0 HoldFire, 1 ReturnFire and 2 FireAtWill.
+pub const SERVER_MESSAGES: &'static [MessageSchema] = &[
+    MessageSchema { name: "Move", tag: ServerMessage::Move as u8, fields: fields![
+        ("order_id", "u32"), ("x", "f64"), ("y", "f64"), ("formation", "u8"), ("queue_order", "u8"), ("units", "u16[rest]"),
+    ]},
+    MessageSchema { name: "AttackMove", tag: ServerMessage::AttackMove as u8, fields: fields![
+        ("order_id", "u32"), ("x", "f64"), ("y", "f64"), ("formation", "u8"), ("queue_order", "u8"), ("units", "u16[rest]"),
+    ]},
+    MessageSchema { name: "AttackTarget", tag: ServerMessage::AttackTarget as u8, fields: fields![
+        ("order_id", "u32"), ("target", "u16"), ("queue_order", "u8"), ("units", "u16[rest]"),
+    ]},
+    MessageSchema { name: "Build", tag: ServerMessage::Build as u8, fields: fields![
+        ("order_id", "u32"), ("unit_type", "u16"), ("x", "f64"), ("y", "f64"), ("queue_order", "u8"), ("units", "u16[rest]"),
+    ]},
+    MessageSchema { name: "Train", tag: ServerMessage::Train as u8, fields: fields![
+        ("order_id", "u32"), ("unit_type", "u16"), ("repeat", "u8"), ("queue_order", "u8"), ("units", "u16[rest]"),
+    ]},
+    MessageSchema { name: "Assist", tag: ServerMessage::Assist as u8, fields: fields![
+        ("order_id", "u32"), ("target", "u16"), ("queue_order", "u8"), ("units", "u16[rest]"),
+    ]},
+    MessageSchema { name: "Stop", tag: ServerMessage::Stop as u8, fields: fields![
+        ("order_id", "u32"), ("queue_order", "u8"), ("units", "u16[rest]"),
+    ]},
+    MessageSchema { name: "MapInfoRequest", tag: ServerMessage::MapInfoRequest as u8, fields: fields![("order_id", "u32")] },
+    MessageSchema { name: "UnitInfoRequest", tag: ServerMessage::UnitInfoRequest as u8, fields: fields![("order_id", "u32")] },
+    MessageSchema { name: "MissileInfoRequest", tag: ServerMessage::MissileInfoRequest as u8, fields: fields![("order_id", "u32")] },
+    MessageSchema { name: "Handshake", tag: ServerMessage::Handshake as u8, fields: fields![("min_version", "u16"), ("max_version", "u16")] },
+    MessageSchema { name: "SnapshotAck", tag: ServerMessage::SnapshotAck as u8, fields: fields![("order_id", "u32"), ("frame", "u32")] },
+    MessageSchema { name: "EventAck", tag: ServerMessage::EventAck as u8, fields: fields![("order_id", "u32"), ("seq", "u32")] },
+    MessageSchema { name: "KeyframeRequest", tag: ServerMessage::KeyframeRequest as u8, fields: fields![("order_id", "u32")] },
+    MessageSchema { name: "Checksum", tag: ServerMessage::Checksum as u8, fields: fields![
+        ("order_id", "u32"), ("frame", "u32"), ("checksum", "u64"),
+    ]},
+    MessageSchema { name: "ReplayControl", tag: ServerMessage::ReplayControl as u8, fields: fields![
+        ("order_id", "u32"), ("action", "u8"), ("frame", "u32?action=2"), ("speed", "f64?action=3"),
+    ]},
+    MessageSchema { name: "Pause", tag: ServerMessage::Pause as u8, fields: fields![("order_id", "u32")] },
+    MessageSchema { name: "Resume", tag: ServerMessage::Resume as u8, fields: fields![("order_id", "u32")] },
+    MessageSchema { name: "GameSpeed", tag: ServerMessage::GameSpeed as u8, fields: fields![("order_id", "u32"), ("speed", "f64")] },
+    MessageSchema { name: "Patrol", tag: ServerMessage::Patrol as u8, fields: fields![
+        ("order_id", "u32"), ("waypoint_count", "u8"), ("waypoints", "f64f64[waypoint_count]"), ("queue_order", "u8"),
+        ("units", "u16[rest]"),
+    ]},
+    MessageSchema { name: "Stance", tag: ServerMessage::Stance as u8, fields: fields![
+        ("order_id", "u32"), ("move_stance", "u8"), ("fire_stance", "u8"), ("units", "u16[rest]"),
+    ]},
+    MessageSchema { name: "Guard", tag: ServerMessage::Guard as u8, fields: fields![
+        ("order_id", "u32"), ("target", "u16"), ("queue_order", "u8"), ("units", "u16[rest]"),
+    ]},
+    MessageSchema { name: "Follow", tag: ServerMessage::Follow as u8, fields: fields![
+        ("order_id", "u32"), ("target", "u16"), ("queue_order", "u8"), ("units", "u16[rest]"),
+    ]},
+];
+
+pub fn schema() -> Schema {
+    Schema {
+        version: PROTOCOL_VERSION,
+        client_messages: CLIENT_MESSAGES,
+        server_messages: SERVER_MESSAGES,
+    }
+}
+
+// Starts a frame. Records get appended to it with write_record.
+pub fn new_frame(frame_number: u32) -> Cursor<Vec<u8>> {
+    let mut frame = Cursor::new(Vec::new());
+    let _ = frame.write_u32::<BigEndian>(frame_number);
+    frame
+}
+
+// Writes a record whose payload is whatever the closure writes.
+pub fn write_record<F>(vec: &mut Cursor<Vec<u8>>, tag: ClientMessage, write_payload: F)
+    where F: FnOnce(&mut Cursor<Vec<u8>>)
+{
+    let mut payload = Cursor::new(Vec::new());
+    write_payload(&mut payload);
+    let payload = payload.into_inner();
+
+    let _ = vec.write_u8(tag as u8);
+    write_varint(vec, payload.len() as u32);
+    let _ = vec.write_all(&payload);
+}
+
+pub fn write_varint(vec: &mut Cursor<Vec<u8>>, mut n: u32) {
+    while n >= 0x80 {
+        let _ = vec.write_u8((n & 0x7F) as u8 | 0x80);
+        n >>= 7;
+    }
+    let _ = vec.write_u8(n as u8);
+}
+
+pub fn read_varint(bytes: &mut Cursor<Vec<u8>>) -> Option<u32> {
+    let mut n: u32 = 0;
+
+    for shift in 0..5 {
+        let byte = bytes.read_u8().ok()?;
+        n |= ((byte & 0x7F) as u32) << (shift * 7);
+
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+
+    None
+}
+
+// Reads the version range out of a Handshake message.
+pub fn read_handshake(msg: &[u8]) -> Option<(u16, u16)> {
+    let mut bytes = Cursor::new(msg.to_vec());
+
+    match bytes.read_u8() {
+        Ok(tag) if tag == ServerMessage::Handshake as u8 => {
+            let min = bytes.read_u16::<BigEndian>().ok()?;
+            let max = bytes.read_u16::<BigEndian>().ok()?;
+            Some((min, max))
+        }
+        _ => None,
+    }
+}
+
+// The newest version both sides speak, if there is one.
+pub fn negotiate(client_min: u16, client_max: u16) -> Option<u16> {
+    let version = if client_max < PROTOCOL_VERSION { client_max } else { PROTOCOL_VERSION };
+
+    if version >= client_min && version >= MIN_PROTOCOL_VERSION {
+        Some(version)
+    }
+    else {
+        None
+    }
+}
+
+pub fn encode_handshake_accepted(version: u16) -> Vec<u8> {
+    let mut frame = new_frame(0);
+
+    write_record(&mut frame, ClientMessage::HandshakeAccepted, |rec| {
+        let _ = rec.write_u16::<BigEndian>(version);
+    });
+
+    write_record(&mut frame, ClientMessage::Schema, |rec| {
+        let json = serde_json::to_string(&schema()).unwrap();
+        let _ = rec.write_all(json.as_bytes());
+    });
+
+    frame.into_inner()
+}
+
+pub fn encode_keyframe_request() -> Vec<u8> {
+    let mut msg = Cursor::new(Vec::new());
+    let _ = msg.write_u8(ServerMessage::KeyframeRequest as u8);
+    let _ = msg.write_u32::<BigEndian>(0);
+    msg.into_inner()
+}
+
+pub fn encode_handshake_rejected() -> Vec<u8> {
+    let mut frame = new_frame(0);
+
+    write_record(&mut frame, ClientMessage::HandshakeRejected, |rec| {
+        let _ = rec.write_u16::<BigEndian>(MIN_PROTOCOL_VERSION);
+        let _ = rec.write_u16::<BigEndian>(PROTOCOL_VERSION);
+    });
+
+    frame.into_inner()
+}
+
+#[test]
+fn records_are_length_prefixed() {
+    let mut frame = new_frame(7);
+
+    write_record(&mut frame, ClientMessage::UnitDeath, |rec| {
+        let _ = rec.write_u16::<BigEndian>(300);
+    });
+    write_record(&mut frame, ClientMessage::UnitInfo, |rec| {
+        let _ = rec.write_all(&[1; 200]);
+    });
+
+    let mut bytes = Cursor::new(frame.into_inner());
+    assert_eq!(bytes.read_u32::<BigEndian>().unwrap(), 7);
+    assert_eq!(bytes.read_u8().unwrap(), ClientMessage::UnitDeath as u8);
+    assert_eq!(read_varint(&mut bytes), Some(2));
+    assert_eq!(bytes.read_u16::<BigEndian>().unwrap(), 300);
+    assert_eq!(bytes.read_u8().unwrap(), ClientMessage::UnitInfo as u8);
+    assert_eq!(read_varint(&mut bytes), Some(200));
+}
+
+#[test]
+fn negotiates_newest_shared_version() {
+    assert_eq!(negotiate(0, 0), None);
+    assert_eq!(negotiate(1, PROTOCOL_VERSION + 5), Some(PROTOCOL_VERSION));
+    assert_eq!(negotiate(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 5), None);
+}
@@ -0,0 +1,308 @@
+/*
+ Unit state is sent as a delta against the last snapshot a team acknowledged.
+
+ Each team keeps the snapshots it was sent but hasn't acknowledged yet. Every frame starts with a Snapshot record
+ naming the baseline frame. Units new to the team since that frame get a full UnitMove record, units that changed get
+ a UnitDelta with only the changed fields, and units that died or left vision get a UnitLeave. Units that didn't
+ change aren't mentioned at all, so the client carries them over from its copy of the baseline.
+
+ With no usable baseline (nothing acknowledged yet, or the acknowledged frame was dropped) every unit is sent in full.
+*/
+
+extern crate byteorder;
+
+use self::byteorder::{WriteBytesExt, BigEndian};
+use std::collections::vec_deque::VecDeque;
+use std::io::Cursor;
+use std::io::prelude::*;
+use data::aliases::*;
+use data::protocol::write_record;
+
+// Snapshots older than this many frames are dropped even if they were never acknowledged
+pub const SNAPSHOT_HISTORY: usize = 32;
+pub const NO_BASELINE: u32 = u32::max_value();
+
+// Bits of the UnitDelta field mask
+pub const DELTA_POSITION: u16 = 1 << 0;
+pub const DELTA_ANIM: u16 = 1 << 1;
+pub const DELTA_TEAM: u16 = 1 << 2;
+pub const DELTA_FACING: u16 = 1 << 3;
+pub const DELTA_HEALTH: u16 = 1 << 4;
+pub const DELTA_PROGRESS: u16 = 1 << 5;
+pub const DELTA_WEAPONS: u16 = 1 << 6;
+pub const DELTA_PASSENGERS: u16 = 1 << 7;
+pub const DELTA_TRAIN: u16 = 1 << 8;
+pub const DELTA_STANCE: u16 = 1 << 9;
+
+// A unit as the client sees it, already quantized the way it goes over the wire.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnitState {
+    pub id: UnitID,
+    pub unit_type: u8,
+    pub x: u16,
+    pub y: u16,
+    pub anim: u8,
+    pub team: u8,
+    pub facing: u8,
+    pub health: u8,
+    pub progress: u8,
+    pub weapon_facings: Vec<u8>,
+    pub passengers: Option<Vec<u16>>,
+    pub train_progress: Option<u8>,
+    pub move_stance: u8,
+    pub fire_stance: u8,
+}
+
+#[derive(Clone, Debug)]
+struct Snapshot {
+    frame: u32,
+    // Sorted by id
+    units: Vec<UnitState>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Snapshots {
+    history: VecUID<TeamID, VecDeque<Snapshot>>,
+    acked: VecUID<TeamID, Option<u32>>,
+}
+
+impl Snapshots {
+    pub fn new(max_teams: usize) -> Snapshots {
+        Snapshots {
+            history: VecUID::full_vec(max_teams, VecDeque::new()),
+            acked: VecUID::full_vec(max_teams, None),
+        }
+    }
+
+    pub fn acknowledge(&mut self, team: TeamID, frame: u32) {
+        let newer = match self.acked[team] {
+            Some(acked) => frame > acked,
+            None => true,
+        };
+
+        if newer && self.history[team].iter().any(|s| s.frame == frame) {
+            self.acked[team] = Some(frame);
+
+            // Acknowledgements only move forward so older snapshots can't be a baseline again
+            while self.history[team].front().map_or(false, |s| s.frame < frame) {
+                self.history[team].pop_front();
+            }
+        }
+    }
+
+    // The frame the team's next deltas are built on, if any.
+    pub fn baseline(&self, team: TeamID) -> Option<u32> {
+        self.acked[team]
+    }
+
+    // Forgets everything the team was sent so the next frame is sent in full.
+    pub fn reset(&mut self, team: TeamID) {
+        self.history[team].clear();
+        self.acked[team] = None;
+    }
+
+    // Writes the Snapshot record and unit records for this frame and remembers what was sent.
+    // units must be sorted by id.
+    pub fn encode(&mut self, team: TeamID, frame: u32, units: Vec<UnitState>, vec: &mut Cursor<Vec<u8>>) {
+        {
+            let baseline = match self.acked[team] {
+                Some(acked) => self.history[team].iter().find(|s| s.frame == acked),
+                None => None,
+            };
+
+            let baseline_frame = baseline.map_or(NO_BASELINE, |s| s.frame);
+            let old_units: &[UnitState] = baseline.map_or(&[], |s| &s.units);
+
+            write_record(vec, ClientMessage::Snapshot, |rec| {
+                let _ = rec.write_u32::<BigEndian>(baseline_frame);
+            });
+
+            let mut old_ix = 0;
+
+            for new in units.iter() {
+                while old_ix < old_units.len() && old_units[old_ix].id < new.id {
+                    write_leave(vec, &old_units[old_ix]);
+                    old_ix += 1;
+                }
+
+                if old_ix < old_units.len() && old_units[old_ix].id == new.id {
+                    let old = &old_units[old_ix];
+
+                    if old.unit_type != new.unit_type {
+                        write_full(vec, new);
+                    }
+                    else if old != new {
+                        write_delta(vec, old, new);
+                    }
+
+                    old_ix += 1;
+                }
+                else {
+                    write_full(vec, new);
+                }
+            }
+
+            for old in &old_units[old_ix..] {
+                write_leave(vec, old);
+            }
+        }
+
+        let history = &mut self.history[team];
+        history.push_back(Snapshot { frame: frame, units: units });
+
+        if history.len() > SNAPSHOT_HISTORY {
+            let dropped = history.pop_front().map(|s| s.frame);
+
+            if dropped == self.acked[team] {
+                self.acked[team] = None;
+            }
+        }
+    }
+}
+
+fn write_full(vec: &mut Cursor<Vec<u8>>, unit: &UnitState) {
+    write_record(vec, ClientMessage::UnitMove, |rec| unsafe {
+        let _ = rec.write_u8(unit.unit_type);
+        let _ = rec.write_u16::<BigEndian>(unit.id.usize_unwrap() as u16);
+        let _ = rec.write_u16::<BigEndian>(unit.x);
+        let _ = rec.write_u16::<BigEndian>(unit.y);
+        let _ = rec.write_u8(unit.anim);
+        let _ = rec.write_u8(unit.team);
+        let _ = rec.write_u8(unit.facing);
+        let _ = rec.write_u8(unit.health);
+        let _ = rec.write_u8(unit.progress);
+        let _ = rec.write_all(&unit.weapon_facings);
+        write_passengers(rec, &unit.passengers);
+
+        if let Some(train_progress) = unit.train_progress {
+            let _ = rec.write_u8(train_progress);
+        }
+
+        let _ = rec.write_u8(unit.move_stance);
+        let _ = rec.write_u8(unit.fire_stance);
+    });
+}
+
+fn write_delta(vec: &mut Cursor<Vec<u8>>, old: &UnitState, new: &UnitState) {
+    let mut mask = 0;
+
+    if (old.x, old.y) != (new.x, new.y) { mask |= DELTA_POSITION; }
+    if old.anim != new.anim { mask |= DELTA_ANIM; }
+    if old.team != new.team { mask |= DELTA_TEAM; }
+    if old.facing != new.facing { mask |= DELTA_FACING; }
+    if old.health != new.health { mask |= DELTA_HEALTH; }
+    if old.progress != new.progress { mask |= DELTA_PROGRESS; }
+    if old.weapon_facings != new.weapon_facings { mask |= DELTA_WEAPONS; }
+    if old.passengers != new.passengers { mask |= DELTA_PASSENGERS; }
+    if old.train_progress != new.train_progress { mask |= DELTA_TRAIN; }
+    if (old.move_stance, old.fire_stance) != (new.move_stance, new.fire_stance) { mask |= DELTA_STANCE; }
+
+    write_record(vec, ClientMessage::UnitDelta, |rec| unsafe {
+        let _ = rec.write_u16::<BigEndian>(new.id.usize_unwrap() as u16);
+        let _ = rec.write_u16::<BigEndian>(mask);
+
+        if mask & DELTA_POSITION != 0 {
+            let _ = rec.write_u16::<BigEndian>(new.x);
+            let _ = rec.write_u16::<BigEndian>(new.y);
+        }
+        if mask & DELTA_ANIM != 0 { let _ = rec.write_u8(new.anim); }
+        if mask & DELTA_TEAM != 0 { let _ = rec.write_u8(new.team); }
+        if mask & DELTA_FACING != 0 { let _ = rec.write_u8(new.facing); }
+        if mask & DELTA_HEALTH != 0 { let _ = rec.write_u8(new.health); }
+        if mask & DELTA_PROGRESS != 0 { let _ = rec.write_u8(new.progress); }
+        if mask & DELTA_WEAPONS != 0 { let _ = rec.write_all(&new.weapon_facings); }
+        if mask & DELTA_PASSENGERS != 0 { write_passengers(rec, &new.passengers); }
+        if mask & DELTA_TRAIN != 0 { let _ = rec.write_u8(new.train_progress.unwrap_or(0)); }
+        if mask & DELTA_STANCE != 0 {
+            let _ = rec.write_u8(new.move_stance);
+            let _ = rec.write_u8(new.fire_stance);
+        }
+    });
+}
+
+fn write_leave(vec: &mut Cursor<Vec<u8>>, unit: &UnitState) {
+    write_record(vec, ClientMessage::UnitLeave, |rec| unsafe {
+        let _ = rec.write_u16::<BigEndian>(unit.id.usize_unwrap() as u16);
+    });
+}
+
+fn write_passengers(rec: &mut Cursor<Vec<u8>>, passengers: &Option<Vec<u16>>) {
+    if let Some(ref passengers) = *passengers {
+        let _ = rec.write_u8(passengers.len() as u8);
+
+        for &psngr in passengers {
+            let _ = rec.write_u16::<BigEndian>(psngr);
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_unit(id: usize, x: u16) -> UnitState {
+    UnitState {
+        id: unsafe { UnitID::usize_wrap(id) },
+        unit_type: 0,
+        x: x,
+        y: 0,
+        anim: 0,
+        team: 0,
+        facing: 0,
+        health: 255,
+        progress: 255,
+        weapon_facings: vec![0],
+        passengers: None,
+        train_progress: None,
+        move_stance: MoveStance::Maneuver as u8,
+        fire_stance: FireStance::FireAtWill as u8,
+    }
+}
+
+// Tags of every record in a frame, after the frame number.
+#[cfg(test)]
+fn record_tags(frame: Vec<u8>) -> Vec<u8> {
+    use self::byteorder::ReadBytesExt;
+    use data::protocol::read_varint;
+
+    let len = frame.len() as u64;
+    let mut bytes = Cursor::new(frame);
+    let mut tags = Vec::new();
+    bytes.set_position(4);
+
+    while bytes.position() < len {
+        tags.push(bytes.read_u8().unwrap());
+        let rec_len = read_varint(&mut bytes).unwrap() as u64;
+        let pos = bytes.position();
+        bytes.set_position(pos + rec_len);
+    }
+
+    tags
+}
+
+#[test]
+fn only_changes_are_sent_after_an_ack() {
+    use data::protocol::new_frame;
+
+    let team = unsafe { TeamID::usize_wrap(0) };
+    let mut snapshots = Snapshots::new(1);
+
+    let mut first = new_frame(0);
+    snapshots.encode(team, 0, vec![test_unit(1, 10), test_unit(2, 10), test_unit(3, 10)], &mut first);
+    let full = ClientMessage::UnitMove as u8;
+    assert_eq!(record_tags(first.into_inner()), vec![ClientMessage::Snapshot as u8, full, full, full]);
+
+    // Unacknowledged, so still everything in full
+    let mut second = new_frame(1);
+    snapshots.encode(team, 1, vec![test_unit(1, 10), test_unit(2, 10), test_unit(3, 10)], &mut second);
+    assert_eq!(record_tags(second.into_inner()), vec![ClientMessage::Snapshot as u8, full, full, full]);
+
+    snapshots.acknowledge(team, 1);
+
+    // Unit 1 is untouched, unit 2 moved, unit 3 left vision and unit 4 entered it
+    let mut third = new_frame(2);
+    snapshots.encode(team, 2, vec![test_unit(1, 10), test_unit(2, 11), test_unit(4, 10)], &mut third);
+    assert_eq!(record_tags(third.into_inner()), vec![
+        ClientMessage::Snapshot as u8,
+        ClientMessage::UnitDelta as u8,
+        ClientMessage::UnitLeave as u8,
+        full,
+    ]);
+}
@@ -1,3 +1,6 @@
+use std::io;
+use data::persist::{Persist, Writer, Reader};
+
 #[derive(Clone, Copy, Debug)]
 pub struct TargetType {
     byte: u8,
@@ -33,4 +36,13 @@ impl TargetType {
     pub fn has_a_match(self, other: TargetType) -> bool {
         self.byte & other.byte > 0
     }
-}
\ No newline at end of file
+}
+
+impl Persist for TargetType {
+    fn save(&self, w: &mut Writer) {
+        self.byte.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<TargetType> {
+        u8::load(r).map(|byte| TargetType { byte: byte })
+    }
+}
@@ -1,5 +1,8 @@
+use std::io;
 use pathing::path_grid::PathGrid;
+use pathing::terrain;
 use data::aliases::*;
+use data::persist::{Persist, Writer, Reader};
 
 #[derive(Clone,Debug)]
 pub struct Teams {
@@ -12,9 +15,12 @@ pub struct Teams {
     pub energy_output: VecUID<TeamID, f64>,
     pub prime_drain: VecUID<TeamID, f64>,
     pub energy_drain: VecUID<TeamID, f64>,
-    pub jps_grid: VecUID<TeamID, PathGrid>,
+    // One for each movement class in pathing::terrain
+    pub jps_grids: VecUID<TeamID, Vec<PathGrid>>,
     pub visible: VecUID<TeamID, VecUID<UnitID, Visibility>>,
     pub visible_missiles: VecUID<TeamID, VecUID<MissileID, Visibility>>,
+    // Teams an admin has shown the whole map to
+    pub revealed: VecUID<TeamID, bool>,
     build_power_distribution: VecUID<TeamID, VecUID<UnitID, f64>>,
     train_power_distribution: VecUID<TeamID, VecUID<UnitID, f64>>,
 }
@@ -31,9 +37,10 @@ impl Teams {
             energy_output: VecUID::full_vec(max_teams, 0.0),
             prime_drain: VecUID::full_vec(max_teams, 0.0),
             energy_drain: VecUID::full_vec(max_teams, 0.0),
-            jps_grid: VecUID::full_vec(max_teams, PathGrid::new(width, height)),
+            jps_grids: VecUID::full_vec(max_teams, vec![PathGrid::new(width, height); terrain::MOVE_CLASSES]),
             visible: VecUID::full_vec(max_teams, VecUID::full_vec(max_units, Visibility::new())),
             visible_missiles: VecUID::full_vec(max_teams, VecUID::full_vec(max_units * 4, Visibility::new())),
+            revealed: VecUID::full_vec(max_teams, false),
             build_power_distribution: VecUID::full_vec(max_teams, VecUID::full_vec(max_units, 0.0)),
             train_power_distribution: VecUID::full_vec(max_teams, VecUID::full_vec(max_units, 0.0)),
         }
@@ -86,4 +93,43 @@ impl Teams {
 
         vec
     }
-}
\ No newline at end of file
+}
+
+impl Persist for Teams {
+    fn save(&self, w: &mut Writer) {
+        self.available_ids.save(w);
+        self.prime.save(w);
+        self.energy.save(w);
+        self.max_prime.save(w);
+        self.max_energy.save(w);
+        self.prime_output.save(w);
+        self.energy_output.save(w);
+        self.prime_drain.save(w);
+        self.energy_drain.save(w);
+        self.jps_grids.save(w);
+        self.visible.save(w);
+        self.visible_missiles.save(w);
+        self.revealed.save(w);
+        self.build_power_distribution.save(w);
+        self.train_power_distribution.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<Teams> {
+        Ok(Teams {
+            available_ids: Persist::load(r)?,
+            prime: Persist::load(r)?,
+            energy: Persist::load(r)?,
+            max_prime: Persist::load(r)?,
+            max_energy: Persist::load(r)?,
+            prime_output: Persist::load(r)?,
+            energy_output: Persist::load(r)?,
+            prime_drain: Persist::load(r)?,
+            energy_drain: Persist::load(r)?,
+            jps_grids: Persist::load(r)?,
+            visible: Persist::load(r)?,
+            visible_missiles: Persist::load(r)?,
+            revealed: Persist::load(r)?,
+            build_power_distribution: Persist::load(r)?,
+            train_power_distribution: Persist::load(r)?,
+        })
+    }
+}
@@ -7,6 +7,8 @@ use std::collections::hash_map::Entry;
 use std::collections::vec_deque::VecDeque;
 use std::ops::{Index, IndexMut};
 use std::fmt::Debug;
+use std::io;
+use data::persist::{Persist, Writer, Reader};
 
 pub unsafe trait USizeWrapper {
     unsafe fn usize_unwrap(self) -> usize;
@@ -37,6 +39,18 @@ impl<UID, T: Clone> VecUID<UID, T> {
     }
 }
 
+impl<UID, T: Persist> Persist for VecUID<UID, T> {
+    fn save(&self, w: &mut Writer) {
+        self.vec.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<VecUID<UID, T>> {
+        Ok(VecUID {
+            vec: Persist::load(r)?,
+            index_type: PhantomData,
+        })
+    }
+}
+
 impl<UID: USizeWrapper, T> Index<UID> for VecUID<UID, T> {
     type Output = T;
 
@@ -148,6 +162,22 @@ impl<T: USizeWrapper + Ord + Copy + Debug> UIDPool<T> {
     }
 }
 
+impl<T: Persist> Persist for UIDPool<T> {
+    fn save(&self, w: &mut Writer) {
+        self.available_ids.save(w);
+        self.iteratable_ids.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<UIDPool<T>> {
+        let available_ids = Persist::load(r)?;
+        let iteratable_ids = Persist::load(r)?;
+
+        Ok(UIDPool {
+            available_ids: available_ids,
+            iteratable_ids: iteratable_ids,
+        })
+    }
+}
+
 macro_rules! id_wrappers {
     ( $( $x:ident ),* $(,)* ) => {
         $(
@@ -163,6 +193,15 @@ macro_rules! id_wrappers {
                     $x(id)
                 }
             }
+
+            impl Persist for $x {
+                fn save(&self, w: &mut Writer) {
+                    self.0.save(w);
+                }
+                fn load(r: &mut Reader) -> io::Result<$x> {
+                    usize::load(r).map($x)
+                }
+            }
         )*
     }
 }
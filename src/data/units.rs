@@ -1,12 +1,14 @@
 extern crate rand;
 
 use std::rc::Rc;
+use std::io;
 use serde_json;
 use libs::movement::{Angle, normalize};
-use std::collections::{HashSet};
+use std::collections::{HashSet, HashMap};
 use std::collections::vec_deque::VecDeque;
 use data::aliases::*;
 use data::kdt_point::KDTUnit;
+use data::persist::{Persist, Writer, Reader, invalid};
 
 macro_rules! copy_or_borrow_getters_setters_single {
     ($field_name:ident, $set_field:ident, copy, $ty:ty ) => {
@@ -117,6 +119,22 @@ macro_rules! units {
             )*
         }
 
+        impl Persist for $singular_name {
+            fn save(&self, w: &mut Writer) {
+                $(
+                    self.$field_name.save(w);
+                )*
+            }
+
+            fn load(r: &mut Reader) -> io::Result<$singular_name> {
+                Ok($singular_name {
+                    $(
+                        $field_name: Persist::load(r)?
+                    ),*
+                })
+            }
+        }
+
         #[derive(Clone,Debug)]
         pub struct $plural_name {
             available_ids: UIDPool<$uid>,
@@ -146,6 +164,25 @@ macro_rules! units {
                 copy_or_borrow_getters_setters_aos!($uid, $plural_name, $field_name, $set_field, $copy_or_borrow, $ty);
             )*
 
+            // Everything but the prototypes and type names, which come from the definitions the game was made with.
+            pub fn save_state(&self, w: &mut Writer) {
+                self.available_ids.save(w);
+                self.elements.save(w);
+            }
+
+            pub fn load_state(&mut self, r: &mut Reader) -> io::Result<()> {
+                let available_ids = Persist::load(r)?;
+                let elements: VecUID<$uid, $singular_name> = Persist::load(r)?;
+
+                if elements.len() != self.elements.len() {
+                    return Err(invalid(stringify!($plural_name)));
+                }
+
+                self.available_ids = available_ids;
+                self.elements = elements;
+                Ok(())
+            }
+
             pub fn make_from_name(&mut self, fps: f64, name: String) -> Option<$uid> {
                 if let Some(type_id) = self.uid_mapping.name_to_id(name) {
                     self.make(fps,type_id)
@@ -155,6 +192,11 @@ macro_rules! units {
                 }
             }
 
+            // The type with this name. Unlike make_from_name this never hands out a type id for an unknown name.
+            pub fn type_named(&self, name: &str) -> Option<$type_id> {
+                self.uid_mapping.id(name.to_string()).filter(|&id| unsafe { id.usize_unwrap() } < self.prototypes.len())
+            }
+
             pub fn make(&mut self, fps: f64, type_id: $type_id) -> Option<$uid> {
                 let mut proto = self.prototypes[type_id].clone();
 
@@ -233,6 +275,22 @@ macro_rules! weapon {
                 copy_or_borrow_getters_setters_single!($field_name, $set_field, $copy_or_borrow, $ty);
             )*
         }
+
+        impl Persist for $name {
+            fn save(&self, w: &mut Writer) {
+                $(
+                    self.$field_name.save(w);
+                )*
+            }
+
+            fn load(r: &mut Reader) -> io::Result<$name> {
+                Ok($name {
+                    $(
+                        $field_name: Persist::load(r)?
+                    ),*
+                })
+            }
+        }
     }
 }
 
@@ -290,6 +348,22 @@ macro_rules! missiles {
             )*
         }
 
+        impl Persist for $singular_name {
+            fn save(&self, w: &mut Writer) {
+                $(
+                    self.$field_name.save(w);
+                )*
+            }
+
+            fn load(r: &mut Reader) -> io::Result<$singular_name> {
+                Ok($singular_name {
+                    $(
+                        $field_name: Persist::load(r)?
+                    ),*
+                })
+            }
+        }
+
         #[derive(Clone,Debug)]
         pub struct $plural_name {
             available_ids: UIDPool<$uid>,
@@ -319,6 +393,25 @@ macro_rules! missiles {
                 copy_or_borrow_getters_setters_aos!($uid, $plural_name, $field_name, $set_field, $copy_or_borrow, $ty);
             )*
 
+            // Everything but the prototypes and type names, which come from the definitions the game was made with.
+            pub fn save_state(&self, w: &mut Writer) {
+                self.available_ids.save(w);
+                self.elements.save(w);
+            }
+
+            pub fn load_state(&mut self, r: &mut Reader) -> io::Result<()> {
+                let available_ids = Persist::load(r)?;
+                let elements: VecUID<$uid, $singular_name> = Persist::load(r)?;
+
+                if elements.len() != self.elements.len() {
+                    return Err(invalid(stringify!($plural_name)));
+                }
+
+                self.available_ids = available_ids;
+                self.elements = elements;
+                Ok(())
+            }
+
             pub fn make(&mut self, fps: f64, type_id: $type_id) -> Option<$uid> {
                 let mut proto = self.prototypes[type_id].clone();
 
@@ -519,6 +612,30 @@ impl JsonConfigure for TargetType {
     }
 }
 
+impl JsonConfigure for MoveStance {
+    fn json_configure(&mut self, field_name: &str, v: &serde_json::value::Value) {
+        match v.as_str() {
+            Some("hold_position") => *self = MoveStance::HoldPosition,
+            Some("maneuver") => *self = MoveStance::Maneuver,
+            Some("roam") => *self = MoveStance::Roam,
+            Some(other) => panic!("Couldn't configure {}. {} is not a valid string.", field_name, other),
+            None => panic!("Couldn't configure {}. The value wasn't a string.", field_name),
+        }
+    }
+}
+
+impl JsonConfigure for FireStance {
+    fn json_configure(&mut self, field_name: &str, v: &serde_json::value::Value) {
+        match v.as_str() {
+            Some("hold_fire") => *self = FireStance::HoldFire,
+            Some("return_fire") => *self = FireStance::ReturnFire,
+            Some("fire_at_will") => *self = FireStance::FireAtWill,
+            Some(other) => panic!("Couldn't configure {}. {} is not a valid string.", field_name, other),
+            None => panic!("Couldn't configure {}. The value wasn't a string.", field_name),
+        }
+    }
+}
+
 impl JsonConfigure for MoveType {
     fn json_configure(&mut self, field_name: &str, v: &serde_json::value::Value) {
         if let &serde_json::value::Value::String(ref s) = v {
@@ -538,6 +655,12 @@ impl JsonConfigure for MoveType {
                 "hover" => {
                     *self = MoveType::Hover;
                 }
+                "underwater" => {
+                    *self = MoveType::Underwater;
+                }
+                "amphibious" => {
+                    *self = MoveType::Amphibious;
+                }
                 other => {
                     panic!("Couldn't configure {}. {} is not a valid string.", field_name, other);
                 }
@@ -709,10 +832,10 @@ units!(Units, Unit, UnitID, UnitTypeID,
     (orders,                mut_orders,             VecDeque<Rc<Order>>,            borrow, none, VecDeque::new()),
     (build_rate,            set_build_rate,         f64,                            copy,   time, 0.0),
     (build_range,           set_build_range,        f64,                            copy,   none, 0.0),
-    (build_roster_names,    mut_build_roster_names, Vec<String>,                    borrow, none, Vec::new()),
-    (train_roster_names,    mut_train_roster_names, Vec<String>,                    borrow, none, Vec::new()),
-    (build_roster,          mut_build_roster,       HashSet<UnitTypeID>,            borrow, none, HashSet::new()),
-    (train_roster,          mut_train_roster,       HashSet<UnitTypeID>,            borrow, none, HashSet::new()),
+    (build_roster,          mut_build_roster,       Vec<String>,                    borrow, none, Vec::new()), // Names, as in the JSON
+    (train_roster,          mut_train_roster,       Vec<String>,                    borrow, none, Vec::new()), // Names, as in the JSON
+    (build_roster_ids,      mut_build_roster_ids,   HashSet<UnitTypeID>,            borrow, none, HashSet::new()),
+    (train_roster_ids,      mut_train_roster_ids,   HashSet<UnitTypeID>,            borrow, none, HashSet::new()),
     (train_rate,            set_train_rate,         f64,                            copy,   time, 0.0),
     (train_progress,        set_train_progress,     f64,                            copy,   none, 0.0),
     (train_queue,           mut_train_queue,        VecDeque<TrainOrder>,           borrow, none, VecDeque::new()),
@@ -722,12 +845,16 @@ units!(Units, Unit, UnitID, UnitTypeID,
     (size,                  set_size,               usize,                          copy,   none, 0),
     (target_type,           set_target_type,        TargetType,                     copy,   none, TargetType::new()),
     (move_type,             set_move_type,          MoveType,                       copy,   none, MoveType::None),
+    (move_stance,           set_move_stance,        MoveStance,                     copy,   none, MoveStance::Maneuver),
+    (fire_stance,           set_fire_stance,        FireStance,                     copy,   none, FireStance::FireAtWill),
+    (provoked,              set_provoked,           f64,                            copy,   none, 0.0), // Seconds left to return fire for
     (collision_type,        set_collision_type,     TargetType,                     copy,   none, TargetType::new()),
 	(ignores_stealth,		set_ignores_stealth,	bool,							copy,	none, false),
     (ignores_cloak,         set_ignores_cloak,      bool,                           copy,   none, false),
     (is_structure,          set_is_structure,       bool,                           copy,   none, false),
     (is_automatic,          set_is_automatic,       bool,                           copy,   none, false),
     (is_extractor,          set_is_extractor,       bool,                           copy,   none, false),
+    (is_commander,          set_is_commander,       bool,                           copy,   none, false), // Losing every commander loses the match under the commander victory condition
     (is_stealthed,          set_is_stealthed,       usize,                          copy,   none, 0), // Anything greater than 0 is stealthed
     (is_cloaked,            set_is_cloaked,         usize,                          copy,   none, 0), // Anything greater than 0 is cloaked
     (engagement_range,      set_engagement_range,   f64,                            copy,   none, 0.0),
@@ -792,6 +919,22 @@ pub struct UnitTarget {
     unit_id: UnitID,
 }
 
+impl Persist for UnitTarget {
+    fn save(&self, w: &mut Writer) {
+        self.soul_id.save(w);
+        self.unit_id.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<UnitTarget> {
+        let soul_id = Persist::load(r)?;
+        let unit_id = Persist::load(r)?;
+
+        Ok(UnitTarget {
+            soul_id: soul_id,
+            unit_id: unit_id,
+        })
+    }
+}
+
 impl Units {
     pub fn kill_unit(&mut self, id: UnitID) {
         self.available_ids.put_id(id);
@@ -803,6 +946,25 @@ impl Units {
         self.prototypes[type_id].clone()
     }
 
+    // Gives every order its own allocation. Units that shared an order keep sharing the new one.
+    pub fn unshare_orders(&mut self) {
+        // The originals are held on to until the end so no address gets reused while it's a key
+        let mut copies: HashMap<*const Order, (Rc<Order>, Rc<Order>)> = HashMap::new();
+
+        for id in self.iter() {
+            for order in self.mut_orders(id).iter_mut() {
+                let key = &**order as *const Order;
+                let original = order.clone();
+                let copy = copies.entry(key).or_insert_with(|| (original.clone(), Rc::new((*original).clone()))).1.clone();
+                *order = copy;
+            }
+        }
+    }
+
+    pub fn num_unit_types(&self) -> usize {
+        self.prototypes.len()
+    }
+
     pub fn iter(&self) -> Vec<UnitID> {
         self.available_ids.iter()
     }
@@ -0,0 +1,264 @@
+/*
+ Deciding who gets in.
+
+ Passwords and join tokens are only ever kept as salted PBKDF2-SHA256 hashes and are compared in constant time.
+ Connection attempts and failed logins are limited per IP address so nobody on the network can hammer the server
+ with guesses.
+*/
+
+use sha2::Sha256;
+use hmac::Hmac;
+use pbkdf2::pbkdf2;
+use subtle::ConstantTimeEq;
+use hex;
+use rand;
+use rand::Rng;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::collections::vec_deque::VecDeque;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use libs::netcom::Role;
+
+pub const HASH_PREFIX: &'static str = "pbkdf2-sha256:";
+// Slow enough that a leaked match file doesn't give its passwords away
+pub const HASH_ROUNDS: u32 = 100_000;
+
+// Per IP address
+pub const MAX_CONNECTIONS: usize = 20;
+pub const CONNECTION_WINDOW_SECS: u64 = 10;
+pub const MAX_FAILED_LOGINS: usize = 5;
+pub const FAILED_LOGIN_WINDOW_SECS: u64 = 60;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecretHash {
+    rounds: u32,
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+impl SecretHash {
+    // Hashes secret with a fresh random salt.
+    pub fn of(secret: &str) -> SecretHash {
+        SecretHash::with_salt(secret, rand::thread_rng().gen(), HASH_ROUNDS)
+    }
+
+    // Something to compare secrets against when there's nothing real to compare them with.
+    pub fn dummy() -> SecretHash {
+        SecretHash::with_salt("", [0; 16], HASH_ROUNDS)
+    }
+
+    fn with_salt(secret: &str, salt: [u8; 16], rounds: u32) -> SecretHash {
+        let mut hash = [0; 32];
+        pbkdf2::<Hmac<Sha256>>(secret.as_bytes(), &salt, rounds as usize, &mut hash);
+
+        SecretHash {
+            rounds: rounds,
+            salt: salt,
+            hash: hash,
+        }
+    }
+
+    // Reads hashes written by to_string, i.e. "pbkdf2-sha256:<rounds>:<32 hex digit salt>:<64 hex digit hash>".
+    pub fn parse(text: &str) -> Result<SecretHash, String> {
+        if !text.starts_with(HASH_PREFIX) {
+            return Err(format!("Password hashes must start with {} (make one with --hash-password).", HASH_PREFIX));
+        }
+
+        let parts: Vec<&str> = text[HASH_PREFIX.len()..].split(':').collect();
+        if parts.len() != 3 {
+            return Err("Password hashes need rounds, salt and hash after the prefix, split by colons.".to_string());
+        }
+
+        let rounds = match parts[0].parse() {
+            Ok(rounds) if rounds > 0 => rounds,
+            _ => return Err("Password hashes need a positive number of rounds.".to_string()),
+        };
+        let mut salt = [0; 16];
+        match hex::decode(parts[1]) {
+            Ok(ref bytes) if bytes.len() == 16 => salt.copy_from_slice(bytes),
+            _ => return Err("Password hashes need a salt of 32 hex digits.".to_string()),
+        }
+        let mut hash = [0; 32];
+        match hex::decode(parts[2]) {
+            Ok(ref bytes) if bytes.len() == 32 => hash.copy_from_slice(bytes),
+            _ => return Err("Password hashes need a hash of 64 hex digits.".to_string()),
+        }
+
+        Ok(SecretHash {
+            rounds: rounds,
+            salt: salt,
+            hash: hash,
+        })
+    }
+
+    pub fn to_string(&self) -> String {
+        format!("{}{}:{}:{}", HASH_PREFIX, self.rounds, hex::encode(&self.salt), hex::encode(&self.hash))
+    }
+
+    pub fn matches(&self, secret: &str) -> bool {
+        let other = SecretHash::with_salt(secret, self.salt, self.rounds);
+        self.hash.ct_eq(&other.hash).into()
+    }
+}
+
+// A random token someone can join with instead of a password.
+pub fn new_token<R: Rng>(rng: &mut R) -> String {
+    let bytes: [u8; 16] = rng.gen();
+    hex::encode(&bytes)
+}
+
+// The first thing a client sends, as a JSON text frame.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Login {
+    pub name: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+    // Which match to join when a server hosts more than one
+    #[serde(default, rename = "match")]
+    pub join_code: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub password: Option<SecretHash>,
+    pub token: Option<SecretHash>,
+    pub role: Role,
+}
+
+// The role the login grants, if it's right. dummy is SecretHash::dummy, made once up front.
+pub fn check_login(roster: &HashMap<String, Credentials>, dummy: &SecretHash, login: &Login) -> Option<Role> {
+    let creds = roster.get(&login.name);
+
+    // Every secret given is hashed exactly once, against the dummy when there's nothing real to check it against,
+    // so turning a login down takes as long whether the name is known or not
+    let matches = |hash: Option<&SecretHash>, secret: &Option<String>| {
+        match *secret {
+            Some(ref secret) => {
+                let ok = hash.unwrap_or(dummy).matches(secret);
+                ok && hash.is_some()
+            }
+            None => false,
+        }
+    };
+
+    let password_ok = matches(creds.and_then(|c| c.password.as_ref()), &login.password);
+    let token_ok = matches(creds.and_then(|c| c.token.as_ref()), &login.token);
+
+    match creds {
+        Some(creds) if password_ok || token_ok => Some(creds.role),
+        _ => None,
+    }
+}
+
+// Allows up to max hits per key (an address, or a player's name) in any window.
+pub struct RateLimiter<K = IpAddr> {
+    max: usize,
+    window: Duration,
+    hits: HashMap<K, VecDeque<Instant>>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(max: usize, window: Duration) -> RateLimiter<K> {
+        RateLimiter {
+            max: max,
+            window: window,
+            hits: HashMap::new(),
+        }
+    }
+
+    pub fn is_limited(&mut self, key: K, now: Instant) -> bool {
+        self.count(&key, now) >= self.max
+    }
+
+    // Hits in the window up to now.
+    pub fn count(&mut self, key: &K, now: Instant) -> usize {
+        self.forget_old(now);
+        self.hits.get(key).map_or(0, |hits| hits.len())
+    }
+
+    // Records a hit. Returns false if the key was already at its limit.
+    pub fn hit(&mut self, key: K, now: Instant) -> bool {
+        if self.count(&key, now) >= self.max {
+            return false;
+        }
+
+        self.hits.entry(key).or_insert_with(VecDeque::new).push_back(now);
+        true
+    }
+
+    pub fn forget(&mut self, key: &K) {
+        self.hits.remove(key);
+    }
+
+    fn forget_old(&mut self, now: Instant) {
+        let window = self.window;
+
+        for hits in self.hits.values_mut() {
+            while hits.front().map_or(false, |&t| now.duration_since(t) >= window) {
+                hits.pop_front();
+            }
+        }
+
+        self.hits.retain(|_, hits| !hits.is_empty());
+    }
+}
+
+#[test]
+fn hashes_round_trip_and_match() {
+    let hash = SecretHash::of("hunter2");
+    assert_eq!(SecretHash::parse(&hash.to_string()), Ok(hash.clone()));
+    assert!(hash.matches("hunter2"));
+    assert!(!hash.matches("hunter3"));
+    assert!(SecretHash::parse("sha256:1234").is_err());
+    assert!(SecretHash::parse("pbkdf2-sha256:0:00:00").is_err());
+
+    // The same password salted twice hashes differently
+    let again = SecretHash::of("hunter2");
+    assert!(again != hash);
+    assert!(again.matches("hunter2"));
+}
+
+#[test]
+fn rate_limiter_forgets_after_window() {
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    let mut limiter = RateLimiter::new(2, Duration::from_secs(10));
+    let start = Instant::now();
+
+    assert!(limiter.hit(ip, start));
+    assert!(limiter.hit(ip, start));
+    assert!(!limiter.hit(ip, start + Duration::from_secs(5)));
+    assert!(limiter.hit(ip, start + Duration::from_secs(10)));
+}
+
+#[test]
+fn logins_without_a_match_join_the_only_one() {
+    use serde_json;
+
+    let login: Login = serde_json::from_str(r#"{"name": "p1", "password": "hunter2"}"#).unwrap();
+    assert_eq!(login.join_code, "");
+
+    let login: Login = serde_json::from_str(r#"{"name": "p1", "token": "abc", "match": "red"}"#).unwrap();
+    assert_eq!(login.join_code, "red");
+}
+
+#[test]
+fn logins_need_a_known_name_and_its_secret() {
+    use serde_json;
+
+    let hash = |secret| SecretHash::with_salt(secret, [1; 16], 1);
+    let mut roster = HashMap::new();
+    roster.insert("p1".to_string(), Credentials { password: Some(hash("hunter2")), token: None, role: Role::Player(0) });
+    roster.insert("p2".to_string(), Credentials { password: None, token: Some(hash("abc")), role: Role::Player(1) });
+    let dummy = SecretHash::with_salt("", [0; 16], 1);
+    let check = |json| check_login(&roster, &dummy, &serde_json::from_str(json).unwrap());
+
+    assert_eq!(check(r#"{"name": "p1", "password": "hunter2"}"#), Some(Role::Player(0)));
+    assert_eq!(check(r#"{"name": "p1", "password": "hunter3"}"#), None);
+    assert_eq!(check(r#"{"name": "p2", "token": "abc"}"#), Some(Role::Player(1)));
+    assert_eq!(check(r#"{"name": "p2", "password": "abc"}"#), None);
+    // The dummy's own secret gets nobody in
+    assert_eq!(check(r#"{"name": "p3", "password": ""}"#), None);
+}
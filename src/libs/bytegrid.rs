@@ -1,6 +1,8 @@
 extern crate bit_vec;
 
 use self::bit_vec::BitVec;
+use std::io;
+use data::persist::{Persist, Writer, Reader, invalid};
 
 pub type Point = (isize, isize);
 
@@ -11,6 +13,29 @@ pub struct ByteGrid {
     vec: BitVec,
 }
 
+impl Persist for ByteGrid {
+    fn save(&self, w: &mut Writer) {
+        self.w.save(w);
+        self.h.save(w);
+        self.vec.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<ByteGrid> {
+        let w = isize::load(r)?;
+        let h = isize::load(r)?;
+        let vec: BitVec = Persist::load(r)?;
+
+        if w < 0 || h < 0 || vec.len() != (w * h) as usize {
+            return Err(invalid("byte grid"));
+        }
+
+        Ok(ByteGrid {
+            w: w,
+            h: h,
+            vec: vec,
+        })
+    }
+}
+
 impl ByteGrid {
     pub fn new(w: isize, h: isize) -> ByteGrid {
         let mut vec = BitVec::with_capacity((w * h) as usize);
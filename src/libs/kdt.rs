@@ -4,6 +4,8 @@ extern crate time;
 use std::ops::Rem;
 use self::rand::Rng;
 use self::time::PreciseTime;
+use std::io;
+use data::persist::{Persist, Writer, Reader, invalid};
 
 #[derive(Clone)]
 pub struct KDTree<T>
@@ -31,6 +33,69 @@ pub trait Dimensions {
     fn radii(&self, dim: usize) -> f64;
 }
 
+impl Persist for Tree {
+    fn save(&self, w: &mut Writer) {
+        match *self {
+            Tree::Fork(line, left, mid, right) => {
+                0u8.save(w);
+                line.save(w);
+                left.save(w);
+                mid.save(w);
+                right.save(w);
+            }
+            Tree::Leaf(start, end) => {
+                1u8.save(w);
+                start.save(w);
+                end.save(w);
+            }
+        }
+    }
+    fn load(r: &mut Reader) -> io::Result<Tree> {
+        match u8::load(r)? {
+            0 => {
+                let line = Persist::load(r)?;
+                let left = Persist::load(r)?;
+                let mid = Persist::load(r)?;
+                let right = Persist::load(r)?;
+                Ok(Tree::Fork(line, left, mid, right))
+            }
+            1 => {
+                let start = Persist::load(r)?;
+                let end = Persist::load(r)?;
+                Ok(Tree::Leaf(start, end))
+            }
+            _ => Err(invalid("kd-tree")),
+        }
+    }
+}
+
+// Saved as built rather than rebuilt, since the order of the points decides the order queries return them in.
+impl<T: Persist + Dimensions> Persist for KDTree<T> {
+    fn save(&self, w: &mut Writer) {
+        (self.trees.len() as u32).save(w);
+        for tree in self.trees.iter() {
+            tree.save(w);
+        }
+        self.vec.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<KDTree<T>> {
+        let mut trees = [Tree::Leaf(0, 0); 1024];
+
+        if u32::load(r)? as usize != trees.len() {
+            return Err(invalid("kd-tree"));
+        }
+
+        for tree in trees.iter_mut() {
+            *tree = Tree::load(r)?;
+        }
+
+        Ok(KDTree {
+            trees: trees,
+            vec: Persist::load(r)?,
+        })
+    }
+}
+
 impl<T: Clone + Dimensions> KDTree<T> {
     pub fn new(vec: Vec<T>) -> KDTree<T> {
         let len = vec.len();
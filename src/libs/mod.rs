@@ -1,5 +1,6 @@
 pub mod kdt;
 pub mod netcom;
+pub mod auth;
 pub mod movement;
 pub mod bytegrid;
 pub mod tmx_decode;
@@ -1,66 +1,121 @@
 extern crate ws;
 
 use std::collections::HashMap;
-use self::ws::{Handler, Sender, Message, Error, ErrorKind, CloseCode, listen};
+use std::mem;
+use self::ws::{Handler, Handshake, Sender, Message, Error, CloseCode, listen};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use serde_json;
+use data::protocol;
+use libs::auth;
+use libs::auth::{Credentials, Login, RateLimiter, SecretHash};
 
 impl Handler for Player {
+    fn on_open(&mut self, shake: Handshake) -> Result<(), Error> {
+        self.ip = shake.peer_addr.map(|addr| addr.ip());
+
+        if let Some(ip) = self.ip {
+            let now = Instant::now();
+            let mut lobby = self.lobby.lock().unwrap();
+
+            if lobby.failed_logins.is_limited(ip, now) || !lobby.connections.hit(ip, now) {
+                println!("Turning away {} for too many attempts.", ip);
+                let _ = self.out.close_with_reason(CloseCode::Again, "Too many attempts, try again later.");
+            }
+        }
+
+        Ok(())
+    }
+
     fn on_message(&mut self, msg: Message) -> Result<(), Error> {
+        self.take_login();
+
+        if msg.len() > MAX_MESSAGE_BYTES {
+            if let Some(ref netcom) = self.netcom {
+                netcom.strike(&self.name, "Message too big.");
+            }
+            else {
+                let _ = self.out.close_with_reason(CloseCode::Size, "Message too big.");
+            }
+            return Ok(());
+        }
+
         match msg {
             Message::Text(txt) => {
-                if !self.has_name {
-                    self.name = txt.clone();
-                    self.has_name = true;
-                }
-                else if !self.has_pass {
-                    self.pass = txt.clone();
-                    self.has_pass = true;
+                match self.netcom {
+                    // Admin commands, see console. The game decides who's allowed to send them.
+                    Some(ref netcom) => {
+                        if !self.queue(netcom, |netcom, name| netcom.commands.push((Some(name), txt))) {
+                            netcom.strike(&self.name, "Too many messages in one frame.");
+                        }
+                    }
+                    None => self.login(&txt),
                 }
+                Ok(())
+            }
+            Message::Binary(vec) => {
+                let netcom = match self.netcom {
+                    Some(ref netcom) => netcom.clone(),
+                    None => {
+                        // A handshake sent straight after the login waits for it to be checked
+                        let accepted = {
+                            let mut state = self.login_state.lock().unwrap();
+
+                            match *state {
+                                LoginState::Checking(None) => {
+                                    *state = LoginState::Checking(Some(vec.clone()));
+                                    false
+                                }
+                                // Checked since this message started being handled
+                                LoginState::Accepted(_) => true,
+                                _ => {
+                                    let _ = self.out.close_with_reason(CloseCode::Policy, "Log in first.");
+                                    false
+                                }
+                            }
+                        };
 
-                if self.has_name && self.has_pass && !self.verified {
-                    if let Some((pass, team)) = self.names_passes_teams.get(&self.name) {
-                        if self.pass == pass.to_owned() {
-                            self.team = *team;
-                            self.verified = true;
-                            let mut netcom = self.netcom.lock().unwrap();
-                            netcom.players.insert(self.name.to_owned(), self.to_owned());
-                            println!("Accepting player: {:?}", self.name);
-                            Ok(())
+                        if !accepted {
+                            return Ok(());
                         }
-                        else {
-                            let _ = self.out.close(CloseCode::Normal);
-                            Err(Error::new(ErrorKind::Protocol, "Passwords didn't match."))
+
+                        self.take_login();
+                        match self.netcom {
+                            Some(ref netcom) => netcom.clone(),
+                            None => return Ok(()),
                         }
                     }
-                    else {
-                        Err(Error::new(ErrorKind::Protocol, "No matching name or password."))
-                    }
-                }
-                else if self.verified {
-                    println!("{:?}", txt.clone());
-                    Ok(())
+                };
+
+                if self.protocol_version.is_none() {
+                    self.handshake(&netcom, &vec)
                 }
                 else {
+                    let role = self.role;
+
+                    if !self.queue(&netcom, |netcom, name| netcom.messages.push((name, role, vec))) {
+                        netcom.strike(&self.name, "Too many messages in one frame.");
+                    }
                     Ok(())
                 }
             }
-            Message::Binary(vec) => {
-                if self.verified {
-                    let mut netcom = self.netcom.lock().unwrap();
-                    netcom.messages.push((self.name.clone(), self.team, vec));
-                    Ok(())
-                }
-                else {
-                    Err(Error::new(ErrorKind::Protocol, "Player not verified."))
-                }
-            }
         }
     }
 
     fn on_close(&mut self, code: CloseCode, reason: &str) {
-        let mut netcom = self.netcom.lock().unwrap();
-        netcom.players.remove(&self.name);
+        self.take_login();
+
+        if let Some(ref netcom) = self.netcom {
+            let mut netcom = netcom.lock().unwrap();
+            let replaced = netcom.players.get(&self.name).map_or(true, |p| p.out.connection_id() != self.out.connection_id());
+
+            // A connection that was taken over mustn't remove the one that replaced it
+            if !replaced {
+                netcom.players.remove(&self.name);
+            }
+        }
         match code {
             CloseCode::Normal => println!("The client is done with the connection."),
             CloseCode::Away   => println!("The client is leaving the site."),
@@ -69,72 +124,397 @@ impl Handler for Player {
     }
 }
 
+impl Player {
+    // Counts a message towards the player's limit for the frame and only adds it if they're under it.
+    // Returns false once they're over.
+    fn queue<F: FnOnce(&mut Netcom, String)>(&self, netcom: &Arc<Mutex<Netcom>>, add: F) -> bool {
+        let mut netcom = netcom.lock().unwrap();
+        let queued = {
+            let queued = netcom.queued.entry(self.name.clone()).or_insert(0);
+            *queued += 1;
+            *queued
+        };
+
+        if queued <= MAX_QUEUED_MESSAGES {
+            add(&mut netcom, self.name.clone());
+        }
+
+        queued <= MAX_QUEUED_MESSAGES
+    }
+
+    // The first message has to be a JSON login, see auth::Login. Its join code picks the match.
+    // Checking it is slow on purpose, so it's done on a thread of its own rather than holding up every connection.
+    fn login(&mut self, txt: &str) {
+        let login: Login = match serde_json::from_str(txt) {
+            Ok(login) => login,
+            Err(_) => {
+                let _ = self.out.close_with_reason(CloseCode::Protocol, "Expected a login message.");
+                return;
+            }
+        };
+
+        {
+            let mut state = self.login_state.lock().unwrap();
+
+            match *state {
+                LoginState::Idle => *state = LoginState::Checking(None),
+                _ => {
+                    let _ = self.out.close_with_reason(CloseCode::Protocol, "Already logging in.");
+                    return;
+                }
+            }
+        }
+
+        let (netcom, dummy) = {
+            let lobby = self.lobby.lock().unwrap();
+            (lobby.matches.get(&login.join_code).cloned(), lobby.dummy_hash.clone())
+        };
+        // A match that doesn't exist turns people away the same as a wrong password does
+        let roster = match netcom {
+            Some(ref netcom) => netcom.lock().unwrap().roster.clone(),
+            None => Arc::new(HashMap::new()),
+        };
+        let mut player = self.clone();
+
+        thread::spawn(move || {
+            match auth::check_login(&roster, &dummy, &login) {
+                Some(role) => {
+                    player.name = login.name;
+                    player.role = role;
+                    player.netcom = netcom;
+                    println!("Accepting player: {:?}", player.name);
+
+                    let state = player.login_state.clone();
+                    let mut state = state.lock().unwrap();
+
+                    if let LoginState::Checking(Some(ref handshake)) = *state {
+                        if let Some(netcom) = player.netcom.clone() {
+                            let _ = player.handshake(&netcom, handshake);
+                        }
+                    }
+
+                    *state = LoginState::Accepted(player.clone());
+                }
+                None => {
+                    if let Some(ip) = player.ip {
+                        let mut lobby = player.lobby.lock().unwrap();
+                        lobby.failed_logins.hit(ip, Instant::now());
+                    }
+
+                    println!("Failed login for {:?}.", login.name);
+                    let _ = player.out.close_with_reason(CloseCode::Policy, "Wrong name or password.");
+                }
+            }
+        });
+    }
+
+    // Picks up a login that has been checked since the last message.
+    fn take_login(&mut self) {
+        let accepted = {
+            let mut state = self.login_state.lock().unwrap();
+
+            match *state {
+                LoginState::Accepted(_) => Some(mem::replace(&mut *state, LoginState::Idle)),
+                _ => None,
+            }
+        };
+
+        if let Some(LoginState::Accepted(player)) = accepted {
+            self.name = player.name;
+            self.role = player.role;
+            self.netcom = player.netcom;
+            self.protocol_version = player.protocol_version;
+        }
+    }
+
+    // The first binary message after logging in has to be a handshake.
+    // Players only start getting frames once they have agreed on a protocol version.
+    fn handshake(&mut self, netcom: &Arc<Mutex<Netcom>>, msg: &[u8]) -> Result<(), Error> {
+        match protocol::read_handshake(msg) {
+            Some((min, max)) => {
+                if let Some(version) = protocol::negotiate(min, max) {
+                    self.protocol_version = Some(version);
+                    let _ = self.out.send(Message::Binary(protocol::encode_handshake_accepted(version)));
+                    let mut netcom = netcom.lock().unwrap();
+
+                    // Logging in again takes over the team slot from the old connection
+                    if let Some(old) = netcom.players.insert(self.name.to_owned(), self.to_owned()) {
+                        let _ = old.out.close_with_reason(CloseCode::Policy, "Logged in from somewhere else.");
+                        println!("{:?} reconnected.", self.name);
+                    }
+
+                    // Asked for on the player's behalf so the game sends everything they missed
+                    netcom.messages.push((self.name.clone(), self.role, protocol::encode_keyframe_request()));
+                    println!("{:?} is using protocol version {}.", self.name, version);
+                }
+                else {
+                    let _ = self.out.send(Message::Binary(protocol::encode_handshake_rejected()));
+                    let _ = self.out.close_with_reason(CloseCode::Unsupported, "No shared protocol version.");
+                }
+                Ok(())
+            }
+            None => {
+                let _ = self.out.close_with_reason(CloseCode::Protocol, "Expected a handshake.");
+                Ok(())
+            }
+        }
+    }
+}
+
+// What a connection is allowed to do and see.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    // Plays for a team
+    Player(usize),
+    // Watches through one team's eyes, or sees the whole map without fog
+    Spectator(Option<usize>),
+}
+
+impl Role {
+    pub fn can_give_orders(&self) -> bool {
+        match *self {
+            Role::Player(_) => true,
+            Role::Spectator(_) => false,
+        }
+    }
+
+    // The team whose messages this connection receives, None if it sees everything.
+    pub fn view_team(&self) -> Option<usize> {
+        match *self {
+            Role::Player(team) => Some(team),
+            Role::Spectator(team) => team,
+        }
+    }
+}
+
+// Where a connection's login has got to. The login is checked on another thread, which leaves the player it
+// logged in as here for the connection to pick up.
+enum LoginState {
+    Idle,
+    // Along with a handshake that arrived before the login was checked
+    Checking(Option<Vec<u8>>),
+    Accepted(Player),
+}
+
 #[derive(Clone)]
 struct Player {
-    netcom: Arc<Mutex<Netcom>>,
-    names_passes_teams: Arc<HashMap<String, (String, usize)>>,
-    verified: bool,
-    has_name: bool,
-    has_pass: bool,
+    lobby: Arc<Mutex<Lobby>>,
+    login_state: Arc<Mutex<LoginState>>,
+    // The match the player logged in to, None until they have
+    netcom: Option<Arc<Mutex<Netcom>>>,
+    ip: Option<IpAddr>,
     name: String,
-    pass: String,
-    team: usize,
+    role: Role,
+    protocol_version: Option<u16>,
     out: Sender,
 }
 
+// Bigger messages are refused
+pub const MAX_MESSAGE_BYTES: usize = 16 * 1024;
+// Messages a player can queue up between two frames, the rest are dropped
+pub const MAX_QUEUED_MESSAGES: usize = 64;
+// Bad or excessive messages a player can send in STRIKE_WINDOW_SECS before being disconnected
+pub const MAX_STRIKES: usize = 10;
+pub const STRIKE_WINDOW_SECS: u64 = 60;
+
+// One match's connections.
 pub struct Netcom {
+    roster: Arc<HashMap<String, Credentials>>,
     players: HashMap<String, Player>,
-    messages: Vec<(String, usize, Vec<u8>)>,
+    messages: Vec<(String, Role, Vec<u8>)>,
+    // Text commands and who sent them, None for the server's own console
+    commands: Vec<(Option<String>, String)>,
+    queued: HashMap<String, usize>,
+    strikes: RateLimiter<String>,
+}
+
+// Everything behind one listening socket: the matches being played, by join code, and the limits on addresses
+// that apply no matter which match they're trying to get into.
+pub struct Lobby {
+    matches: HashMap<String, Arc<Mutex<Netcom>>>,
+    connections: RateLimiter,
+    failed_logins: RateLimiter,
+    // What secrets are compared against when the name isn't in the roster
+    dummy_hash: Arc<SecretHash>,
 }
 
-pub fn get_messages(net: &Arc<Mutex<Netcom>>) -> Vec<(String, usize, Vec<u8>)> {
+pub fn get_messages(net: &Arc<Mutex<Netcom>>) -> Vec<(String, Role, Vec<u8>)> {
     let mut net = net.lock().unwrap();
     let vec = net.messages.clone();
     net.messages.clear();
+    net.queued.clear();
     vec
 }
 
-pub fn send_message_to_player(net: Arc<Mutex<Netcom>>, msg: Vec<u8>, name: String) {
-    let players = {
-        let net = net.lock().unwrap();
-        net.players.clone()
-    };
+// Everyone who has finished handshaking.
+pub fn connected(net: &Arc<Mutex<Netcom>>) -> Vec<(String, Role)> {
+    let net = net.lock().unwrap();
+    net.players.values().map(|p| (p.name.clone(), p.role)).collect()
+}
 
-    for (player_name, player) in players {
-        if player_name == name {
-            let bin_msg = Message::Binary(msg.clone());
-            let _ = player.out.send(bin_msg);
-        }
+pub fn get_commands(net: &Arc<Mutex<Netcom>>) -> Vec<(Option<String>, String)> {
+    let mut net = net.lock().unwrap();
+    net.commands.drain(..).collect()
+}
+
+pub fn queue_command(net: &Arc<Mutex<Netcom>>, from: Option<String>, command: String) {
+    net.lock().unwrap().commands.push((from, command));
+}
+
+// Answers a text command with a text frame.
+pub fn send_text(net: &Arc<Mutex<Netcom>>, name: &str, text: &str) {
+    let net = net.lock().unwrap();
+
+    if let Some(player) = net.players.get(name) {
+        let _ = player.out.send(Message::Text(text.to_string()));
     }
 }
 
-pub fn send_message_to_team(net: Arc<Mutex<Netcom>>, msg: Vec<u8>, team: usize) {
-    let players = {
-        let net = net.lock().unwrap();
-        net.players.clone()
-    };
+// Disconnects someone. Returns whether they were connected.
+pub fn kick(net: &Arc<Mutex<Netcom>>, name: &str) -> bool {
+    let net = net.lock().unwrap();
 
-    for (_, player) in players {
-        if player.team == team {
-            let bin_msg = Message::Binary(msg.clone());
-            let _ = player.out.send(bin_msg);
+    match net.players.get(name) {
+        Some(player) => {
+            let _ = player.out.close_with_reason(CloseCode::Policy, "Kicked by an admin.");
+            true
         }
+        None => false,
     }
 }
 
-pub fn new(names_passes_teams: &[(String, String, usize)], port: &str, address: &str) -> Arc<Mutex<Netcom>> {
-    let netcom = Arc::new(Mutex::new(Netcom {
-        players: HashMap::new(),
-        messages: Vec::new(),
-    }));
+// Sends everyone away, e.g. once the match is over.
+pub fn close_all(net: &Arc<Mutex<Netcom>>, reason: &str) {
+    let net = net.lock().unwrap();
 
-    let mut npt_map = HashMap::new();
+    for player in net.players.values() {
+        let _ = player.out.close_with_reason(CloseCode::Normal, reason.to_string());
+    }
+}
 
-    for (name, pass, team) in names_passes_teams {
-        npt_map.insert(name.to_owned(), (pass.to_owned(), team.to_owned()));
+// Anywhere the game can push encoded frames to. The live server uses Netcom,
+// tests, bots and replay tools can use something that never touches a socket.
+pub trait MessageSink {
+    fn send_message_to_player(&self, msg: Vec<u8>, name: &str);
+    // Also reaches spectators watching that team
+    fn send_message_to_team(&self, msg: Vec<u8>, team: usize);
+    // Only the team's players, for what's encoded against their own acknowledgements
+    fn send_message_to_players(&self, msg: Vec<u8>, team: usize);
+    // Spectators watching team, or who see the whole map if it's None
+    fn send_message_to_spectators(&self, msg: Vec<u8>, team: Option<usize>);
+    fn has_spectators(&self, team: Option<usize>) -> bool;
+    // Counts a bad message against a player
+    fn strike(&self, name: &str, reason: &str);
+}
+
+impl MessageSink for Mutex<Netcom> {
+    fn send_message_to_player(&self, msg: Vec<u8>, name: &str) {
+        let players = {
+            let net = self.lock().unwrap();
+            net.players.clone()
+        };
+
+        for (player_name, player) in players {
+            if player_name == name {
+                let bin_msg = Message::Binary(msg.clone());
+                let _ = player.out.send(bin_msg);
+            }
+        }
+    }
+
+    fn send_message_to_team(&self, msg: Vec<u8>, team: usize) {
+        let players = {
+            let net = self.lock().unwrap();
+            net.players.clone()
+        };
+
+        for (_, player) in players {
+            if player.role.view_team() == Some(team) {
+                let bin_msg = Message::Binary(msg.clone());
+                let _ = player.out.send(bin_msg);
+            }
+        }
     }
 
-    let return_netcom = netcom.clone();
+    fn send_message_to_players(&self, msg: Vec<u8>, team: usize) {
+        let players = {
+            let net = self.lock().unwrap();
+            net.players.clone()
+        };
+
+        for (_, player) in players {
+            if player.role == Role::Player(team) {
+                let bin_msg = Message::Binary(msg.clone());
+                let _ = player.out.send(bin_msg);
+            }
+        }
+    }
+
+    fn send_message_to_spectators(&self, msg: Vec<u8>, team: Option<usize>) {
+        let players = {
+            let net = self.lock().unwrap();
+            net.players.clone()
+        };
+
+        for (_, player) in players {
+            if player.role == Role::Spectator(team) {
+                let bin_msg = Message::Binary(msg.clone());
+                let _ = player.out.send(bin_msg);
+            }
+        }
+    }
+
+    fn has_spectators(&self, team: Option<usize>) -> bool {
+        let net = self.lock().unwrap();
+        net.players.values().any(|p| p.role == Role::Spectator(team))
+    }
+
+    fn strike(&self, name: &str, reason: &str) {
+        let mut net = self.lock().unwrap();
+        let now = Instant::now();
+        net.strikes.hit(name.to_string(), now);
+        let strikes = net.strikes.count(&name.to_string(), now);
+
+        println!("Strike {} of {} for {:?}: {}", strikes, MAX_STRIKES, name, reason);
+
+        if strikes >= MAX_STRIKES {
+            net.strikes.forget(&name.to_string());
+
+            if let Some(player) = net.players.get(name) {
+                let _ = player.out.close_with_reason(CloseCode::Policy, "Too many bad messages.");
+            }
+        }
+    }
+}
+
+// Drops every message. Used when the game runs headless.
+pub struct NullSink;
+
+impl MessageSink for NullSink {
+    fn send_message_to_player(&self, _: Vec<u8>, _: &str) {}
+    fn send_message_to_team(&self, _: Vec<u8>, _: usize) {}
+    fn send_message_to_players(&self, _: Vec<u8>, _: usize) {}
+    fn send_message_to_spectators(&self, _: Vec<u8>, _: Option<usize>) {}
+    fn has_spectators(&self, _: Option<usize>) -> bool { false }
+    fn strike(&self, _: &str, _: &str) {}
+}
+
+// A server with a single match, which players join without a join code.
+pub fn new(roster: Vec<(String, Credentials)>, port: &str, address: &str) -> Arc<Mutex<Netcom>> {
+    let lobby = listen_on(port, address);
+    open_match(&lobby, "", roster)
+}
+
+// Starts listening for players. Matches are added with open_match.
+pub fn listen_on(port: &str, address: &str) -> Arc<Mutex<Lobby>> {
+    let lobby = Arc::new(Mutex::new(Lobby {
+        matches: HashMap::new(),
+        connections: RateLimiter::new(auth::MAX_CONNECTIONS, Duration::from_secs(auth::CONNECTION_WINDOW_SECS)),
+        failed_logins: RateLimiter::new(auth::MAX_FAILED_LOGINS, Duration::from_secs(auth::FAILED_LOGIN_WINDOW_SECS)),
+        dummy_hash: Arc::new(SecretHash::dummy()),
+    }));
+
+    let return_lobby = lobby.clone();
 
     let mut listen_on = String::new();
     listen_on.push_str(address);
@@ -142,21 +522,43 @@ pub fn new(names_passes_teams: &[(String, String, usize)], port: &str, address:
     listen_on.push_str(port);
 
     thread::spawn(move || {
-        let npt_map = Arc::new(npt_map);
         listen(listen_on, |out| {
             Player {
-                netcom: netcom.clone(),
-                names_passes_teams: npt_map.clone(),
-                verified: false,
-                has_name: false,
-                has_pass: false,
+                lobby: lobby.clone(),
+                login_state: Arc::new(Mutex::new(LoginState::Idle)),
+                netcom: None,
+                ip: None,
                 name: String::new(),
-                pass: String::new(),
-                team: 0,
+                role: Role::Spectator(None),
+                protocol_version: None,
                 out: out,
             }
         }).unwrap()
     });
 
-    return return_netcom;
+    return return_lobby;
+}
+
+// Lets players log in to a match with its join code.
+pub fn open_match(lobby: &Arc<Mutex<Lobby>>, join_code: &str, roster: Vec<(String, Credentials)>) -> Arc<Mutex<Netcom>> {
+    let netcom = Arc::new(Mutex::new(Netcom {
+        roster: Arc::new(roster.into_iter().collect()),
+        players: HashMap::new(),
+        messages: Vec::new(),
+        commands: Vec::new(),
+        queued: HashMap::new(),
+        strikes: RateLimiter::new(MAX_STRIKES, Duration::from_secs(STRIKE_WINDOW_SECS)),
+    }));
+
+    lobby.lock().unwrap().matches.insert(join_code.to_string(), netcom.clone());
+    netcom
+}
+
+pub fn find_match(lobby: &Arc<Mutex<Lobby>>, join_code: &str) -> Option<Arc<Mutex<Netcom>>> {
+    lobby.lock().unwrap().matches.get(join_code).cloned()
+}
+
+// Nobody can log in to the match any more. Whoever is still connected stays until they're closed.
+pub fn close_match(lobby: &Arc<Mutex<Lobby>>, join_code: &str) {
+    lobby.lock().unwrap().matches.remove(join_code);
 }
\ No newline at end of file
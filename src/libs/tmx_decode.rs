@@ -47,23 +47,32 @@ impl Location {
     }
 }
 
+// The Tiled JSON of a map file, as MapData::from_json takes it.
+pub fn read_map(map_name: &str) -> String {
+    let mut file = File::open(map_name).unwrap();
+    let mut contents = String::new();
+
+    file.read_to_string(&mut contents).unwrap();
+    contents
+}
+
 impl MapData {
     pub fn collisions(&self) -> &Vec<usize> {
         &self.collisions
     }
 
     pub fn new(map_name: &str) -> MapData {
-        let tilesheet_w = 2048;
-        let mut file = File::open(map_name).unwrap();
-        let mut contents = String::new();
-
-        file.read_to_string(&mut contents).unwrap();
+        MapData::from_json(&read_map(map_name))
+    }
 
+    // Builds a map out of the contents of a Tiled JSON file.
+    pub fn from_json(contents: &str) -> MapData {
+        let tilesheet_w = 2048;
         let mut tiles = Vec::new();
         let mut collisions = Vec::new();
         let mut start_locations = Vec::new();
         let mut prime_nodes = Vec::new();
-        let temp: TempMapData = serde_json::from_str(&contents).unwrap();
+        let temp: TempMapData = serde_json::from_str(contents).unwrap();
         let w = temp.width;
         let h = temp.height;
         let tw = temp.tilewidth;
@@ -139,6 +148,15 @@ impl MapData {
         (self.width, self.height)
     }
 
+    // The collision type of a tile, with y counted up from the bottom like the grids do. Off the map is nothing.
+    pub fn collision_at(&self, (x, y): (isize, isize)) -> usize {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return 0;
+        }
+
+        self.collisions[(self.height - y as usize - 1) * self.width + x as usize]
+    }
+
     pub fn encode(&self) -> Vec<u8> {
         let mut vec = Cursor::new(Vec::new());
 
@@ -13,6 +13,12 @@ extern crate serde_derive;
 extern crate enum_primitive;
 extern crate num;
 extern crate rand;
+extern crate rand_pcg;
+extern crate sha2;
+extern crate subtle;
+extern crate hex;
+extern crate hmac;
+extern crate pbkdf2;
 
 mod data;
 mod pathing;
@@ -20,24 +26,37 @@ mod libs;
 mod behavior;
 mod useful_bits;
 mod setup_game;
-
-use time::Instant;
-use byteorder::{WriteBytesExt, BigEndian};
+mod config;
+mod simulation;
+mod replay;
+mod save;
+mod console;
+#[cfg(test)]
+mod test_messages;
+
+use rand::Rng;
+use std::env;
 use std::io;
-use std::time::Duration;
+use std::process;
+use std::time::{Duration, Instant};
+use std::thread;
 use std::thread::sleep;
-use std::io::Cursor;
+use std::sync::{Arc, Mutex};
 use libs::netcom;
-use libs::tmx_decode::MapData;
+use libs::netcom::Netcom;
+use libs::auth;
+use libs::auth::{Credentials, SecretHash};
+use libs::tmx_decode::read_map;
 
-use data::game::Game;
-use data::logger;
-use data::kdt_point as kdtp;
+use config::MatchConfig;
+use simulation::Simulation;
 use data::aliases::*;
-use setup_game::setup_game;
-
-use behavior::missile::core as missile;
-use behavior::unit::core as unit;
+use data::game::{Game, incorporate_messages};
+use data::lifecycle;
+use data::protocol::MatchPhase;
+use libs::netcom::Role;
+use replay::{ReplayHeader, Recorder};
+use console::Console;
 
 fn main() {
     //libs::fine_grid::bench_fine_grid();
@@ -51,348 +70,319 @@ fn main() {
 }
 
 fn main_main() {
-    let mut address = String::new();
-    let mut port = String::new();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    // The password comes from stdin so it stays out of the process list and shell history
+    if args.iter().any(|a| a == "--hash-password") {
+        eprintln!("Password:");
+        let mut password = String::new();
+        match io::stdin().read_line(&mut password) {
+            Ok(_) => println!("{}", SecretHash::of(password.trim_end_matches(|c| c == '\n' || c == '\r')).to_string()),
+            Err(e) => println!("Couldn't read a password: {}", e),
+        }
+        return;
+    }
+
+    // --replay, --serve, --load and --matches pick what to run rather than how the match is set up
+    let serve = args.iter().any(|a| a == "--serve");
+    let replay_path = path_flag(&args, "--replay");
+    let load_path = path_flag(&args, "--load");
+    let matches_path = path_flag(&args, "--matches");
+
+    let args: Vec<String> = args.iter().enumerate().filter(|&(ix, a)| {
+        let takes_value = |flag: &str| flag == "--replay" || flag == "--load" || flag == "--matches";
+        a != "--serve" && !takes_value(a) && (ix == 0 || !takes_value(&args[ix - 1]))
+    }).map(|(_, a)| a.clone()).collect();
+
+    let config = match MatchConfig::from_args(&args) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("{}", config::USAGE);
+            return;
+        }
+        Err(e) => {
+            println!("{}", e);
+            println!("{}", config::USAGE);
+            process::exit(1);
+        }
+    };
+
+    if let Some(ref path) = replay_path {
+        if !serve {
+            if let Err(e) = replay::run_headless(path) {
+                println!("{}", e);
+                process::exit(1);
+            }
+            return;
+        }
+    }
 
-    println!("Enter your IP address");
-    let _ = io::stdin().read_line(&mut address).unwrap();
+    if let Some(ref path) = matches_path {
+        if replay_path.is_some() || load_path.is_some() {
+            println!("--matches can't be used with --replay or --load.");
+            process::exit(1);
+        }
+
+        if let Err(e) = host_matches(&config, path) {
+            println!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
 
-    println!("Enter port number");
-    let _ = io::stdin().read_line(&mut port).unwrap();
+    if config.players.is_empty() && replay_path.is_none() {
+        println!("No players are configured. Nobody will be able to join.");
+    }
 
-    address = address.trim().to_string();
-    port = port.trim().to_string();
+    let roster = roster_with_tokens(&config, "");
+
+    if let Some(ref path) = replay_path {
+        if let Err(e) = replay::serve(path, roster, &config) {
+            println!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if load_path.is_some() && config.record.is_some() {
+        println!("A replay has to start at the beginning of a match, so --record can't be used with --load.");
+        process::exit(1);
+    }
 
     println!("Networking.");
-    let players = [
-        ("p1".to_string(), "p1".to_string(), 0),
-        ("p2".to_string(), "p2".to_string(), 0),
-        ("p3".to_string(), "p3".to_string(), 1),
-        ("p4".to_string(), "p4".to_string(), 1),
-    ];
+    let netc = netcom::new(roster, &config.port.to_string(), &config.address);
 
-    let netc = netcom::new(&players, &port, &address);
+    let console_netc = netc.clone();
+    console::read_stdin(move |line| netcom::queue_command(&console_netc, None, line));
 
-    let (units,unit_id_map,missiles,missile_id_map,encoded_unit_info,encoded_misl_info) = setup_game::list();
+    if let Err(e) = play(&config, &netc, load_path.as_ref().map(|p| p.as_str())) {
+        println!("{}", e);
+        process::exit(1);
+    }
+}
 
-    let map_data = MapData::new("./maps/Map2.json");
+// Everyone in the match's roster, with a join token printed for each of them if the match uses them.
+fn roster_with_tokens(config: &MatchConfig, join_code: &str) -> Vec<(String, Credentials)> {
+    let mut roster = config.roster();
 
-    let game = &mut Game::new(4096, 8, map_data, units, unit_id_map, missiles, missile_id_map, encoded_unit_info, encoded_misl_info, netc);
-    setup_game(game);
+    if config.join_tokens {
+        for &mut (ref name, ref mut creds) in roster.iter_mut() {
+            let token = auth::new_token(&mut rand::thread_rng());
 
-    println!("Game started.");
-    let mut loop_count: u32 = 0;
+            if join_code.is_empty() {
+                println!("Join token for {}: {}", name, token);
+            }
+            else {
+                println!("Join token for {} in {}: {}", name, join_code, token);
+            }
+            creds.token = Some(SecretHash::of(&token));
+        }
+    }
 
-    loop {
-        let start_time = Instant::now();
-        let player_msgs = netcom::get_messages(&game.netcom);
+    roster
+}
 
-        data::game::incorporate_messages(game, player_msgs);
+// Plays every match in a matches file at once, each on its own thread, behind the address and port of config.
+// Players pick their match with its join code when they log in.
+fn host_matches(config: &MatchConfig, path: &str) -> Result<(), String> {
+    let matches = config::read_matches(path)?;
 
-        // RESET ECONOMY TRACKING
-        for &team in &game.teams.iter() {
-            game.teams.prime_output[team] = 0.0;
-            game.teams.energy_output[team] = 0.0;
-            game.teams.prime_drain[team] = 0.0;
-            game.teams.energy_drain[team] = 0.0;
+    println!("Networking.");
+    let lobby = netcom::listen_on(&config.port.to_string(), &config.address);
+    let mut threads = Vec::new();
+
+    // Console commands start with the join code of the match they're for
+    let console_lobby = lobby.clone();
+    console::read_stdin(move |line| {
+        let mut words = line.splitn(2, char::is_whitespace);
+        let join_code = words.next().unwrap_or("");
+
+        match (netcom::find_match(&console_lobby, join_code), words.next()) {
+            (Some(netc), Some(command)) => netcom::queue_command(&netc, None, command.trim().to_string()),
+            (Some(_), None) => println!("What should {} do? Try {} help.", join_code, join_code),
+            (None, _) => println!("There's no match called {}. Commands start with the match's code.", join_code),
         }
+    });
+
+    for (join_code, match_config) in matches {
+        let netc = netcom::open_match(&lobby, &join_code, roster_with_tokens(&match_config, &join_code));
+        let lobby = lobby.clone();
+
+        // Games can't move between threads, so each one is built on the thread that plays it
+        threads.push(thread::spawn(move || {
+            println!("Hosting {}.", join_code);
+
+            if let Err(e) = play(&match_config, &netc, None) {
+                println!("{}: {}", join_code, e);
+            }
+
+            netcom::close_match(&lobby, &join_code);
+        }));
+    }
+
+    for thread in threads {
+        let _ = thread.join();
+    }
+
+    Ok(())
+}
 
-        // STEP MISSILES
-        for &id in &game.missiles.iter() {
-            missile::step_missile(game, id);
+// Runs a match until it's over. load_path picks up a saved match instead of starting a new one.
+fn play(config: &MatchConfig, netc: &Arc<Mutex<Netcom>>, load_path: Option<&str>) -> Result<(), String> {
+    // A loaded match keeps the map, definitions and settings it was started with
+    let (header, mut game) = match load_path {
+        Some(path) => {
+            let (header, game) = save::load(path, netc.clone())?;
+            println!("Loaded {} at frame {}.", path, game.frame_number);
+            (header, game)
         }
+        None => {
+            let seed: u64 = config.seed.unwrap_or_else(|| rand::thread_rng().gen());
+            println!("Match seed: {}", seed);
+
+            let header = ReplayHeader {
+                version: replay::REPLAY_VERSION,
+                config: config.without_secrets(),
+                seed: seed,
+                map: read_map(&config.map),
+                units: setup_game::read_definitions(&config.unit_dir),
+                missiles: setup_game::read_definitions(&config.missile_dir),
+            };
+
+            let game = setup_game::new_game(&config, seed, &header.map, &header.units, &header.missiles, netc.clone());
+            (header, game)
+        }
+    };
+    let game = &mut game;
+
+    if let Some(ref lockstep) = game.lockstep {
+        println!("Lockstep with a delay of {} frames.", lockstep.delay());
+    }
 
-        // STEP UNITS
-        let unit_iterator = game.units.iter();
+    let save_every = u32::max(1, (config.save_seconds * game.fps()).round() as u32);
+    let countdown_frames = (config.countdown_seconds * game.fps()).round() as u32;
 
-        for &id in &unit_iterator {
-			unit::event_handler(game, UnitEvent::UnitSteps(id));
+    // Without a roster there's nobody to wait for. A loaded match that was running waits for everyone again.
+    if !config.players.is_empty() && !game.lifecycle.is_finished() {
+        game.lifecycle.wait_for_players();
+    }
+
+    let mut recorder = match config.record {
+        Some(ref path) => {
+            let recorder = Recorder::create(path, &header)?;
+            println!("Recording to {}.", path);
+            Some(recorder)
         }
+        None => None,
+    };
 
-        game.unit_kdt = kdtp::populate_with_kdtunits(&game);
-        game.missile_kdt = kdtp::populate_with_kdtmissiles(&game.missiles);
+    println!("Game started.");
+    let mut loop_count: u32 = 0;
+    let mut console = Console::new();
 
-        let frame_time = 1.0 / game.fps();
-        for &team in &game.teams.iter() {
-            // CLEAR VISIBLE UNITS
-            for &id in &unit_iterator {
-                game.teams.visible[team][id] = game.teams.visible[team][id].step(frame_time);
-            }
+    loop {
+        let start_time = Instant::now();
+        let (controls, player_msgs): (Vec<_>, Vec<_>) = netcom::get_messages(netc).into_iter().partition(|m| lifecycle::is_control(&m.2));
 
-            // CLEAR VISIBLE MISSILES
-            for &id in &game.missiles.iter() {
-                game.teams.visible_missiles[team][id] = game.teams.visible_missiles[team][id].step(frame_time);
+        // Pausing and the speed only change when frames run, so they're kept out of recordings
+        for (name, role, data) in controls {
+            if let Err(e) = lifecycle::incorporate_control(game, role, config.is_admin(&name), data) {
+                game.sink.strike(&name, &e.to_string());
             }
+        }
 
-            // SET VISIBLE UNITS AND MISSILES
-            for &id in &unit_iterator {
-                if game.units.team(id) == team && game.units.is_active(id) {
-					let ignores_stealth = game.units.ignores_stealth(id);
-                    let ignores_cloak = game.units.ignores_cloak(id);
-					let radar_range = game.units.radar_range(id);
-					let sight_range = game.units.sight_range(id);
-                    let sight_dur = game.units.sight_duration(id);
-                    let radar_dur = game.units.radar_duration(id);
-
-                    let radar_blips = kdtp::all_enemies_in_range(game, id, radar_range);
-
-					for kdtp in radar_blips {
-						if let Some(blip_id) = game.units.target_id(kdtp.target) {
-							let stealth = game.units.is_stealthed(blip_id) > 0;
-
-							if ignores_stealth || !stealth {
-								game.teams.visible[team][blip_id] = game.teams.visible[team][blip_id].spot_radar(radar_dur);
-							}
-                        }
-					}
-
-                    let vis_enemies = kdtp::all_enemies_in_range(game, id, sight_range);
-
-                    for kdtp in vis_enemies {
-                        if let Some(vis_id) = game.units.target_id(kdtp.target) {
-                            let cloaked = game.units.is_cloaked(vis_id) > 0;
-
-                            if ignores_cloak || !cloaked {
-                                game.teams.visible[team][vis_id] = game.teams.visible[team][vis_id].spot_vision(sight_dur);
-                            }
-                        }
-                    }
+        console.take_commands(game, netc, |name| config.is_admin(name));
 
-                    let vis_missiles = unit::missiles_in_vision(game, id);
+        if game.lifecycle.is_running() {
+            let commands = console.apply_pending(game, netc);
 
-                    for kdtp in vis_missiles {
-                        game.teams.visible_missiles[team][kdtp.id] = game.teams.visible_missiles[team][kdtp.id].spot_vision(frame_time);
-                    }
-                }
+            if let Some(ref mut recorder) = recorder {
+                recorder.record(game.frame_number, &player_msgs, &commands);
             }
 
-            // ADJUST TEAMS RESOURCES
-            let build_power_distribution = game.teams.get_build_power_applications(team);
-            let total_energy = game.teams.energy[team];
-            let total_prime = game.teams.prime[team];
-            let mut total_prime_drain = 0.0;
-            let mut total_energy_drain = 0.0;
-            let mut prime = game.teams.prime[team];
-            let mut energy = game.teams.energy[team];
-
-            for &(id, build_power) in &build_power_distribution {
-                let build_cost = game.units.build_cost(id);
-                let prime_cost = game.units.prime_cost(id);
-                let energy_cost = game.units.energy_cost(id);
-                let build_ratio = build_power / build_cost;
-
-                total_prime_drain += prime_cost * build_ratio;
-                total_energy_drain += energy_cost * build_ratio;
-            }
+            Simulation::step(game, player_msgs);
 
-			for &id in &unit_iterator {
-                if game.units.team(id) == team && game.units.is_active(id) {
-                    if let Some(train_order) = game.units.train_queue(id).front() {
-                        let proto = game.units.proto(train_order.unit_type);
-                        let build_power = game.units.train_rate(id);
-                        let build_cost = proto.build_cost();
-                        let prime_cost = proto.prime_cost();
-                        let energy_cost = proto.energy_cost();
-                        let build_ratio = build_power / build_cost;
-
-                        total_prime_drain += prime_cost * build_ratio;
-                        total_energy_drain += energy_cost * build_ratio;
+            if let Some(ref path) = config.save {
+                if game.frame_number % save_every == 0 {
+                    if let Err(e) = save::save(path, &header, game) {
+                        println!("{}", e);
                     }
-                }
-			}
-
-            let energy_drain_ratio = f64::min(1.0, total_energy / total_energy_drain);
-            let prime_drain_ratio = f64::min(1.0, total_prime / total_prime_drain);
-            let drain_ratio = f64::min(energy_drain_ratio, prime_drain_ratio);
-
-            for &(id, build_power) in &build_power_distribution {
-                let prime_cost = game.units.prime_cost(id);
-                let energy_cost = game.units.energy_cost(id);
-                let build_fraction = build_power * drain_ratio;
-                let progress = game.units.progress(id);
-                let build_cost = game.units.build_cost(id);
-                let new_progress = progress + build_fraction;
-                let health = game.units.health(id);
-                let max_health = game.units.max_health(id);
-                let new_health = health + max_health * (build_fraction / build_cost);
-
-                if new_health > max_health {
-                    let excess = (new_health - max_health) / max_health;
-                    prime += excess * prime_cost;
-                    energy += excess * energy_cost;
-                    game.units.set_health(id, max_health);
-                    game.units.set_progress(id, build_cost);
-                }
-                else {
-                    game.units.set_health(id, new_health);
-                    game.units.set_progress(id, new_progress);
                 }
             }
+        }
+        else {
+            // Only requests are answered. Orders are dropped until the match starts.
+            incorporate_messages(game, player_msgs);
 
-			for &id in &unit_iterator {
-                if game.units.team(id) == team && game.units.is_active(id) {
-    				let train_queue_front = game.units.train_queue(id).front().cloned();
-    				if let Some(train_order) = train_queue_front {
-    					let proto = game.units.proto(train_order.unit_type);
-    					let build_power = game.units.train_rate(id);
-    					let build_cost = proto.build_cost();
-    					let prime_cost = proto.prime_cost();
-    					let energy_cost = proto.energy_cost();
-    					let build_fraction = build_power * drain_ratio;
-    					let progress = game.units.train_progress(id);
-    					let new_progress = progress + build_fraction;
-
-    					if new_progress > build_cost {
-    						let excess = (new_progress - build_cost) / build_cost;
-    						prime += excess * prime_cost;
-    						energy += excess * energy_cost;
-                            behavior::unit::building::train_unit(game, id, train_order);
-    					}
-    					else {
-    						game.units.set_train_progress(id, new_progress);
-    					}
-    				}
-                }
-			}
+            let here = netcom::connected(netc);
+            let everyone_here = config.players.iter().all(|p| here.iter().any(|h| h.0 == p.name && h.1 == Role::Player(p.team)));
+            let was_paused = game.lifecycle.phase() == MatchPhase::Paused;
+            lifecycle::tick(game, everyone_here, countdown_frames);
 
-            prime -= total_prime_drain * drain_ratio;
-            energy -= total_energy_drain * drain_ratio;
-            let max_prime = game.teams.max_prime[team];
-            let max_energy = game.teams.max_energy[team];
+            if game.lifecycle.is_running() && !was_paused {
+                println!("Match started.");
+            }
+        }
 
-            game.teams.prime_drain[team] = total_prime_drain;
-            game.teams.energy_drain[team] = total_energy_drain;
-            game.teams.prime[team] = f64::min(max_prime, prime);
-            game.teams.energy[team] = f64::min(max_energy, energy);
+        if game.lifecycle.is_finished() {
+            finish(config, game);
+            // Give the last frames a moment to go out
+            sleep(Duration::from_millis(500));
+            netcom::close_all(netc, "The match is over.");
+            return Ok(());
         }
-        game.frame_number = loop_count;
-        encode_and_send_data_to_teams(game);
 
         // LOOP TIMING STUFF
         loop_count += 1;
-        let end_time = Instant::now();
-        let time_spent = start_time.to(end_time).num_milliseconds();
+        let time_spent = start_time.elapsed();
 
-        if (1000 / FPS as i64) - time_spent > 0 {
-            sleep(Duration::from_millis(
-                ((1000 / FPS as i64) - time_spent) as u64,
-            ));
+        // Waiting and pauses go by in real time no matter the speed
+        let speed = if game.lifecycle.is_running() { game.lifecycle.speed() } else { 1.0 };
+        let frame_time = Duration::from_millis((1000.0 / (game.fps() * speed)) as u64);
+
+        if frame_time > time_spent {
+            sleep(frame_time - time_spent);
         } else {
             println!(
                 "Logic is laggy. Loop# {}. Time (ms): {:?}",
                 loop_count,
-                time_spent
+                time_spent.as_millis()
             );
         }
     }
 }
 
-fn encode_and_send_data_to_teams(game: &mut Game) {
-    let team_iter = game.teams.iter();
-    let frame_number = game.frame_number;
-
-    for &team in &team_iter {
-        let mut logg_msg = Cursor::new(Vec::new());
-        let _ = logg_msg.write_u32::<BigEndian>(frame_number);
-        logger::encode_missile_booms(game, team, &mut logg_msg);
-        logger::encode_unit_deaths(game, team, &mut logg_msg);
-        logger::encode_order_completed(game, team, &mut logg_msg);
-        logger::encode_melee_smacks(game, team, &mut logg_msg);
-        logger::encode_construction(game, team, &mut logg_msg);
-
-        let team_usize = unsafe { team.usize_unwrap() };
-        netcom::send_message_to_team(game.netcom.clone(), logg_msg.into_inner(), team_usize);
+fn finish(config: &MatchConfig, game: &Game) {
+    for &team in game.lifecycle.winners() {
+        println!("Team {} wins.", unsafe { team.usize_unwrap() });
     }
 
-    for &team in &team_iter {
-        for ref boom in &game.logger.missile_booms {
-            // NOTE! Sets exploded missiles visibility to false so they aren't encoded twice
-            game.teams.visible_missiles[team][boom.id] = Visibility::new();
-        }
-        for &death in &game.logger.unit_deaths {
-            // NOTE! Sets dead units visibility to false so they aren't encoded twice
-            game.teams.visible[team][death.id] = Visibility::new();
-        }
+    if game.lifecycle.winners().is_empty() {
+        println!("Nobody wins.");
     }
 
-    for ref boom in &game.logger.missile_booms {
-        game.missiles.kill_missile(boom.id);
-    }
+    if let Some(ref path) = config.results {
+        let players: Vec<(String, usize)> = config.players.iter().map(|p| (p.name.clone(), p.team)).collect();
+        let results = lifecycle::results(game, &players);
+        let written = serde_json::to_string_pretty(&results)
+            .map_err(|e| e.to_string())
+            .and_then(|json| std::fs::write(path, json).map_err(|e| e.to_string()));
 
-    for &death in &game.logger.unit_deaths {
-        let team = game.units.team(death.id);
-
-        if game.units.is_structure(death.id) {
-            match game.units.width_and_height(death.id) {
-                Some((w, h)) => {
-                    let (x, y) = game.units.xy(death.id);
-                    let hw = w as f64 / 2.0;
-                    let hh = h as f64 / 2.0;
-                    let bx = (x - hw + 0.0001) as isize;
-                    let by = (y - hh + 0.0001) as isize;
-
-                    for xo in bx..bx + w {
-                        for yo in by..by + h {
-                            game.bytegrid.set_point(true, (xo, yo));
-                            game.teams.jps_grid[team].open_point((xo, yo));
-                        }
-                    }
-                }
-                None => {
-                    panic!("encode_and_send_data_to_teams: Building without width and height.");
-                }
-            }
+        if let Err(e) = written {
+            println!("Couldn't write the results to {}: {}", path, e);
         }
     }
+}
 
-    game.logger.clear();
-
-    for &team in &team_iter {
-        let mut unit_msg = Cursor::new(Vec::new());
-        let _ = unit_msg.write_u32::<BigEndian>(frame_number as u32);
-
-        // CONVERT UNITS INTO DATA PACKETS
-        for &id in &game.units.iter() {
-            let unit_team = game.units.team(id);
-
-            if unit_team == team {
-                unit::encode(game, id, &mut unit_msg);
-            }
-            else if game.teams.visible[team][id].is_visible() {
-                unit::encode(game, id, &mut unit_msg);
-            }
-            else if game.teams.visible[team][id].is_blip() {
-
-            }
-        }
-
-        let mut misl_msg = Cursor::new(Vec::new());
-        let _ = misl_msg.write_u32::<BigEndian>(frame_number as u32);
+// The value after flag, if flag was given. Exits if it was given without one.
+fn path_flag(args: &[String], flag: &str) -> Option<String> {
+    let ix = args.iter().position(|a| a == flag)?;
 
-        // CONVERT MISSILES INTO DATA PACKETS
-        for &id in &game.missiles.iter() {
-            if game.teams.visible_missiles[team][id].is_visible() {
-                missile::encode(game, id, &mut misl_msg);
-            }
+    match args.get(ix + 1) {
+        Some(path) => Some(path.clone()),
+        None => {
+            println!("{} needs a value.", flag);
+            process::exit(1);
         }
-
-        let team_usize = unsafe { team.usize_unwrap() };
-        let mut team_msg = Cursor::new(Vec::new());
-        let _ = team_msg.write_u32::<BigEndian>(frame_number);
-        let _ = team_msg.write_u8(ClientMessage::TeamInfo as u8);
-        let _ = team_msg.write_u8(team_usize as u8);
-        let _ = team_msg.write_u32::<BigEndian>(game.teams.max_prime[team] as u32);
-        let _ = team_msg.write_u32::<BigEndian>(game.teams.prime[team] as u32);
-        let _ = team_msg.write_f64::<BigEndian>(game.teams.prime_output[team] * game.fps());
-        let _ = team_msg.write_f64::<BigEndian>(game.teams.prime_drain[team] * game.fps());
-
-        let _ = team_msg.write_u32::<BigEndian>(game.teams.max_energy[team] as u32);
-        let _ = team_msg.write_u32::<BigEndian>(game.teams.energy[team] as u32);
-        let _ = team_msg.write_f64::<BigEndian>(game.teams.energy_output[team] * game.fps());
-        let _ = team_msg.write_f64::<BigEndian>(game.teams.energy_drain[team] * game.fps());
-
-        netcom::send_message_to_team(game.netcom.clone(), team_msg.into_inner(), team_usize);
-        netcom::send_message_to_team(game.netcom.clone(), misl_msg.into_inner(), team_usize);
-        netcom::send_message_to_team(game.netcom.clone(), unit_msg.into_inner(), team_usize);
     }
-}
\ No newline at end of file
+}
@@ -1 +1,2 @@
 pub mod path_grid;
+pub mod terrain;
@@ -10,6 +10,8 @@ use std::cmp::Ordering;
 use self::fnv::FnvHashMap;
 use self::rand::Rng;
 use self::time::PreciseTime;
+use std::io;
+use data::persist::{Persist, Writer, Reader, invalid};
 
 type Point = (isize, isize);
 
@@ -96,6 +98,56 @@ pub struct PathGrid {
     came_from: FnvHashMap<Point, Point>,
 }
 
+impl Persist for Jumps {
+    fn save(&self, w: &mut Writer) {
+        self.nj.save(w);
+        self.ej.save(w);
+        self.sj.save(w);
+        self.wj.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<Jumps> {
+        let nj = Persist::load(r)?;
+        let ej = Persist::load(r)?;
+        let sj = Persist::load(r)?;
+        let wj = Persist::load(r)?;
+
+        Ok(Jumps {
+            nj: nj,
+            ej: ej,
+            sj: sj,
+            wj: wj,
+        })
+    }
+}
+
+// The search buffers are scratch space and start out empty again.
+impl Persist for PathGrid {
+    fn save(&self, w: &mut Writer) {
+        self.w.save(w);
+        self.h.save(w);
+        self.counter.save(w);
+        self.states.save(w);
+        self.jumps.save(w);
+    }
+    fn load(r: &mut Reader) -> io::Result<PathGrid> {
+        let w = isize::load(r)?;
+        let h = isize::load(r)?;
+        let counter = usize::load(r)?;
+        let states: BitVec = Persist::load(r)?;
+        let jumps: Vec<Jumps> = Persist::load(r)?;
+
+        if w < 0 || h < 0 || states.len() != (w * h) as usize || jumps.len() != states.len() {
+            return Err(invalid("path grid"));
+        }
+
+        let mut pg = PathGrid::new(w as usize, h as usize);
+        pg.counter = counter;
+        pg.states = states;
+        pg.jumps = jumps;
+        Ok(pg)
+    }
+}
+
 impl PathGrid {
 
     fn reset(&mut self) {
@@ -0,0 +1,121 @@
+/*
+ Which tiles each kind of unit can move over.
+
+ Every movement class that paths gets its own passability grids: a ByteGrid in the game that keeps units off tiles
+ they can't be on, and a PathGrid per team to find paths with. Structures close their tiles in every class, and give
+ each class back what the terrain allows when they die.
+
+ Tiles come from the map's collision layer (see tmx_decode):
+   land          ground, hover and amphibious units
+   water         ships, submarines, hover and amphibious units
+   hover         only hover units, e.g. swamp or lava
+   underwater    only submarines and amphibious units, e.g. water under ice
+   amphibious    only amphibious and hover units, e.g. shoreline mud
+   air, none     nothing that moves on the surface
+ Air units go wherever ground units do, as they always have.
+*/
+
+use data::aliases::*;
+use data::game::Game;
+use pathing::path_grid::PathGrid;
+
+// Tile types, as tmx_decode turns them into map collisions
+pub const TILE_NONE: usize = 0;
+pub const TILE_GROUND: usize = 1;
+pub const TILE_AIR: usize = 2;
+pub const TILE_WATER: usize = 3;
+pub const TILE_HOVER: usize = 4;
+pub const TILE_UNDERWATER: usize = 5;
+pub const TILE_AMPHIBIOUS: usize = 6;
+
+// Movement classes with grids of their own
+pub const GROUND: usize = 0;
+pub const WATER: usize = 1;
+pub const UNDERWATER: usize = 2;
+pub const HOVER: usize = 3;
+pub const AMPHIBIOUS: usize = 4;
+pub const MOVE_CLASSES: usize = 5;
+
+// The grids a unit moving this way paths on. None for units that don't path.
+pub fn move_class(move_type: MoveType) -> Option<usize> {
+    match move_type {
+        MoveType::Ground => Some(GROUND),
+        MoveType::Water => Some(WATER),
+        MoveType::Underwater => Some(UNDERWATER),
+        MoveType::Hover => Some(HOVER),
+        MoveType::Amphibious => Some(AMPHIBIOUS),
+        MoveType::Air | MoveType::None => None,
+    }
+}
+
+// The grids a unit is kept on. Units that don't path use the ground's.
+pub fn grid_class(move_type: MoveType) -> usize {
+    move_class(move_type).unwrap_or(GROUND)
+}
+
+pub fn passable(class: usize, tile: usize) -> bool {
+    match (class, tile) {
+        (GROUND, TILE_GROUND) => true,
+        (WATER, TILE_WATER) => true,
+        (UNDERWATER, TILE_WATER) | (UNDERWATER, TILE_UNDERWATER) => true,
+        (HOVER, TILE_GROUND) | (HOVER, TILE_WATER) | (HOVER, TILE_HOVER) | (HOVER, TILE_AMPHIBIOUS) => true,
+        (AMPHIBIOUS, TILE_GROUND) | (AMPHIBIOUS, TILE_WATER) | (AMPHIBIOUS, TILE_UNDERWATER) | (AMPHIBIOUS, TILE_AMPHIBIOUS) => true,
+        _ => false,
+    }
+}
+
+// Closes what the terrain doesn't allow in every class's grids. Done once when the match is set up.
+pub fn apply_terrain(game: &mut Game) {
+    let (width, height) = game.map_data.width_and_height();
+
+    for class in 0..MOVE_CLASSES {
+        // Closing tiles one at a time is slow, so each row is closed a run at a time on one grid that's copied to every team
+        let mut grid = PathGrid::new(width, height);
+
+        for y in 0..height as isize {
+            let mut x = 0;
+
+            while x < width as isize {
+                let run = (x..width as isize).take_while(|&xo| !passable(class, game.map_data.collision_at((xo, y)))).count() as isize;
+
+                if run > 0 {
+                    grid.close_area((x, y, run, 1));
+
+                    for xo in x..x + run {
+                        game.bytegrids[class].set_point(false, (xo, y));
+                    }
+                }
+
+                x += run + 1;
+            }
+        }
+
+        for team in game.teams.iter() {
+            game.teams.jps_grids[team][class] = grid.clone();
+        }
+    }
+}
+
+// Tiles a structure of team stands on, which nothing can move through.
+pub fn close_tiles(game: &mut Game, team: TeamID, tiles: &[(isize, isize)]) {
+    for &xy in tiles {
+        for class in 0..MOVE_CLASSES {
+            game.bytegrids[class].set_point(false, xy);
+            game.teams.jps_grids[team][class].close_point(xy);
+        }
+    }
+}
+
+// Gives tiles back to whoever the terrain lets on them.
+pub fn open_tiles(game: &mut Game, team: TeamID, tiles: &[(isize, isize)]) {
+    for &xy in tiles {
+        let tile = game.map_data.collision_at(xy);
+
+        for class in 0..MOVE_CLASSES {
+            if passable(class, tile) {
+                game.bytegrids[class].set_point(true, xy);
+                game.teams.jps_grids[team][class].open_point(xy);
+            }
+        }
+    }
+}
@@ -0,0 +1,435 @@
+/*
+ Recording matches and playing them back.
+
+ A replay file is JSON lines. The first line is a ReplayHeader with everything needed to rebuild the match from
+ nothing: its config (without passwords), seed, map and unit and missile definitions. Every line after that is a
+ RecordedFrame with the orders players sent during that frame, hex encoded exactly as they arrived. Frames nobody
+ gave orders in are left out, except for one every second of game time so the file shows how long the match ran.
+
+ Only orders and the admin commands that change the game are recorded. Nothing else players send changes it.
+
+ Playing back rebuilds the game with setup_game::new_game and feeds the recorded orders to Simulation::step at
+ the frames they arrived in, after that frame's commands, so it plays out exactly like the original. A served replay keeps a checkpoint of the
+ game every CHECKPOINT_SECONDS so seeking only re-simulates from the nearest one, and viewers are sent a fresh
+ keyframe after every seek.
+*/
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, Cursor};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
+use num::FromPrimitive;
+use serde_json;
+use hex;
+use config::MatchConfig;
+use console;
+use data::aliases::*;
+use data::game::{Game, incorporate_messages};
+use data::protocol::{new_frame, write_record, encode_keyframe_request, ReplayAction};
+use libs::auth::Credentials;
+use libs::netcom;
+use libs::netcom::{MessageSink, NullSink, Role};
+use setup_game::new_game;
+use simulation::Simulation;
+
+pub const REPLAY_VERSION: u32 = 3;
+// Game time between the checkpoints seeking starts from
+pub const CHECKPOINT_SECONDS: f64 = 30.0;
+// Replays can be watched further from real time than lifecycle::MIN_SPEED and MAX_SPEED let a live match run
+pub const MIN_REPLAY_SPEED: f64 = 0.125;
+pub const MAX_REPLAY_SPEED: f64 = 16.0;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplayHeader {
+    pub version: u32,
+    pub config: MatchConfig,
+    pub seed: u64,
+    pub map: String,
+    pub units: Vec<String>,
+    pub missiles: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    pub name: String,
+    pub team: usize,
+    pub data: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    pub frame: u32,
+    pub messages: Vec<RecordedMessage>,
+    // Admin commands, see console
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<String>,
+}
+
+pub struct Recorder {
+    out: BufWriter<File>,
+    marker_every: u32,
+}
+
+impl Recorder {
+    pub fn create(path: &str, header: &ReplayHeader) -> Result<Recorder, String> {
+        let file = File::create(path).map_err(|e| format!("Couldn't create {}: {}", path, e))?;
+        let mut recorder = Recorder {
+            out: BufWriter::new(file),
+            marker_every: u32::max(1, header.config.fps as u32),
+        };
+
+        recorder.write_line(header);
+        Ok(recorder)
+    }
+
+    // Call with each frame's messages and the commands applied to it before it's stepped.
+    pub fn record(&mut self, frame: u32, msgs: &[(String, Role, Vec<u8>)], commands: &[String]) {
+        let messages: Vec<RecordedMessage> = msgs.iter().filter_map(|&(ref name, role, ref data)| {
+            let is_order = data.first().and_then(|&tag| ServerMessage::from_u8(tag)).map_or(false, |m| m.is_order());
+
+            match role {
+                Role::Player(team) if is_order => Some(RecordedMessage {
+                    name: name.clone(),
+                    team: team,
+                    data: hex::encode(data),
+                }),
+                _ => None,
+            }
+        }).collect();
+
+        if !messages.is_empty() || !commands.is_empty() || frame % self.marker_every == 0 {
+            self.write_line(&RecordedFrame {
+                frame: frame,
+                messages: messages,
+                commands: commands.to_vec(),
+            });
+        }
+    }
+
+    // Lines are flushed as they're written so a crash loses as little as possible
+    fn write_line<T: ::serde::Serialize>(&mut self, line: &T) {
+        if let Ok(json) = serde_json::to_string(line) {
+            let _ = writeln!(self.out, "{}", json);
+            let _ = self.out.flush();
+        }
+    }
+}
+
+pub fn load(path: &str) -> Result<(ReplayHeader, Vec<RecordedFrame>), String> {
+    let file = File::open(path).map_err(|e| format!("Couldn't open {}: {}", path, e))?;
+    let mut lines = BufReader::new(file).lines();
+
+    let header: ReplayHeader = match lines.next() {
+        Some(Ok(line)) => serde_json::from_str(&line).map_err(|e| format!("Couldn't parse the replay header: {}", e))?,
+        _ => return Err(format!("{} is empty.", path)),
+    };
+
+    if header.version != REPLAY_VERSION {
+        return Err(format!("{} is a version {} replay, this server plays version {}.", path, header.version, REPLAY_VERSION));
+    }
+
+    let mut frames = Vec::new();
+
+    for (ix, line) in lines.enumerate() {
+        let line = line.map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+
+        // The last line of a match that didn't end cleanly can be cut off
+        match serde_json::from_str::<RecordedFrame>(&line) {
+            Ok(frame) => frames.push(frame),
+            Err(e) => {
+                println!("Stopping the replay at line {}: {}", ix + 2, e);
+                break;
+            }
+        }
+    }
+
+    Ok((header, frames))
+}
+
+pub struct ReplayRunner {
+    pub game: Game,
+    pub paused: bool,
+    pub speed: f64,
+    frames: Vec<RecordedFrame>,
+    checkpoints: Vec<Game>,
+    checkpoint_every: u32,
+}
+
+impl ReplayRunner {
+    pub fn new(header: &ReplayHeader, frames: Vec<RecordedFrame>, sink: Arc<dyn MessageSink>) -> ReplayRunner {
+        let game = new_game(&header.config, header.seed, &header.map, &header.units, &header.missiles, sink);
+        ReplayRunner::from_game(game, frames)
+    }
+
+    // Plays frames back on a game that's already set up the way the recorded one started.
+    pub fn from_game(game: Game, frames: Vec<RecordedFrame>) -> ReplayRunner {
+        let checkpoint_every = u32::max(1, (CHECKPOINT_SECONDS * game.fps()) as u32);
+
+        ReplayRunner {
+            checkpoints: vec![game.checkpoint()],
+            game: game,
+            paused: false,
+            speed: 1.0,
+            frames: frames,
+            checkpoint_every: checkpoint_every,
+        }
+    }
+
+    // The last frame anything was recorded in.
+    pub fn last_frame(&self) -> u32 {
+        self.frames.last().map_or(0, |f| f.frame)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.game.frame_number > self.last_frame()
+    }
+
+    // Steps one frame with the orders recorded for it, plus whatever else is passed in.
+    pub fn step(&mut self, mut extra: Vec<(String, Role, Vec<u8>)>) {
+        if let Ok(ix) = self.frames.binary_search_by_key(&self.game.frame_number, |f| f.frame) {
+            for line in &self.frames[ix].commands {
+                if let Ok(command) = console::parse(line) {
+                    let _ = console::apply(&mut self.game, &command);
+                }
+            }
+        }
+
+        let mut inputs = self.inputs(self.game.frame_number);
+        inputs.append(&mut extra);
+        Simulation::step(&mut self.game, inputs);
+
+        let frame = self.game.frame_number;
+        let newest = self.checkpoints.last().map_or(0, |c| c.frame_number);
+
+        if frame % self.checkpoint_every == 0 && frame > newest {
+            self.checkpoints.push(self.game.checkpoint());
+        }
+    }
+
+    // Puts the game at the start of frame, going back to a checkpoint if it has to.
+    pub fn seek(&mut self, frame: u32) {
+        let frame = u32::min(frame, self.last_frame() + 1);
+        let start = self.checkpoints.iter().rev().find(|c| c.frame_number <= frame).map(|c| c.frame_number);
+
+        if let Some(start) = start {
+            if frame < self.game.frame_number || start > self.game.frame_number {
+                let sink = self.game.sink.clone();
+                let checkpoint = self.checkpoints.iter().find(|c| c.frame_number == start).unwrap();
+                self.game = checkpoint.checkpoint();
+                self.game.sink = sink;
+            }
+        }
+
+        // Nobody needs to see the frames being skipped
+        let sink = self.game.sink.clone();
+        self.game.sink = Arc::new(NullSink);
+
+        while self.game.frame_number < frame {
+            self.step(Vec::new());
+        }
+
+        self.game.sink = sink;
+    }
+
+    // Carries out a ReplayControl message. Returns whether the game moved, in which case viewers need a keyframe.
+    pub fn control(&mut self, data: Vec<u8>) -> io::Result<bool> {
+        let bytes = &mut Cursor::new(data);
+        bytes.read_u8()?;
+        bytes.read_u32::<BigEndian>()?;
+
+        let action = ReplayAction::from_u8(bytes.read_u8()?).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unknown replay action."))?;
+
+        match action {
+            ReplayAction::Pause => self.paused = true,
+            ReplayAction::Resume => self.paused = false,
+            ReplayAction::Seek => {
+                let frame = bytes.read_u32::<BigEndian>()?;
+                self.seek(frame);
+                return Ok(true);
+            }
+            ReplayAction::Speed => {
+                let speed = bytes.read_f64::<BigEndian>()?;
+
+                if !speed.is_finite() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Speed has to be a number."));
+                }
+
+                self.speed = f64::min(MAX_REPLAY_SPEED, f64::max(MIN_REPLAY_SPEED, speed));
+            }
+        }
+
+        Ok(false)
+    }
+
+    pub fn encode_status(&self) -> Vec<u8> {
+        let mut msg = new_frame(self.game.frame_number);
+
+        write_record(&mut msg, ClientMessage::ReplayStatus, |rec| {
+            let _ = rec.write_u32::<BigEndian>(self.game.frame_number);
+            let _ = rec.write_u32::<BigEndian>(self.last_frame());
+            let _ = rec.write_u8(self.paused as u8);
+            let _ = rec.write_f64::<BigEndian>(self.speed);
+        });
+
+        msg.into_inner()
+    }
+
+    fn inputs(&self, frame: u32) -> Vec<(String, Role, Vec<u8>)> {
+        match self.frames.binary_search_by_key(&frame, |f| f.frame) {
+            Ok(ix) => {
+                self.frames[ix].messages.iter().filter_map(|msg| {
+                    hex::decode(&msg.data).ok().map(|data| (msg.name.clone(), Role::Player(msg.team), data))
+                }).collect()
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+// Re-simulates a replay as fast as it can and prints where it ended up.
+pub fn run_headless(path: &str) -> Result<(), String> {
+    let (header, frames) = load(path)?;
+    let mut runner = ReplayRunner::new(&header, frames, Arc::new(NullSink));
+
+    while !runner.is_done() {
+        runner.step(Vec::new());
+    }
+
+    println!("Replayed {} frames with seed {}.", runner.game.frame_number, header.seed);
+    println!("Final checksum: {:016x}", runner.game.checksum());
+    Ok(())
+}
+
+// Streams a replay to whoever logs in as if it were live. Players from the roster watch through their own team's eyes.
+pub fn serve(path: &str, roster: Vec<(String, Credentials)>, config: &MatchConfig) -> Result<(), String> {
+    let (header, frames) = load(path)?;
+
+    let roster = roster.into_iter().map(|(name, mut creds)| {
+        if let Role::Player(team) = creds.role {
+            creds.role = Role::Spectator(Some(team));
+        }
+        (name, creds)
+    }).collect();
+
+    println!("Networking.");
+    let netc = netcom::new(roster, &config.port.to_string(), &config.address);
+    let mut runner = ReplayRunner::new(&header, frames, netc.clone());
+
+    println!("Replaying {} frames.", runner.last_frame() + 1);
+
+    loop {
+        let start_time = Instant::now();
+        let mut viewer_msgs = Vec::new();
+        let mut controlled = false;
+        let mut moved = false;
+
+        for (name, role, data) in netcom::get_messages(&netc) {
+            if data.first() == Some(&(ServerMessage::ReplayControl as u8)) {
+                match runner.control(data) {
+                    Ok(seeked) => {
+                        controlled = true;
+                        moved |= seeked;
+                    }
+                    Err(e) => runner.game.sink.strike(&name, &e.to_string()),
+                }
+            }
+            else {
+                viewer_msgs.push((name, role, data));
+            }
+        }
+
+        if moved {
+            for (name, role) in netcom::connected(&netc) {
+                viewer_msgs.push((name, role, encode_keyframe_request()));
+            }
+        }
+
+        if controlled {
+            send_to_everyone(&runner.game, runner.encode_status());
+        }
+
+        if runner.paused || runner.is_done() {
+            incorporate_messages(&mut runner.game, viewer_msgs);
+        }
+        else {
+            runner.step(viewer_msgs);
+        }
+
+        let time_spent = start_time.elapsed();
+        let frame_time = Duration::from_millis((1000.0 / (runner.game.fps() * runner.speed)) as u64);
+
+        if frame_time > time_spent {
+            sleep(frame_time - time_spent);
+        }
+    }
+}
+
+fn send_to_everyone(game: &Game, msg: Vec<u8>) {
+    for &team in &game.teams.iter() {
+        let team_usize = unsafe { team.usize_unwrap() };
+        game.sink.send_message_to_team(msg.clone(), team_usize);
+    }
+
+    if game.sink.has_spectators(None) {
+        game.sink.send_message_to_spectators(msg, None);
+    }
+}
+
+#[test]
+fn replays_play_out_like_the_original() {
+    use std::env;
+    use simulation::test_game;
+    use test_messages::move_message;
+
+    let path = env::temp_dir().join("rust_rts_replay_test.jsonl");
+    let path = path.to_str().unwrap();
+    let header = ReplayHeader {
+        version: REPLAY_VERSION,
+        config: MatchConfig::default(),
+        seed: 3,
+        map: String::new(),
+        units: Vec::new(),
+        missiles: Vec::new(),
+    };
+
+    let spawns = [(10.0, 40.0), (12.0, 40.0)];
+    let game = &mut test_game(10.0, header.seed, &spawns);
+    let mut recorder = Recorder::create(path, &header).unwrap();
+    let id = game.units.iter()[0];
+
+    // Ends on a frame the recorder marks so the replay knows how far it goes
+    for _ in 0..21 {
+        let mut msgs = Vec::new();
+
+        if game.frame_number == 2 {
+            msgs.push(("p1".to_string(), Role::Player(0), move_message(id, (10.5, 60.5))));
+        }
+
+        let mut commands = Vec::new();
+
+        if game.frame_number == 5 {
+            commands.push("spawn Medium1 0 14 40".to_string());
+            console::apply(game, &console::parse(&commands[0]).unwrap()).unwrap();
+        }
+
+        recorder.record(game.frame_number, &msgs, &commands);
+        Simulation::step(game, msgs);
+    }
+
+    drop(recorder);
+    let (_, frames) = load(path).unwrap();
+    let mut runner = ReplayRunner::from_game(test_game(10.0, header.seed, &spawns), frames);
+    runner.checkpoint_every = 5;
+
+    runner.seek(21);
+    assert!(runner.is_done());
+    assert_eq!(runner.game.checksum(), game.checksum());
+
+    runner.seek(7);
+    runner.seek(21);
+    assert_eq!(runner.game.checksum(), game.checksum());
+}
@@ -0,0 +1,163 @@
+/*
+ Saving a running match to a file and picking it back up, so a long match can outlive the server process and so a
+ game from the middle of a match can be set up again on purpose.
+
+ A save file starts with one line of JSON, a SaveHeader: the save format's version, the frame the save was taken
+ at and the match's ReplayHeader, which has everything needed to build the game from nothing. The rest of the file
+ is the game's state as written by data::persist, in the order write_state puts it.
+
+ Loading builds an empty game from the header the same way a new match is built and reads the state over the top.
+ What has been sent to clients (snapshots, unacknowledged events) isn't kept. Clients that reconnect are sent a
+ keyframe like anyone else joining.
+*/
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufRead, BufReader};
+use std::sync::Arc;
+use serde_json;
+use data::game::Game;
+use pathing::terrain;
+use data::persist::{Persist, Writer, Reader, invalid};
+use libs::netcom::MessageSink;
+use replay::ReplayHeader;
+use setup_game::empty_game;
+
+pub const SAVE_VERSION: u32 = 9;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SaveHeader {
+    pub version: u32,
+    pub frame: u32,
+    pub game: ReplayHeader,
+}
+
+pub fn write_state(game: &Game, w: &mut Writer) {
+    game.frame_number.save(w);
+    game.rng.save(w);
+    game.units.save_state(w);
+    game.missiles.save_state(w);
+    game.teams.save(w);
+    game.unit_kdt.save(w);
+    game.missile_kdt.save(w);
+    game.bytegrids.save(w);
+    game.lockstep.save(w);
+    game.lifecycle.save_state(w);
+}
+
+// Reads state written by write_state over a game built from the same header.
+pub fn read_state(game: &mut Game, r: &mut Reader) -> io::Result<()> {
+    game.frame_number = Persist::load(r)?;
+    game.rng = Persist::load(r)?;
+    game.units.load_state(r)?;
+    game.missiles.load_state(r)?;
+    game.teams = Persist::load(r)?;
+    game.unit_kdt = Persist::load(r)?;
+    game.missile_kdt = Persist::load(r)?;
+    game.bytegrids = Persist::load(r)?;
+    game.lockstep = Persist::load(r)?;
+    game.lifecycle.load_state(r)?;
+
+    if !r.is_done() {
+        return Err(invalid("length"));
+    }
+
+    let classes = terrain::MOVE_CLASSES;
+    if game.bytegrids.len() != classes || game.teams.iter().into_iter().any(|team| game.teams.jps_grids[team].len() != classes) {
+        return Err(invalid("movement classes"));
+    }
+
+    Ok(())
+}
+
+// Writes next to the file first so a crash halfway through never leaves a broken save behind.
+pub fn save(path: &str, match_info: &ReplayHeader, game: &Game) -> Result<(), String> {
+    let header = SaveHeader {
+        version: SAVE_VERSION,
+        frame: game.frame_number,
+        game: match_info.clone(),
+    };
+
+    let mut w = Writer::new();
+    write_state(game, &mut w);
+
+    let tmp_path = format!("{}.tmp", path);
+    let write = || -> io::Result<()> {
+        let mut file = File::create(&tmp_path)?;
+        serde_json::to_writer(&mut file, &header)?;
+        file.write_all(b"\n")?;
+        file.write_all(&w.into_inner())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    };
+
+    write().map_err(|e| format!("Couldn't save to {}: {}", path, e))
+}
+
+pub fn load(path: &str, sink: Arc<dyn MessageSink>) -> Result<(ReplayHeader, Game), String> {
+    let file = File::open(path).map_err(|e| format!("Couldn't open {}: {}", path, e))?;
+    let mut input = BufReader::new(file);
+    let mut line = String::new();
+    let mut state = Vec::new();
+
+    input.read_line(&mut line).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+    input.read_to_end(&mut state).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+
+    let header: SaveHeader = serde_json::from_str(&line).map_err(|e| format!("Couldn't parse {}: {}", path, e))?;
+
+    if header.version != SAVE_VERSION {
+        return Err(format!("{} is a version {} save. This server reads version {}.", path, header.version, SAVE_VERSION));
+    }
+
+    let info = header.game;
+    let mut game = empty_game(&info.config, info.seed, &info.map, &info.units, &info.missiles, sink);
+
+    read_state(&mut game, &mut Reader::new(&state)).map_err(|e| format!("Couldn't load {}: {}", path, e))?;
+    Ok((info, game))
+}
+
+#[test]
+fn saved_games_play_out_like_the_original() {
+    use std::rc::Rc;
+    use data::aliases::*;
+    use data::checksum::first_divergence;
+    use libs::netcom::Role;
+    use simulation::{Simulation, test_game};
+    use test_messages::formation_message;
+
+    let game = &mut test_game(10.0, 5, &[(10.0, 40.0), (12.0, 40.0), (30.0, 60.0)]);
+    let ids = game.units.iter();
+    let team = game.units.team(ids[1]);
+    game.units.set_team(ids[2], team);
+
+    // Two units sharing one move order in formation, so the order has to come back shared and with its slots
+    let msg = formation_message(&ids[1..], (10.5, 60.5), Formation::Box);
+    Simulation::step(game, vec![("p1".to_string(), Role::Player(unsafe { team.usize_unwrap() }), msg)]);
+
+    for _ in 0..10 {
+        Simulation::step(game, Vec::new());
+    }
+
+    let mut w = Writer::new();
+    write_state(game, &mut w);
+    let bytes = w.into_inner();
+
+    let loaded = &mut test_game(10.0, 5, &[]);
+    read_state(loaded, &mut Reader::new(&bytes)).unwrap();
+
+    assert_eq!(loaded.checksum(), game.checksum());
+    assert!(first_divergence(loaded, game).is_none());
+
+    let (a, b) = (ids[1], ids[2]);
+    assert!(!loaded.units.orders(a).is_empty());
+    assert!(Rc::ptr_eq(&loaded.units.orders(a)[0], &loaded.units.orders(b)[0]));
+
+    for _ in 0..20 {
+        Simulation::step(game, Vec::new());
+        Simulation::step(loaded, Vec::new());
+    }
+
+    assert_eq!(loaded.checksum(), game.checksum());
+}
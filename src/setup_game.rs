@@ -4,49 +4,69 @@ use self::rand::Rng;
 use data::game::Game;
 use data::aliases::*;
 use data::units::Unit;
+use data::protocol::write_record;
+use data::lockstep::Lockstep;
+use config::MatchConfig;
+use libs::netcom::MessageSink;
+use libs::tmx_decode::MapData;
+use pathing::terrain;
+use std::sync::Arc;
 use std::fs;
+use std::path::PathBuf;
 use std::io::prelude::*;
 use std::io::Cursor;
-use byteorder::{WriteBytesExt, BigEndian};
 
-pub fn setup_game(game: &mut Game) {
-    let mut rng = rand::thread_rng();
+// Builds and sets up a match from the contents of its map and definition files.
+// Live matches and replays both start here so they start out identical.
+pub fn new_game(config: &MatchConfig, seed: u64, map_json: &str, unit_jsons: &[String], missile_jsons: &[String], sink: Arc<dyn MessageSink>) -> Game {
+    let mut game = empty_game(config, seed, map_json, unit_jsons, missile_jsons, sink);
+    setup_game(&mut game, config);
+    game
+}
+
+// The same game before anything is put in it. Loading a save starts from this.
+pub fn empty_game(config: &MatchConfig, seed: u64, map_json: &str, unit_jsons: &[String], missile_jsons: &[String], sink: Arc<dyn MessageSink>) -> Game {
+    let (units, unit_id_map, missiles, missile_id_map, unit_info, misl_info) = list_from_json(unit_jsons, missile_jsons);
+    let map_data = MapData::from_json(map_json);
+    let mut game = Game::new(config.fps, seed, config.max_units, config.max_teams, map_data, units, unit_id_map, missiles, missile_id_map, unit_info, misl_info, sink);
+
+    if config.lockstep {
+        game.lockstep = Some(Lockstep::new(config.lockstep_delay));
+    }
+
+    let time_limit = config.time_limit.map(|seconds| (seconds * config.fps).round() as u32);
+    let pause_budget = (config.pause_seconds * config.fps).round() as u32;
+    game.lifecycle.set_rules(config.victory, time_limit, pause_budget);
+    game
+}
+
+// Makes a team for every team in the roster and scatters each one's starting units over its start area.
+pub fn setup_game(game: &mut Game, config: &MatchConfig) {
     let fps = game.fps();
+    let starting_teams = config.starting_teams();
 
-    if let Some(team) = game.teams.make_team() {
-        game.teams.max_prime[team] = 1000.0;
-        game.teams.max_energy[team] = 1000.0;
-        game.teams.prime[team] = 1000.0;
-        game.teams.energy[team] = 1000.0;
+    for ix in 0..config.teams() {
+        let team = match game.teams.make_team() {
+            Some(team) => team,
+            None => panic!("setup_game: Not enough team IDs to go around."),
+        };
 
-        for _ in 0..2000 {
-            match game.units.make_from_name(fps, "Medium1".to_string()) {
-                Some(id) => {
-                    let x = rng.gen_range(0.0, 24.0);
-                    let y = rng.gen_range(0.0, 96.0);
-                    game.units.set_xy(id, (x, y));
-                    game.units.set_team(id, team);
-                    let prog_required = game.units.build_cost(id);
-                    let max_health = game.units.max_health(id);
-                    game.units.set_progress(id, prog_required);
-                    game.units.set_health(id, max_health);
-                }
-                None => panic!("setup_game: Not enough unit IDs to go around."),
-            }
+        game.teams.max_prime[team] = config.max_prime;
+        game.teams.max_energy[team] = config.max_energy;
+        game.teams.prime[team] = config.starting_prime;
+        game.teams.energy[team] = config.starting_energy;
+
+        if !starting_teams.contains(&ix) {
+            continue;
         }
-    }
 
-    if let Some(team) = game.teams.make_team() {
-        game.teams.max_prime[team] = 1000.0;
-        game.teams.max_energy[team] = 1000.0;
-        game.teams.prime[team] = 1000.0;
-        game.teams.energy[team] = 1000.0;
+        let area = config.start_areas[ix];
 
-        for _ in 0..2000 {
-            match game.units.make_from_name(fps, "Medium1".to_string()) {
+        for _ in 0..config.starting_units {
+            match game.units.make_from_name(fps, config.starting_unit.clone()) {
                 Some(id) => {
-                    let x = rng.gen_range(36.0, 108.0);
-                    let y = rng.gen_range(0.0, 24.0);
+                    let x = game.rng.gen_range(area.x, area.x + area.width);
+                    let y = game.rng.gen_range(area.y, area.y + area.height);
                     game.units.set_xy(id, (x, y));
                     game.units.set_team(id, team);
                     let prog_required = game.units.build_cost(id);
@@ -54,45 +74,38 @@ pub fn setup_game(game: &mut Game) {
                     game.units.set_progress(id, prog_required);
                     game.units.set_health(id, max_health);
                 }
+                None if game.units.type_named(&config.starting_unit).is_none() => {
+                    panic!("setup_game: There's no unit type {}.", config.starting_unit)
+                }
                 None => panic!("setup_game: Not enough unit IDs to go around."),
             }
         }
     }
 
-    let (width, height) = game.map_data.width_and_height();
+    terrain::apply_terrain(game);
+}
 
-    for team in game.teams.iter() {
-        for i in 0..game.map_data.collisions().len() {
-            let collision = game.map_data.collisions()[i];
-            let x = i % width;
-            let y = i / width;
-            let xy = (x as isize, (height - y - 1) as isize);
+pub fn list(unit_dir: &str, missile_dir: &str) -> (VecUID<UnitTypeID, Unit>, UIDMapping<UnitTypeID>, VecUID<MissileTypeID, Missile>, UIDMapping<MissileTypeID>, Vec<u8>, Vec<u8>) {
+    list_from_json(&read_definitions(unit_dir), &read_definitions(missile_dir))
+}
 
-            match collision {
-                0 | 3 | 4 => {
-                    game.teams.jps_grid[team].close_point(xy);
-                }
-                _ => (),
-            }
-        }
-    }
+// The contents of every file in dir.
+// Files are read in name order so type IDs don't depend on the file system.
+pub fn read_definitions(dir: &str) -> Vec<String> {
+    let mut definitions = Vec::new();
 
-    for i in 0..game.map_data.collisions().len() {
-        let collision = game.map_data.collisions()[i];
-        let x = i % width;
-        let y = i / width;
-        let xy = (x as isize, (height - y - 1) as isize);
+    for path in sorted_dir_entries(dir) {
+        let mut file = fs::File::open(path).unwrap();
+        let mut contents = String::new();
 
-        match collision {
-            0 | 3 | 4 => {
-                game.bytegrid.set_point(false, xy);
-            }
-            _ => (),
-        }
+        file.read_to_string(&mut contents).unwrap();
+        definitions.push(contents);
     }
+
+    definitions
 }
 
-pub fn list() -> (VecUID<UnitTypeID, Unit>, UIDMapping<UnitTypeID>, VecUID<MissileTypeID, Missile>, UIDMapping<MissileTypeID>, Vec<u8>, Vec<u8>) {
+pub fn list_from_json(unit_jsons: &[String], missile_jsons: &[String]) -> (VecUID<UnitTypeID, Unit>, UIDMapping<UnitTypeID>, VecUID<MissileTypeID, Missile>, UIDMapping<MissileTypeID>, Vec<u8>, Vec<u8>) {
     let mut unit_list = Vec::new();
     let mut misl_list = Vec::new();
     let mut unit_uids = UIDMapping::new(256);
@@ -101,21 +114,11 @@ pub fn list() -> (VecUID<UnitTypeID, Unit>, UIDMapping<UnitTypeID>, VecUID<Missi
     let mut misl_info = Cursor::new(Vec::new());
 
     // Convert JSON to units & create unit info message for clients
-    for entry in fs::read_dir("./src/units/").unwrap() {
-        let mut file = fs::File::open(entry.unwrap().path()).unwrap();
-        let mut contents = String::new();
-
-        file.read_to_string(&mut contents).unwrap();
-
+    for contents in unit_jsons {
         if let Some(unit) = Unit::from_json(contents.as_ref()) {
-            let bytes: Vec<u8> = contents.into_bytes();
-
-            let _ = unit_info.write_u8(ClientMessage::UnitInfo as u8);
-            let _ = unit_info.write_u32::<BigEndian>(bytes.len() as u32);
-
-            for byte in bytes {
-                let _ = unit_info.write_u8(byte);
-            }
+            write_record(&mut unit_info, ClientMessage::UnitInfo, |rec| {
+                let _ = rec.write_all(contents.as_bytes());
+            });
 
             unit_list.push(unit);
         }
@@ -125,21 +128,11 @@ pub fn list() -> (VecUID<UnitTypeID, Unit>, UIDMapping<UnitTypeID>, VecUID<Missi
     }
 
     // Convert JSON to missiles & create missile info message for clients
-    for entry in fs::read_dir("./src/missiles/").unwrap() {
-        let mut file = fs::File::open(entry.unwrap().path()).unwrap();
-        let mut contents = String::new();
-
-        file.read_to_string(&mut contents).unwrap();
-
+    for contents in missile_jsons {
         if let Some(misl) = Missile::from_json(contents.as_ref()) {
-            let bytes: Vec<u8> = contents.into_bytes();
-
-            let _ = misl_info.write_u8(ClientMessage::MissileInfo as u8);
-            let _ = misl_info.write_u32::<BigEndian>(bytes.len() as u32);
-
-            for byte in bytes {
-                let _ = misl_info.write_u8(byte);
-            }
+            write_record(&mut misl_info, ClientMessage::MissileInfo, |rec| {
+                let _ = rec.write_all(contents.as_bytes());
+            });
 
             misl_list.push(misl);
         }
@@ -195,10 +188,10 @@ pub fn list() -> (VecUID<UnitTypeID, Unit>, UIDMapping<UnitTypeID>, VecUID<Missi
         }
 
         // Set build roster IDs
-        for build_rostee in unit.build_roster_names().clone().iter() {
+        for build_rostee in unit.build_roster().clone().iter() {
             match unit_uids.id(build_rostee.clone()) {
                 Some(unit_type_id) => {
-                    unit.mut_build_roster().insert(unit_type_id);
+                    unit.mut_build_roster_ids().insert(unit_type_id);
                 }
                 None => {
                     panic!("You have a bad build roster reference for {}.", build_rostee);
@@ -207,10 +200,10 @@ pub fn list() -> (VecUID<UnitTypeID, Unit>, UIDMapping<UnitTypeID>, VecUID<Missi
         }
 
         // Set train roster IDs
-        for train_rostee in unit.train_roster_names().clone().iter() {
+        for train_rostee in unit.train_roster().clone().iter() {
             match unit_uids.id(train_rostee.clone()) {
                 Some(unit_type_id) => {
-                    unit.mut_train_roster().insert(unit_type_id);
+                    unit.mut_train_roster_ids().insert(unit_type_id);
                 }
                 None => {
                     panic!("You have a bad train roster reference for {}.", train_rostee);
@@ -233,4 +226,10 @@ pub fn list() -> (VecUID<UnitTypeID, Unit>, UIDMapping<UnitTypeID>, VecUID<Missi
     }
 
     (unit_vec, unit_uids, misl_vec, misl_uids, unit_info.into_inner(), misl_info.into_inner())
-}
\ No newline at end of file
+}
+
+fn sorted_dir_entries(dir: &str) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    paths.sort();
+    paths
+}
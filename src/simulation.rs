@@ -0,0 +1,969 @@
+/*
+ Advances a game by exactly one frame.
+ Nothing in here touches sockets, clocks or thread local randomness, so the same game fed the same
+ inputs always ends up in the same place. The server loop, tests, bots and replay tools all drive
+ the game through Simulation::step.
+*/
+
+use data::game::{Game, incorporate_messages, incorporate_due_orders, encode_team_info};
+use data::logger;
+use data::lockstep;
+use data::lifecycle;
+use data::kdt_point as kdtp;
+use data::aliases::*;
+use data::protocol::new_frame;
+use libs::netcom::Role;
+use data::events::EVENT_RESEND_SECONDS;
+use std::io::Cursor;
+use behavior::missile::core as missile;
+use behavior::unit::core as unit;
+use behavior::unit::building;
+use pathing::terrain;
+#[cfg(test)]
+use test_messages::*;
+
+pub struct Simulation;
+
+impl Simulation {
+    // Applies the players messages, steps every missile and unit, updates vision and
+    // the economy, then encodes the frame for each team and hands it to the games sink.
+    pub fn step(game: &mut Game, inputs: Vec<(String, Role, Vec<u8>)>) {
+        incorporate_messages(game, inputs);
+        incorporate_due_orders(game);
+
+        // RESET ECONOMY TRACKING
+        for &team in &game.teams.iter() {
+            game.teams.prime_output[team] = 0.0;
+            game.teams.energy_output[team] = 0.0;
+            game.teams.prime_drain[team] = 0.0;
+            game.teams.energy_drain[team] = 0.0;
+        }
+
+        // STEP MISSILES
+        for &id in &game.missiles.iter() {
+            missile::step_missile(game, id);
+        }
+
+        // STEP UNITS
+        let unit_iterator = game.units.iter();
+
+        for &id in &unit_iterator {
+            unit::event_handler(game, UnitEvent::UnitSteps(id));
+        }
+
+        game.unit_kdt = kdtp::populate_with_kdtunits(&game);
+        game.missile_kdt = kdtp::populate_with_kdtmissiles(&game.missiles);
+
+        let frame_time = game.frame_time();
+        for &team in &game.teams.iter() {
+            // CLEAR VISIBLE UNITS
+            for &id in &unit_iterator {
+                game.teams.visible[team][id] = game.teams.visible[team][id].step(frame_time);
+            }
+
+            // CLEAR VISIBLE MISSILES
+            for &id in &game.missiles.iter() {
+                game.teams.visible_missiles[team][id] = game.teams.visible_missiles[team][id].step(frame_time);
+            }
+
+            // SET VISIBLE UNITS AND MISSILES
+            for &id in &unit_iterator {
+                if game.units.team(id) == team && game.units.is_active(id) {
+                    let ignores_stealth = game.units.ignores_stealth(id);
+                    let ignores_cloak = game.units.ignores_cloak(id);
+                    let radar_range = game.units.radar_range(id);
+                    let sight_range = game.units.sight_range(id);
+                    let sight_dur = game.units.sight_duration(id);
+                    let radar_dur = game.units.radar_duration(id);
+
+                    let radar_blips = kdtp::all_enemies_in_range(game, id, radar_range);
+
+                    for kdtp in radar_blips {
+                        if let Some(blip_id) = game.units.target_id(kdtp.target) {
+                            let stealth = game.units.is_stealthed(blip_id) > 0;
+
+                            if ignores_stealth || !stealth {
+                                game.teams.visible[team][blip_id] = game.teams.visible[team][blip_id].spot_radar(radar_dur);
+                            }
+                        }
+                    }
+
+                    let vis_enemies = kdtp::all_enemies_in_range(game, id, sight_range);
+
+                    for kdtp in vis_enemies {
+                        if let Some(vis_id) = game.units.target_id(kdtp.target) {
+                            let cloaked = game.units.is_cloaked(vis_id) > 0;
+
+                            if ignores_cloak || !cloaked {
+                                game.teams.visible[team][vis_id] = game.teams.visible[team][vis_id].spot_vision(sight_dur);
+                            }
+                        }
+                    }
+
+                    let vis_missiles = unit::missiles_in_vision(game, id);
+
+                    for kdtp in vis_missiles {
+                        game.teams.visible_missiles[team][kdtp.id] = game.teams.visible_missiles[team][kdtp.id].spot_vision(frame_time);
+                    }
+                }
+            }
+
+            if game.teams.revealed[team] {
+                for &id in &unit_iterator {
+                    if game.units.team(id) != team {
+                        game.teams.visible[team][id] = game.teams.visible[team][id].spot_vision(frame_time);
+                    }
+                }
+
+                for &id in &game.missiles.iter() {
+                    game.teams.visible_missiles[team][id] = game.teams.visible_missiles[team][id].spot_vision(frame_time);
+                }
+            }
+
+            // ADJUST TEAMS RESOURCES
+            let build_power_distribution = game.teams.get_build_power_applications(team);
+            let total_energy = game.teams.energy[team];
+            let total_prime = game.teams.prime[team];
+            let mut total_prime_drain = 0.0;
+            let mut total_energy_drain = 0.0;
+            let mut prime = game.teams.prime[team];
+            let mut energy = game.teams.energy[team];
+
+            for &(id, build_power) in &build_power_distribution {
+                let build_cost = game.units.build_cost(id);
+                let prime_cost = game.units.prime_cost(id);
+                let energy_cost = game.units.energy_cost(id);
+                let build_ratio = build_power / build_cost;
+
+                total_prime_drain += prime_cost * build_ratio;
+                total_energy_drain += energy_cost * build_ratio;
+            }
+
+            for &id in &unit_iterator {
+                if game.units.team(id) == team && game.units.is_active(id) {
+                    if let Some(train_order) = game.units.train_queue(id).front() {
+                        let proto = game.units.proto(train_order.unit_type);
+                        let build_power = game.units.train_rate(id);
+                        let build_cost = proto.build_cost();
+                        let prime_cost = proto.prime_cost();
+                        let energy_cost = proto.energy_cost();
+                        let build_ratio = build_power / build_cost;
+
+                        total_prime_drain += prime_cost * build_ratio;
+                        total_energy_drain += energy_cost * build_ratio;
+                    }
+                }
+            }
+
+            let energy_drain_ratio = f64::min(1.0, total_energy / total_energy_drain);
+            let prime_drain_ratio = f64::min(1.0, total_prime / total_prime_drain);
+            let drain_ratio = f64::min(energy_drain_ratio, prime_drain_ratio);
+
+            for &(id, build_power) in &build_power_distribution {
+                let prime_cost = game.units.prime_cost(id);
+                let energy_cost = game.units.energy_cost(id);
+                let build_fraction = build_power * drain_ratio;
+                let progress = game.units.progress(id);
+                let build_cost = game.units.build_cost(id);
+                let new_progress = progress + build_fraction;
+                let health = game.units.health(id);
+                let max_health = game.units.max_health(id);
+                let new_health = health + max_health * (build_fraction / build_cost);
+
+                if new_health > max_health {
+                    let excess = (new_health - max_health) / max_health;
+                    prime += excess * prime_cost;
+                    energy += excess * energy_cost;
+                    game.units.set_health(id, max_health);
+                    game.units.set_progress(id, build_cost);
+                }
+                else {
+                    game.units.set_health(id, new_health);
+                    game.units.set_progress(id, new_progress);
+                }
+            }
+
+            for &id in &unit_iterator {
+                if game.units.team(id) == team && game.units.is_active(id) {
+                    let train_queue_front = game.units.train_queue(id).front().cloned();
+                    if let Some(train_order) = train_queue_front {
+                        let proto = game.units.proto(train_order.unit_type);
+                        let build_power = game.units.train_rate(id);
+                        let build_cost = proto.build_cost();
+                        let prime_cost = proto.prime_cost();
+                        let energy_cost = proto.energy_cost();
+                        let build_fraction = build_power * drain_ratio;
+                        let progress = game.units.train_progress(id);
+                        let new_progress = progress + build_fraction;
+
+                        if new_progress > build_cost {
+                            let excess = (new_progress - build_cost) / build_cost;
+                            prime += excess * prime_cost;
+                            energy += excess * energy_cost;
+                            building::train_unit(game, id, train_order);
+                        }
+                        else {
+                            game.units.set_train_progress(id, new_progress);
+                        }
+                    }
+                }
+            }
+
+            prime -= total_prime_drain * drain_ratio;
+            energy -= total_energy_drain * drain_ratio;
+            let max_prime = game.teams.max_prime[team];
+            let max_energy = game.teams.max_energy[team];
+
+            game.teams.prime_drain[team] = total_prime_drain;
+            game.teams.energy_drain[team] = total_energy_drain;
+            game.teams.prime[team] = f64::min(max_prime, prime);
+            game.teams.energy[team] = f64::min(max_energy, energy);
+        }
+
+        lifecycle::check_victory(game);
+
+        if game.lockstep.is_some() {
+            lockstep::send_turn(game);
+            clear_frame(game);
+        }
+        else {
+            encode_and_send_data_to_teams(game);
+        }
+
+        game.frame_number += 1;
+    }
+}
+
+fn encode_and_send_data_to_teams(game: &mut Game) {
+    let team_iter = game.teams.iter();
+    let frame_number = game.frame_number;
+
+    let resend_after = f64::max(1.0, EVENT_RESEND_SECONDS * game.fps()) as u32;
+    // Spectators of each team, then those who see the whole map
+    let watched: Vec<(Option<TeamID>, TeamID)> = team_iter.iter().map(|&team| (Some(team), game.spectator_slot(team)))
+        .chain(Some((None, game.observer_slot())))
+        .filter(|&(view, _)| game.sink.has_spectators(view.map(|team| unsafe { team.usize_unwrap() })))
+        .collect();
+
+    for &team in &team_iter {
+        let events = encode_events(game, Some(team));
+        game.events.push(team, frame_number, events);
+
+        let mut logg_msg = new_frame(frame_number);
+        game.events.encode(team, frame_number, resend_after, &mut logg_msg);
+
+        let team_usize = unsafe { team.usize_unwrap() };
+        game.sink.send_message_to_players(logg_msg.into_inner(), team_usize);
+    }
+
+    // Nothing spectators are sent is waited on, so their batches go out once
+    for &(view, slot) in &watched {
+        let events = encode_events(game, view);
+        game.events.push(slot, frame_number, events);
+
+        let mut logg_msg = new_frame(frame_number);
+        game.events.encode(slot, frame_number, resend_after, &mut logg_msg);
+        let last_seq = game.events.last_seq(slot);
+        game.events.acknowledge(slot, last_seq);
+        game.sink.send_message_to_spectators(logg_msg.into_inner(), view.map(|team| unsafe { team.usize_unwrap() }));
+    }
+
+    clear_frame(game);
+
+    for &team in &team_iter {
+        let (team_msg, misl_msg, unit_msg) = encode_state(game, Some(team), team);
+        let team_usize = unsafe { team.usize_unwrap() };
+
+        game.sink.send_message_to_players(team_msg, team_usize);
+        game.sink.send_message_to_players(misl_msg, team_usize);
+        game.sink.send_message_to_players(unit_msg, team_usize);
+    }
+
+    // Always in full, since spectators' acknowledgements aren't trusted
+    for &(view, slot) in &watched {
+        game.snapshots.reset(slot);
+        let (team_msg, misl_msg, unit_msg) = encode_state(game, view, slot);
+        let view = view.map(|team| unsafe { team.usize_unwrap() });
+
+        game.sink.send_message_to_spectators(team_msg, view);
+        game.sink.send_message_to_spectators(misl_msg, view);
+        game.sink.send_message_to_spectators(unit_msg, view);
+    }
+}
+
+// Removes what died this frame and forgets the frame's events once they've been sent.
+fn clear_frame(game: &mut Game) {
+    for &team in &game.teams.iter() {
+        for ref boom in &game.logger.missile_booms {
+            // NOTE! Sets exploded missiles visibility to false so they aren't encoded twice
+            game.teams.visible_missiles[team][boom.id] = Visibility::new();
+        }
+        for &death in &game.logger.unit_deaths {
+            // NOTE! Sets dead units visibility to false so they aren't encoded twice
+            game.teams.visible[team][death.id] = Visibility::new();
+        }
+    }
+
+    for ref boom in &game.logger.missile_booms {
+        game.missiles.kill_missile(boom.id);
+    }
+
+    for death in game.logger.unit_deaths.clone() {
+        let team = game.units.team(death.id);
+
+        if game.units.is_structure(death.id) {
+            match game.units.width_and_height(death.id) {
+                Some((w, h)) => {
+                    let (x, y) = game.units.xy(death.id);
+                    let hw = w as f64 / 2.0;
+                    let hh = h as f64 / 2.0;
+                    let bx = (x - hw + 0.0001) as isize;
+                    let by = (y - hh + 0.0001) as isize;
+
+                    let tiles: Vec<(isize, isize)> = (bx..bx + w).flat_map(|xo| (by..by + h).map(move |yo| (xo, yo))).collect();
+                    terrain::open_tiles(game, team, &tiles);
+                }
+                None => {
+                    panic!("encode_and_send_data_to_teams: Building without width and height.");
+                }
+            }
+        }
+    }
+
+    game.logger.clear();
+}
+
+// This frame's one-shot events as seen through view's eyes. None sees everything.
+fn encode_events(game: &Game, view: Option<TeamID>) -> Vec<u8> {
+    let mut events = Cursor::new(Vec::new());
+    logger::encode_missile_booms(game, view, &mut events);
+    logger::encode_unit_deaths(game, view, &mut events);
+    logger::encode_order_completed(game, view, &mut events);
+    logger::encode_orders_rejected(game, view, &mut events);
+    logger::encode_training_completed(game, view, &mut events);
+    logger::encode_melee_smacks(game, view, &mut events);
+    logger::encode_construction(game, view, &mut events);
+    events.into_inner()
+}
+
+// Team info, missile and unit messages for one view. slot is where its snapshots are kept.
+fn encode_state(game: &mut Game, view: Option<TeamID>, slot: TeamID) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let frame_number = game.frame_number;
+    let mut unit_msg = new_frame(frame_number);
+    let mut unit_states = Vec::new();
+
+    // CONVERT UNITS INTO DATA PACKETS
+    for &id in &game.units.iter() {
+        match view {
+            Some(team) => {
+                let unit_team = game.units.team(id);
+
+                if unit_team == team {
+                    unit_states.push(unit::unit_state(game, id));
+                }
+                else if game.teams.visible[team][id].is_visible() {
+                    unit_states.push(unit::unit_state(game, id));
+                }
+                else if game.teams.visible[team][id].is_blip() {
+
+                }
+            }
+            None => {
+                unit_states.push(unit::unit_state(game, id));
+            }
+        }
+    }
+
+    game.snapshots.encode(slot, frame_number, unit_states, &mut unit_msg);
+
+    let mut misl_msg = new_frame(frame_number);
+
+    // CONVERT MISSILES INTO DATA PACKETS
+    for &id in &game.missiles.iter() {
+        if view.map_or(true, |team| game.teams.visible_missiles[team][id].is_visible()) {
+            missile::encode(game, id, &mut misl_msg);
+        }
+    }
+
+    let mut team_msg = new_frame(frame_number);
+
+    match view {
+        Some(team) => encode_team_info(game, team, &mut team_msg),
+        None => {
+            for &team in &game.teams.iter() {
+                encode_team_info(game, team, &mut team_msg);
+            }
+        }
+    }
+
+    (team_msg.into_inner(), misl_msg.into_inner(), unit_msg.into_inner())
+}
+
+// A game that never touches the network with one Medium1 per spawn point, each on its own team.
+#[cfg(test)]
+pub fn test_game(fps: f64, seed: u64, spawns: &[(f64, f64)]) -> Game {
+    use std::sync::Arc;
+    use libs::netcom::NullSink;
+    use libs::tmx_decode::MapData;
+    use setup_game;
+
+    let (units, unit_id_map, missiles, missile_id_map, unit_info, misl_info) = setup_game::list("./src/units/", "./src/missiles/");
+    let map_data = MapData::new("./maps/Map2.json");
+    let mut game = Game::new(fps, seed, 64, 8, map_data, units, unit_id_map, missiles, missile_id_map, unit_info, misl_info, Arc::new(NullSink));
+
+    for &xy in spawns {
+        if let Some(team) = game.teams.make_team() {
+            spawn(&mut game, "Medium1", team, xy, 1.0);
+        }
+    }
+
+    game
+}
+
+// A finished unit of type name with health_fraction of its health.
+#[cfg(test)]
+pub fn spawn(game: &mut Game, name: &str, team: TeamID, xy: (f64, f64), health_fraction: f64) -> UnitID {
+    let fps = game.fps();
+    let id = game.units.make_from_name(fps, name.to_string()).unwrap();
+    let build_cost = game.units.build_cost(id);
+    let max_health = game.units.max_health(id);
+    game.units.set_xy(id, xy);
+    game.units.set_team(id, team);
+    game.units.set_progress(id, build_cost);
+    game.units.set_health(id, max_health * health_fraction);
+    id
+}
+
+// Two opposing Medium1s within range of each other.
+#[cfg(test)]
+fn skirmish(seed: u64) -> Game {
+    test_game(10.0, seed, &[(10.0, 40.0), (12.0, 40.0)])
+}
+
+#[test]
+fn step_headless() {
+    let game = &mut skirmish(0);
+
+    for _ in 0..30 {
+        Simulation::step(game, Vec::new());
+    }
+
+    let total_health = game.units.iter().iter().fold(0.0, |acc, &id| acc + game.units.health(id));
+
+    assert_eq!(game.frame_number, 30);
+    assert!(total_health < 250.0);
+}
+
+#[test]
+fn same_seed_same_game() {
+    let a = &mut skirmish(7);
+    let b = &mut skirmish(7);
+
+    for _ in 0..50 {
+        Simulation::step(a, Vec::new());
+        Simulation::step(b, Vec::new());
+    }
+
+    assert_eq!(a.units.iter(), b.units.iter());
+
+    for id in a.units.iter() {
+        assert_eq!(a.units.xy(id), b.units.xy(id));
+        assert_eq!(a.units.health(id), b.units.health(id));
+    }
+
+    assert_eq!(a.checksum(), b.checksum());
+}
+
+#[test]
+fn divergence_names_the_first_differing_field() {
+    use data::checksum::{first_divergence, Item};
+
+    let a = &mut skirmish(7);
+    let b = &mut skirmish(7);
+    let id = a.units.iter()[1];
+
+    for _ in 0..5 {
+        Simulation::step(a, Vec::new());
+        Simulation::step(b, Vec::new());
+    }
+
+    assert!(first_divergence(a, b).is_none());
+
+    let health = b.units.health(id);
+    b.units.set_health(id, health - 1.0);
+    let divergence = first_divergence(a, b).unwrap();
+    let entry = divergence.a.unwrap();
+
+    assert_ne!(a.checksum(), b.checksum());
+    assert_eq!(entry.item, Item::Unit(id));
+    assert_eq!(entry.field, "health");
+}
+
+#[test]
+fn same_pace_at_any_fps() {
+    let mut distances = Vec::new();
+
+    for &fps in &[10.0, 20.0, 30.0] {
+        let game = &mut test_game(fps, 0, &[(10.0, 40.0)]);
+        let id = game.units.iter()[0];
+
+        Simulation::step(game, vec![("p1".to_string(), Role::Player(0), move_message(id, (10.5, 80.5)))]);
+
+        // Two seconds of game time
+        for _ in 1..(fps as usize * 2) {
+            Simulation::step(game, Vec::new());
+        }
+
+        let (_, y) = game.units.xy(id);
+        distances.push(y - 40.0);
+    }
+
+    for d in &distances {
+        assert!((d - distances[0]).abs() < 0.2, "{:?}", distances);
+    }
+}
+
+#[test]
+fn spectators_cant_give_orders() {
+    let game = &mut test_game(10.0, 0, &[(10.0, 40.0)]);
+    let id = game.units.iter()[0];
+
+    Simulation::step(game, vec![
+        ("watcher".to_string(), Role::Spectator(Some(0)), move_message(id, (10.5, 80.5))),
+        ("caster".to_string(), Role::Spectator(None), move_message(id, (10.5, 80.5))),
+    ]);
+
+    assert!(game.units.orders(id).is_empty());
+}
+
+#[test]
+fn spectators_cant_move_a_teams_baseline() {
+    let game = &mut test_game(10.0, 0, &[(10.0, 40.0)]);
+    let team = game.units.team(game.units.iter()[0]);
+    let ack = snapshot_ack_message;
+
+    Simulation::step(game, Vec::new());
+    Simulation::step(game, vec![("watcher".to_string(), Role::Spectator(Some(0)), ack(0))]);
+    assert_eq!(game.snapshots.baseline(team), None);
+
+    Simulation::step(game, vec![("p1".to_string(), Role::Player(0), ack(1))]);
+    assert_eq!(game.snapshots.baseline(team), Some(1));
+
+    // Nor start the team over by joining
+    Simulation::step(game, vec![("watcher".to_string(), Role::Spectator(Some(0)), bare_message(ServerMessage::MapInfoRequest))]);
+    assert_eq!(game.snapshots.baseline(team), Some(1));
+}
+
+#[cfg(test)]
+struct StrikeCounter(::std::cell::Cell<u32>);
+
+#[cfg(test)]
+impl ::libs::netcom::MessageSink for StrikeCounter {
+    fn send_message_to_player(&self, _: Vec<u8>, _: &str) {}
+    fn send_message_to_team(&self, _: Vec<u8>, _: usize) {}
+    fn send_message_to_players(&self, _: Vec<u8>, _: usize) {}
+    fn send_message_to_spectators(&self, _: Vec<u8>, _: Option<usize>) {}
+    fn has_spectators(&self, _: Option<usize>) -> bool { false }
+    fn strike(&self, _: &str, _: &str) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+#[test]
+fn malformed_messages_are_struck_not_obeyed() {
+    use std::sync::Arc;
+
+    let game = &mut test_game(10.0, 0, &[(10.0, 40.0)]);
+    let id = game.units.iter()[0];
+    let strikes = Arc::new(StrikeCounter(::std::cell::Cell::new(0)));
+    game.sink = strikes.clone();
+
+    let off_map = move_message(id, (-5.0, 80.5));
+    let not_a_number = move_message(id, (::std::f64::NAN, 80.5));
+    let mut bad_formation = move_message(id, (10.5, 80.5));
+    bad_formation[21] = 200;
+    let mut bad_queue_order = move_message(id, (10.5, 80.5));
+    bad_queue_order[22] = 200;
+    let mut bad_unit_id = move_message(id, (10.5, 80.5));
+    bad_unit_id[23] = 0xFF;
+    bad_unit_id[24] = 0xFF;
+    let truncated = move_message(id, (10.5, 80.5))[..10].to_vec();
+    let unknown_type = vec![250, 0, 0, 0, 1];
+
+    let msgs = vec![off_map, not_a_number, bad_formation, bad_queue_order, bad_unit_id, truncated, unknown_type];
+    let count = msgs.len() as u32;
+    Simulation::step(game, msgs.into_iter().map(|m| ("p1".to_string(), Role::Player(0), m)).collect());
+
+    assert_eq!(strikes.0.get(), count);
+    assert!(game.units.orders(id).is_empty());
+}
+
+#[cfg(test)]
+fn unit_type_named(game: &Game, name: &str) -> UnitTypeID {
+    (0..game.units.num_unit_types())
+        .map(|t| unsafe { UnitTypeID::usize_wrap(t) })
+        .find(|&t| game.units.proto(t).name() == name)
+        .unwrap()
+}
+
+#[test]
+fn orders_outside_a_units_rosters_are_rejected() {
+    use std::sync::Arc;
+
+    let game = &mut test_game(10.0, 0, &[(10.0, 40.0)]);
+    let id = game.units.iter()[0];
+    let strikes = Arc::new(StrikeCounter(::std::cell::Cell::new(0)));
+    game.sink = strikes.clone();
+
+    let order = |msg: Vec<u8>| ("p1".to_string(), Role::Player(0), msg);
+
+    let fast = unit_type_named(game, "Fast1");
+    let factory = unit_type_named(game, "Factory1");
+    let msgs = vec![order(build_message(id, fast, (14.5, 40.5))), order(train_message(id, fast))];
+    ::data::game::incorporate_messages(game, msgs);
+
+    // Medium1 can't build Fast1 and isn't a factory, but asking isn't misbehaving
+    assert_eq!(strikes.0.get(), 0);
+    assert!(game.units.orders(id).is_empty());
+    assert!(game.units.train_queue(id).is_empty());
+
+    ::data::game::incorporate_messages(game, vec![order(build_message(id, factory, (14.5, 40.5)))]);
+    assert_eq!(game.units.orders(id).len(), 1);
+}
+
+#[test]
+fn lockstep_orders_run_after_the_delay() {
+    use data::lockstep::Lockstep;
+
+    let game = &mut test_game(10.0, 0, &[(10.0, 40.0)]);
+    let id = game.units.iter()[0];
+    game.lockstep = Some(Lockstep::new(3));
+
+    Simulation::step(game, vec![("p1".to_string(), Role::Player(0), move_message(id, (10.5, 80.5)))]);
+    Simulation::step(game, Vec::new());
+    Simulation::step(game, Vec::new());
+    assert!(game.units.orders(id).is_empty());
+
+    Simulation::step(game, Vec::new());
+    assert_eq!(game.units.orders(id).len(), 1);
+}
+
+#[test]
+fn last_team_standing_wins() {
+    use data::lifecycle::{Victory, EndReason};
+    use data::protocol::MatchPhase;
+
+    let game = &mut skirmish(0);
+    let ids = game.units.iter();
+    let (loser, winner) = (game.units.team(ids[0]), game.units.team(ids[1]));
+    game.lifecycle.set_rules(Victory::Units, None, 0);
+
+    Simulation::step(game, Vec::new());
+    assert!(game.lifecycle.is_running());
+
+    game.units.kill_unit(ids[0]);
+    Simulation::step(game, Vec::new());
+
+    assert_eq!(game.lifecycle.phase(), MatchPhase::Finished);
+    assert_eq!(game.lifecycle.end_reason(), Some(EndReason::LastTeamStanding));
+    assert_eq!(game.lifecycle.winners(), &[winner]);
+    assert_eq!(game.lifecycle.defeated_at(loser), Some(1));
+
+    // Nobody gives orders once it's over
+    Simulation::step(game, vec![("p2".to_string(), Role::Player(unsafe { winner.usize_unwrap() }), move_message(ids[1], (10.5, 80.5)))]);
+    assert!(game.units.orders(ids[1]).is_empty());
+}
+
+#[test]
+fn commanders_only_count_once_a_team_has_one() {
+    use data::lifecycle::Victory;
+
+    let game = &mut test_game(10.0, 0, &[(10.0, 40.0), (40.0, 80.0), (42.0, 80.0)]);
+    let ids = game.units.iter();
+    let (without, with) = (game.units.team(ids[0]), game.units.team(ids[1]));
+    game.units.set_team(ids[2], with);
+    game.units.set_is_commander(ids[1], true);
+    game.lifecycle.set_rules(Victory::Commander, None, 0);
+
+    // The team without a commander isn't out for never having had one
+    Simulation::step(game, Vec::new());
+    assert!(game.lifecycle.is_running());
+
+    game.units.kill_unit(ids[1]);
+    Simulation::step(game, Vec::new());
+
+    assert!(game.lifecycle.is_finished());
+    assert!(game.lifecycle.is_defeated(with));
+    assert_eq!(game.lifecycle.winners(), &[without]);
+}
+
+#[test]
+fn the_time_limit_goes_to_the_best_score() {
+    use data::lifecycle::{Victory, EndReason};
+
+    let game = &mut test_game(10.0, 0, &[(10.0, 40.0), (40.0, 80.0), (42.0, 80.0)]);
+    let ids = game.units.iter();
+    let (behind, ahead) = (game.units.team(ids[0]), game.units.team(ids[1]));
+    game.units.set_team(ids[2], ahead);
+    game.lifecycle.set_rules(Victory::Score, Some(5), 0);
+
+    for _ in 0..4 {
+        Simulation::step(game, Vec::new());
+        assert!(game.lifecycle.is_running());
+    }
+
+    Simulation::step(game, Vec::new());
+    assert!(game.lifecycle.is_finished());
+    assert_eq!(game.lifecycle.end_reason(), Some(EndReason::TimeLimit));
+    assert_eq!(game.lifecycle.winners(), &[ahead]);
+    assert!(game.lifecycle.is_defeated(behind));
+}
+
+#[test]
+fn pauses_run_out() {
+    use data::lifecycle::{tick, incorporate_control, Victory};
+    use data::protocol::MatchPhase;
+
+    let game = &mut skirmish(0);
+    let team = game.units.team(game.units.iter()[0]);
+    game.lifecycle.set_rules(Victory::Units, None, 2);
+    let control = bare_message;
+
+    assert!(incorporate_control(game, Role::Spectator(None), false, control(ServerMessage::Pause)).is_err());
+    incorporate_control(game, Role::Player(0), false, control(ServerMessage::Pause)).unwrap();
+    assert_eq!(game.lifecycle.phase(), MatchPhase::Paused);
+
+    // Two frames of budget, then it resumes by itself
+    tick(game, true, 0);
+    tick(game, true, 0);
+    assert_eq!(game.lifecycle.pause_left(team), 0);
+    assert_eq!(game.lifecycle.phase(), MatchPhase::Paused);
+    tick(game, true, 0);
+    assert!(game.lifecycle.is_running());
+
+    incorporate_control(game, Role::Player(0), false, control(ServerMessage::Pause)).unwrap();
+    assert!(game.lifecycle.is_running());
+
+    // Admins don't need a budget, and only they resume what they paused
+    incorporate_control(game, Role::Spectator(None), true, control(ServerMessage::Pause)).unwrap();
+    incorporate_control(game, Role::Player(1), false, control(ServerMessage::Resume)).unwrap();
+    assert_eq!(game.lifecycle.phase(), MatchPhase::Paused);
+    incorporate_control(game, Role::Spectator(None), true, control(ServerMessage::Resume)).unwrap();
+    assert!(game.lifecycle.is_running());
+    assert_eq!(game.frame_number, 0);
+}
+
+#[test]
+fn units_keep_to_the_terrain_they_move_on() {
+    let game = &mut test_game(10.0, 0, &[(10.5, 20.5), (60.5, 40.5)]);
+    let (tank, boat) = (game.units.iter()[0], game.units.iter()[1]);
+    game.units.set_move_type(boat, MoveType::Water);
+    terrain::apply_terrain(game);
+
+    // The tank can't drive into the lake and the boat can't sail out of it
+    Simulation::step(game, vec![
+        ("p1".to_string(), Role::Player(0), move_message(tank, (60.5, 60.5))),
+        ("p2".to_string(), Role::Player(1), move_message(boat, (60.5, 10.5))),
+    ]);
+    assert!(game.units.orders(tank).is_empty());
+    assert!(game.units.orders(boat).is_empty());
+
+    Simulation::step(game, vec![("p2".to_string(), Role::Player(1), move_message(boat, (100.5, 60.5)))]);
+
+    for _ in 0..300 {
+        Simulation::step(game, Vec::new());
+
+        let (x, y) = game.units.xy(boat);
+        assert_eq!(game.map_data.collision_at((x as isize, y as isize)), terrain::TILE_WATER);
+    }
+
+    let (x, y) = game.units.xy(boat);
+    assert!((x - 100.5).abs() < 2.0 && (y - 60.5).abs() < 2.0, "{:?}", (x, y));
+}
+
+#[test]
+fn patrols_go_back_and_forth_and_mend_allies() {
+    let game = &mut test_game(10.0, 0, &[(10.5, 40.5)]);
+    let id = game.units.iter()[0];
+    let team = game.units.team(id);
+
+    Simulation::step(game, vec![("p1".to_string(), Role::Player(0), patrol_message(&[(10.5, 60.5)], &[id]))]);
+
+    let mut furthest = 0.0;
+    let mut came_back = false;
+
+    for _ in 0..300 {
+        Simulation::step(game, Vec::new());
+
+        let (_, y) = game.units.xy(id);
+        furthest = f64::max(furthest, y);
+        came_back = came_back || (furthest > 58.0 && y < 43.0);
+    }
+
+    assert!(came_back, "got as far as {}", furthest);
+    assert_eq!(game.units.orders(id).len(), 1);
+
+    // Medium1s can build, so they patch up whoever's hurt along the way, even with nothing to fight the enemy with
+    game.teams.max_prime[team] = 1000.0;
+    game.teams.max_energy[team] = 1000.0;
+    game.teams.prime[team] = 1000.0;
+    game.teams.energy[team] = 1000.0;
+    game.units.mut_weapons(id).clear();
+
+    let ally = spawn(game, "Medium1", team, (14.5, 50.5), 0.5);
+    let enemy_team = game.teams.make_team().unwrap();
+    let enemy = spawn(game, "Medium1", enemy_team, (13.5, 48.5), 1.0);
+    game.units.set_fire_stance(enemy, FireStance::HoldFire);
+
+    for _ in 0..300 {
+        Simulation::step(game, Vec::new());
+    }
+
+    assert_eq!(game.units.health(ally), game.units.max_health(ally));
+    assert_eq!(game.units.orders(id).len(), 1);
+}
+
+#[test]
+fn flyers_patrol_without_a_path() {
+    let game = &mut test_game(10.0, 0, &[(10.5, 40.5)]);
+    let id = game.units.iter()[0];
+    game.units.set_move_type(id, MoveType::Air);
+
+    Simulation::step(game, vec![("p1".to_string(), Role::Player(0), patrol_message(&[(10.5, 60.5)], &[id]))]);
+
+    let mut furthest = 0.0;
+    let mut came_back = false;
+
+    for _ in 0..300 {
+        Simulation::step(game, Vec::new());
+
+        let (_, y) = game.units.xy(id);
+        furthest = f64::max(furthest, y);
+        came_back = came_back || (furthest > 58.0 && y < 43.0);
+    }
+
+    assert!(came_back, "got as far as {}", furthest);
+}
+
+#[test]
+fn patrols_skip_waypoints_they_cant_reach() {
+    let game = &mut test_game(10.0, 0, &[(10.5, 20.5)]);
+    let id = game.units.iter()[0];
+    terrain::apply_terrain(game);
+
+    // The first waypoint is out in the lake
+    Simulation::step(game, vec![("p1".to_string(), Role::Player(0), patrol_message(&[(60.5, 60.5), (10.5, 40.5), (10.5, 20.5)], &[id]))]);
+
+    let mut furthest = 0.0;
+    let mut came_back = false;
+
+    for _ in 0..300 {
+        Simulation::step(game, Vec::new());
+
+        let (_, y) = game.units.xy(id);
+        furthest = f64::max(furthest, y);
+        came_back = came_back || (furthest > 38.0 && y < 23.0);
+    }
+
+    assert!(came_back, "got as far as {}", furthest);
+    assert_eq!(game.units.orders(id).len(), 1);
+}
+
+#[test]
+fn return_fire_waits_to_be_shot_at() {
+    let game = &mut skirmish(0);
+    let (a, b) = (game.units.iter()[0], game.units.iter()[1]);
+    let full = |game: &Game, id: UnitID| game.units.health(id) >= game.units.max_health(id);
+
+    Simulation::step(game, vec![
+        ("p1".to_string(), Role::Player(0), stance_message(a, 255, FireStance::HoldFire as u8)),
+        ("p2".to_string(), Role::Player(1), stance_message(b, 255, FireStance::ReturnFire as u8)),
+    ]);
+
+    for _ in 0..30 {
+        Simulation::step(game, Vec::new());
+    }
+
+    assert!(full(game, a) && full(game, b));
+    assert_eq!(game.units.move_stance(a), MoveStance::Maneuver);
+
+    Simulation::step(game, vec![("p1".to_string(), Role::Player(0), stance_message(a, 255, FireStance::FireAtWill as u8))]);
+
+    for _ in 0..30 {
+        Simulation::step(game, Vec::new());
+    }
+
+    assert!(!full(game, b));
+    assert!(!full(game, a));
+}
+
+#[test]
+fn guards_keep_up_and_fight_off_attackers() {
+    let game = &mut test_game(10.0, 0, &[(10.5, 40.5), (40.5, 90.5)]);
+    let (a, enemy) = (game.units.iter()[0], game.units.iter()[1]);
+    let team = game.units.team(a);
+    let dist = |game: &Game, a: UnitID, b: UnitID| {
+        let ((ax, ay), (bx, by)) = (game.units.xy(a), game.units.xy(b));
+        ((ax - bx) * (ax - bx) + (ay - by) * (ay - by)).sqrt()
+    };
+
+    let guard = spawn(game, "Medium1", team, (10.5, 34.5), 1.0);
+
+    // Only allies can be guarded
+    Simulation::step(game, vec![("p1".to_string(), Role::Player(0), escort_message(ServerMessage::Guard, enemy, &[guard]))]);
+    assert!(game.units.orders(guard).is_empty());
+
+    Simulation::step(game, vec![
+        ("p1".to_string(), Role::Player(0), escort_message(ServerMessage::Guard, a, &[guard])),
+        ("p1".to_string(), Role::Player(0), move_message(a, (10.5, 60.5))),
+        ("p1".to_string(), Role::Player(0), stance_message(a, 255, FireStance::HoldFire as u8)),
+    ]);
+
+    for _ in 0..200 {
+        Simulation::step(game, Vec::new());
+    }
+
+    assert!(dist(game, guard, a) < 4.0, "{} behind", dist(game, guard, a));
+
+    // a won't shoot back, so the enemy only goes down if the guard goes after it
+    let (ax, ay) = game.units.xy(a);
+    let enemy_health = game.units.max_health(enemy) * 0.3;
+    game.units.set_xy(enemy, (ax, ay - 11.0));
+    game.units.set_health(enemy, enemy_health);
+
+    for _ in 0..300 {
+        Simulation::step(game, Vec::new());
+    }
+
+    assert!(!game.units.iter().contains(&enemy));
+    assert!(dist(game, guard, a) < 4.0, "{} away", dist(game, guard, a));
+    assert_eq!(game.units.orders(guard).len(), 1);
+}
+
+#[test]
+fn formations_keep_apart_and_keep_together() {
+    let game = &mut test_game(10.0, 0, &[(10.5, 40.5)]);
+    let slow = game.units.iter()[0];
+    let team = game.units.team(slow);
+
+    let fast = spawn(game, "Fast1", team, (12.5, 40.5), 1.0);
+    assert!(game.units.top_speed(fast) > game.units.top_speed(slow));
+
+    Simulation::step(game, vec![("p1".to_string(), Role::Player(0), formation_message(&[slow, fast], (11.5, 60.5), Formation::Line))]);
+
+    let apart = |game: &Game| {
+        let ((ax, ay), (bx, by)) = (game.units.xy(slow), game.units.xy(fast));
+        ((ax - bx) * (ax - bx) + (ay - by) * (ay - by)).sqrt()
+    };
+
+    for _ in 0..200 {
+        Simulation::step(game, Vec::new());
+        assert!(apart(game) < 4.0, "{} apart", apart(game));
+    }
+
+    // Side by side across the way they went rather than bunched on the same tile
+    let ((sx, sy), (fx, fy)) = (game.units.xy(slow), game.units.xy(fast));
+    assert!(game.units.orders(slow).is_empty() && game.units.orders(fast).is_empty());
+    assert!(sy > 57.0 && fy > 57.0, "stopped at {} and {}", sy, fy);
+    assert!((sx - fx).abs() > 1.5, "{} and {}", sx, fx);
+}
@@ -0,0 +1,100 @@
+/*
+ Messages as a client would send them, for tests to feed to the game. See data::protocol for what's in each.
+ Every one carries order ID 1 and replaces the units' orders unless it says otherwise.
+*/
+
+use byteorder::{WriteBytesExt, BigEndian};
+use std::io::Cursor;
+use data::aliases::*;
+
+fn message(tag: ServerMessage) -> Cursor<Vec<u8>> {
+    let mut msg = Cursor::new(Vec::new());
+    let _ = msg.write_u8(tag as u8);
+    let _ = msg.write_u32::<BigEndian>(1);
+    msg
+}
+
+fn write_units(msg: &mut Cursor<Vec<u8>>, units: &[UnitID]) {
+    for &id in units {
+        let _ = msg.write_u16::<BigEndian>(unsafe { id.usize_unwrap() } as u16);
+    }
+}
+
+// One with nothing after the order ID, like Pause or MapInfoRequest.
+pub(crate) fn bare_message(tag: ServerMessage) -> Vec<u8> {
+    message(tag).into_inner()
+}
+
+pub(crate) fn snapshot_ack_message(frame: u32) -> Vec<u8> {
+    let mut msg = message(ServerMessage::SnapshotAck);
+    let _ = msg.write_u32::<BigEndian>(frame);
+    msg.into_inner()
+}
+
+// A Move order for one unit.
+pub(crate) fn move_message(id: UnitID, xy: (f64, f64)) -> Vec<u8> {
+    formation_message(&[id], xy, Formation::Cluster)
+}
+
+pub(crate) fn formation_message(units: &[UnitID], xy: (f64, f64), formation: Formation) -> Vec<u8> {
+    let mut msg = message(ServerMessage::Move);
+    let _ = msg.write_f64::<BigEndian>(xy.0);
+    let _ = msg.write_f64::<BigEndian>(xy.1);
+    let _ = msg.write_u8(formation as u8);
+    let _ = msg.write_u8(QueueOrder::Replace as u8);
+    write_units(&mut msg, units);
+    msg.into_inner()
+}
+
+// Added to the end of the builder's orders.
+pub(crate) fn build_message(id: UnitID, unit_type: UnitTypeID, xy: (f64, f64)) -> Vec<u8> {
+    let mut msg = message(ServerMessage::Build);
+    let _ = msg.write_u16::<BigEndian>(unsafe { unit_type.usize_unwrap() } as u16);
+    let _ = msg.write_f64::<BigEndian>(xy.0);
+    let _ = msg.write_f64::<BigEndian>(xy.1);
+    let _ = msg.write_u8(QueueOrder::Append as u8);
+    write_units(&mut msg, &[id]);
+    msg.into_inner()
+}
+
+// Trains one, added to the end of the factory's queue.
+pub(crate) fn train_message(id: UnitID, unit_type: UnitTypeID) -> Vec<u8> {
+    let mut msg = message(ServerMessage::Train);
+    let _ = msg.write_u16::<BigEndian>(unsafe { unit_type.usize_unwrap() } as u16);
+    let _ = msg.write_u8(0);
+    let _ = msg.write_u8(QueueOrder::Append as u8);
+    write_units(&mut msg, &[id]);
+    msg.into_inner()
+}
+
+pub(crate) fn patrol_message(waypoints: &[(f64, f64)], units: &[UnitID]) -> Vec<u8> {
+    let mut msg = message(ServerMessage::Patrol);
+    let _ = msg.write_u8(waypoints.len() as u8);
+
+    for &(x, y) in waypoints {
+        let _ = msg.write_f64::<BigEndian>(x);
+        let _ = msg.write_f64::<BigEndian>(y);
+    }
+
+    let _ = msg.write_u8(QueueOrder::Replace as u8);
+    write_units(&mut msg, units);
+    msg.into_inner()
+}
+
+// 255 leaves a stance as it is.
+pub(crate) fn stance_message(id: UnitID, move_stance: u8, fire_stance: u8) -> Vec<u8> {
+    let mut msg = message(ServerMessage::Stance);
+    let _ = msg.write_u8(move_stance);
+    let _ = msg.write_u8(fire_stance);
+    write_units(&mut msg, &[id]);
+    msg.into_inner()
+}
+
+// Guard or Follow.
+pub(crate) fn escort_message(order: ServerMessage, target: UnitID, units: &[UnitID]) -> Vec<u8> {
+    let mut msg = message(order);
+    let _ = msg.write_u16::<BigEndian>(unsafe { target.usize_unwrap() } as u16);
+    let _ = msg.write_u8(QueueOrder::Replace as u8);
+    write_units(&mut msg, units);
+    msg.into_inner()
+}